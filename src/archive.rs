@@ -0,0 +1,312 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use crate::dedup::content_hash;
+use crate::image_file::{has_authorized_extension, FoundImage, ImageFile, ScanOptions, ScanResult};
+use crate::metadata_cache::{escape_path, MetadataCache, ProbeStats};
+use crate::xdg;
+
+// the entries that are larger are skipped, they are read in memory to be decoded
+const MAX_ENTRY_SIZE: u64 = 256 * 1024 * 1024;
+// the decompressed copy of a compressed tar archive stops there, the entries beyond are read by
+// decompressing the archive from the start
+const MAX_COPY_SIZE: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let file_name = path.file_name()?.to_string_lossy().to_lowercase();
+    if file_name.ends_with(".zip") {
+	Some(ArchiveKind::Zip)
+    } else if file_name.ends_with(".tar") {
+	Some(ArchiveKind::Tar)
+    } else if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
+	Some(ArchiveKind::TarGz)
+    } else {
+	None
+    }
+}
+
+// A source that is an archive rather than a directory
+pub fn is_archive(path: &Path) -> bool {
+    archive_kind(path).is_some() && !path.is_dir()
+}
+
+// Where an image found in an archive is
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    pub archive: PathBuf,
+    // the name of the entry, as stored in the archive
+    pub name: String,
+    pub size: u64,
+    // in a tar archive, where the data of the entry starts once the archive is decompressed
+    pub offset: Option<u64>,
+    // the modification time of the archive when it was read, the offset is only valid for it
+    pub mtime: (i64, i64),
+}
+
+// The entries of an archive are cached like files, under the path of the archive followed by their
+// name. They are valid as long as the archive is not modified.
+struct ArchiveScan<'a> {
+    archive: &'a Path,
+    mtime: (i64, i64),
+    scan_options: &'a ScanOptions<'a>,
+    metadata_cache: &'a mut MetadataCache,
+    found: FoundImage<'a>,
+    image_list: Vec<ImageFile>,
+    stats: ProbeStats,
+    entry_count: usize,
+    skipped_by_extension: usize,
+}
+
+impl ArchiveScan<'_> {
+    fn add(&mut self, name: &str, size: u64, offset: Option<u64>, read: impl FnOnce() -> io::Result<Vec<u8>>) {
+	self.entry_count += 1;
+	// an absolute name would replace the path of the archive
+	let path = self.archive.join(name.trim_start_matches('/').trim_start_matches("./"));
+	if !has_authorized_extension(&path, self.scan_options) {
+	    self.skipped_by_extension += 1;
+	    return;
+	}
+	if size > MAX_ENTRY_SIZE {
+	    println!("skipping {}, it is larger than {} bytes", path.to_string_lossy(), MAX_ENTRY_SIZE);
+	    return;
+	}
+	let image_file = self.metadata_cache.probe_data(&path, size, self.mtime, read, self.scan_options, &mut self.stats);
+	if let Some(mut image_file) = image_file {
+	    image_file.archive_entry = Some(ArchiveEntry {
+		archive: self.archive.to_path_buf(),
+		name: String::from(name),
+		size,
+		offset,
+		mtime: self.mtime,
+	    });
+	    (self.found)(&mut image_file);
+	    self.image_list.push(image_file);
+	}
+    }
+}
+
+fn open_tar(archive: &Path, kind: ArchiveKind) -> io::Result<tar::Archive<Box<dyn Read>>> {
+    let file = BufReader::new(File::open(archive)?);
+    let reader: Box<dyn Read> = match kind {
+	ArchiveKind::TarGz => Box::new(GzDecoder::new(file)),
+	_ => Box::new(file),
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+// Reads size bytes, the size of the entry as its header gives it, which add checked
+fn read_data<R: Read>(reader: R, size: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size as usize);
+    reader.take(size).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn scan_zip(scan: &mut ArchiveScan) -> io::Result<()> {
+    let mut zip = ZipArchive::new(File::open(scan.archive)?).map_err(io::Error::other)?;
+    for index in 0..zip.len() {
+	let mut zip_file = match zip.by_index(index) {
+	    Ok(zip_file) => zip_file,
+	    Err(error) => {
+		println!("could not read entry {} of {} : {}", index, scan.archive.to_string_lossy(), error);
+		continue;
+	    }
+	};
+	if !zip_file.is_file() {
+	    continue;
+	}
+	let name = match zip_file.name() {
+	    Ok(name) => name.into_owned(),
+	    Err(error) => {
+		println!("could not read entry {} of {} : {}", index, scan.archive.to_string_lossy(), error);
+		continue;
+	    }
+	};
+	let size = zip_file.size();
+	scan.add(&name, size, None, || read_data(&mut zip_file, size));
+    }
+    Ok(())
+}
+
+// A tar archive can only be read from start to end, the entries that have to be probed are read
+// as they come
+fn scan_tar(scan: &mut ArchiveScan, kind: ArchiveKind) -> io::Result<()> {
+    let mut tar = open_tar(scan.archive, kind)?;
+    for entry in tar.entries()? {
+	let mut entry = entry?;
+	if !entry.header().entry_type().is_file() {
+	    continue;
+	}
+	let name = match entry.path()?.to_str() {
+	    Some(name) => String::from(name),
+	    None => {
+		println!("skipping an entry of {} whose name is not valid UTF-8", scan.archive.to_string_lossy());
+		continue;
+	    }
+	};
+	let size = entry.size();
+	let offset = entry.raw_file_position();
+	scan.add(&name, size, Some(offset), || read_data(&mut entry, size));
+    }
+    Ok(())
+}
+
+// The images of the archive, in the order in which they are stored
+pub fn scan_archive(
+    archive: &Path,
+    scan_options: &ScanOptions,
+    metadata_cache: &mut MetadataCache,
+    found: FoundImage,
+) -> Result<ScanResult, io::Error> {
+    let start = Instant::now();
+    let kind = match archive_kind(archive) {
+	Some(kind) => kind,
+	None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a zip or tar archive")),
+    };
+    let metadata = fs::metadata(archive)?;
+    let mut scan = ArchiveScan {
+	archive,
+	mtime: (metadata.mtime(), metadata.mtime_nsec()),
+	scan_options,
+	metadata_cache,
+	found,
+	image_list: Vec::new(),
+	stats: ProbeStats::default(),
+	entry_count: 0,
+	skipped_by_extension: 0,
+    };
+    match kind {
+	ArchiveKind::Zip => scan_zip(&mut scan)?,
+	ArchiveKind::Tar | ArchiveKind::TarGz => scan_tar(&mut scan, kind)?,
+    }
+
+    println!(
+	"read archive {} in {:.2?}: {} entries, {} skipped by extension, {} from the cache, {} probed, {} filtered out, {} images",
+	archive.to_string_lossy(),
+	start.elapsed(),
+	scan.entry_count,
+	scan.skipped_by_extension,
+	scan.stats.cached,
+	scan.stats.probed,
+	scan.stats.rejected,
+	scan.image_list.len()
+    );
+    Ok(ScanResult {
+	image_list: scan.image_list,
+	// the directory of the archive is watched, to read it again when it changes
+	dirs: archive.parent().map(Path::to_path_buf).into_iter().collect(),
+    })
+}
+
+fn copies_dir() -> PathBuf {
+    xdg::cache_home().join("wl-bg-gallery").join("archives")
+}
+
+// The copies of an archive start with the same prefix, whatever its version
+fn copy_prefix(archive: &Path) -> String {
+    format!("{:016x}-", content_hash(archive, escape_path(archive).as_slice()).unwrap_or(0))
+}
+
+fn copy_name(archive: &Path, mtime: (i64, i64)) -> String {
+    format!("{}{}.{}.tar", copy_prefix(archive), mtime.0, mtime.1)
+}
+
+// A compressed tar archive cannot be read from the middle: it is decompressed once to the cache,
+// up to MAX_COPY_SIZE, and its entries are read from there. The copies of the previous versions
+// of the archive are removed.
+fn decompressed_copy(archive: &Path, mtime: (i64, i64)) -> io::Result<PathBuf> {
+    let cache_dir = copies_dir();
+    let prefix = copy_prefix(archive);
+    let copy_path = cache_dir.join(copy_name(archive, mtime));
+    if copy_path.exists() {
+	return Ok(copy_path);
+    }
+    fs::create_dir_all(&cache_dir)?;
+    for dir_entry in fs::read_dir(&cache_dir)?.flatten() {
+	if dir_entry.file_name().to_string_lossy().starts_with(&prefix) {
+	    let _ = fs::remove_file(dir_entry.path());
+	}
+    }
+
+    println!("decompressing {} to {}", archive.to_string_lossy(), copy_path.to_string_lossy());
+    let tmp_path = copy_path.with_extension(format!("{}.tmp", std::process::id()));
+    let mut decoder = GzDecoder::new(BufReader::new(File::open(archive)?)).take(MAX_COPY_SIZE);
+    let copied = File::create(&tmp_path).and_then(|mut tmp_file| io::copy(&mut decoder, &mut tmp_file));
+    if let Err(error) = copied.and_then(|_| fs::rename(&tmp_path, &copy_path)) {
+	let _ = fs::remove_file(&tmp_path);
+	return Err(error);
+    }
+    Ok(copy_path)
+}
+
+// Removes the decompressed copies of the archives that are no longer used, and of the previous
+// versions of those that are
+pub fn retain_decompressed_copies<'a>(archives: impl Iterator<Item = &'a PathBuf>) {
+    let kept: HashSet<String> = archives
+	.filter(|archive| archive_kind(archive) == Some(ArchiveKind::TarGz))
+	.filter_map(|archive| {
+	    let metadata = fs::metadata(archive).ok()?;
+	    Some(copy_name(archive, (metadata.mtime(), metadata.mtime_nsec())))
+	})
+	.collect();
+    let dir_entries = match fs::read_dir(copies_dir()) {
+	Ok(dir_entries) => dir_entries,
+	Err(_) => return,
+    };
+    // the copies this process is writing are kept
+    let writing = format!(".{}.tmp", std::process::id());
+    for dir_entry in dir_entries.flatten() {
+	let file_name = dir_entry.file_name().to_string_lossy().into_owned();
+	if kept.contains(&file_name) || file_name.ends_with(&writing) {
+	    continue;
+	}
+	println!("removing {}, its archive is no longer used", dir_entry.path().to_string_lossy());
+	if let Err(error) = fs::remove_file(dir_entry.path()) {
+	    println!("failed to remove {}: {}", dir_entry.path().to_string_lossy(), error);
+	}
+    }
+}
+
+// The contents of an entry, to decode it
+pub fn read_entry(archive_entry: &ArchiveEntry) -> io::Result<Vec<u8>> {
+    let kind = match archive_kind(&archive_entry.archive) {
+	Some(kind) => kind,
+	None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a zip or tar archive")),
+    };
+    if kind == ArchiveKind::Zip {
+	let mut zip = ZipArchive::new(File::open(&archive_entry.archive)?).map_err(io::Error::other)?;
+	let zip_file = zip.by_name(&archive_entry.name).map_err(io::Error::other)?;
+	return read_data(zip_file, archive_entry.size);
+    }
+
+    let metadata = fs::metadata(&archive_entry.archive)?;
+    let offset = match archive_entry.offset {
+	Some(offset) if (metadata.mtime(), metadata.mtime_nsec()) == archive_entry.mtime => offset,
+	_ => return Err(io::Error::new(io::ErrorKind::NotFound, "the archive changed since it was read")),
+    };
+    if kind == ArchiveKind::TarGz && offset + archive_entry.size > MAX_COPY_SIZE {
+	// past the end of the decompressed copy
+	let mut decoder = GzDecoder::new(BufReader::new(File::open(&archive_entry.archive)?));
+	io::copy(&mut (&mut decoder).take(offset), &mut io::sink())?;
+	return read_data(decoder, archive_entry.size);
+    }
+    let mut file = match kind {
+	ArchiveKind::TarGz => File::open(decompressed_copy(&archive_entry.archive, archive_entry.mtime)?)?,
+	_ => File::open(&archive_entry.archive)?,
+    };
+    file.seek(SeekFrom::Start(offset))?;
+    read_data(BufReader::new(file), archive_entry.size)
+}
@@ -1,7 +1,10 @@
-use std::{fmt::Display, path::PathBuf};
-use rand::Rng;
+use std::{fmt::Display, io::{BufRead, Cursor, Seek}};
 
-use image::{imageops::{overlay, resize}, ImageReader, RgbaImage};
+use image::{imageops::{crop_imm, overlay, resize}, DynamicImage, ImageReader, Rgba, RgbaImage};
+
+use crate::archive::read_entry;
+use crate::config::FitMode;
+use crate::image_file::ImageFile;
 
 pub enum BackgroundImageError {
     ImageOpenError,
@@ -17,40 +20,49 @@ impl Display for BackgroundImageError {
     }
 }
 
-pub fn fill_buffer_random(buf: &mut[u8]) -> Result<(), BackgroundImageError> {
-    println!("in fill_buffer_random");
-    let mut rng = rand::rng();
-    let red_value = rng.random_range(0..=255);
-    let green_value = rng.random_range(0..=255);
-    let blue_value = rng.random_range(0..=255);
-    let alpha_value = rng.random_range(0..=255);
+pub fn fill_buffer_with_color(background_color: [u8; 4], buf: &mut[u8]) -> Result<(), BackgroundImageError> {
     for i in (0..buf.len()).step_by(4) {
-	buf[i] = blue_value; // blue
-	buf[i + 1] = green_value; // green
-	buf[i + 2] = red_value; // red
-	buf[i + 3] = alpha_value; // alpha
+	buf[i] = background_color[2]; // blue
+	buf[i + 1] = background_color[1]; // green
+	buf[i + 2] = background_color[0]; // red
+	buf[i + 3] = background_color[3]; // alpha
     }
     return Ok(());
 }
 
 // TODO: use our defined BackgroundImageError
-pub fn open_and_decode_image(path: &PathBuf) -> Option<RgbaImage>{
-    let image_buffer = match ImageReader::open(path) {
-        Ok(image_buffer) => image_buffer,
+fn decode_image<R: BufRead + Seek>(image_buffer: ImageReader<R>) -> Option<DynamicImage> {
+    match image_buffer.decode() {
+	Ok(dynamic_image) => Some(dynamic_image),
         Err(error) => {
 	    println!("image open error: {}", error);
-	    return None
+	    None
 	},
-    };
+    }
+}
 
-    let dynamic_image = match image_buffer.decode() {
-        Ok(dynamic_image) => dynamic_image,
-        Err(error) => {
-	    println!("image open error: {}", error);
-	    return None
+pub fn open_and_decode_image(image_file: &ImageFile) -> Option<RgbaImage>{
+    let decoded = match image_file.archive_entry {
+	// the entries are read in memory, in the format they were probed with
+	Some(ref archive_entry) => match read_entry(archive_entry) {
+	    Ok(data) => decode_image(ImageReader::with_format(Cursor::new(data), image_file.format)),
+	    Err(error) => {
+		println!("image open error: {}", error);
+		return None
+	    },
+	},
+	None => match ImageReader::open(&image_file.path) {
+	    Ok(image_buffer) => decode_image(image_buffer),
+	    Err(error) => {
+		println!("image open error: {}", error);
+		return None
+	    },
 	},
     };
+    let mut dynamic_image = decoded?;
 
+    // rotate the pixels the way a photo viewer would, the dimensions of the image file already are
+    dynamic_image.apply_orientation(image_file.orientation);
     return Some(dynamic_image.to_rgba8());
 }
 
@@ -82,21 +94,59 @@ pub fn downsize_image(image: &RgbaImage, target_width: u32, target_height: u32)
 
 }
 
-pub fn overlay_image(image: &RgbaImage, target_width: u32, target_height: u32) -> RgbaImage {
+pub fn overlay_image(image: &RgbaImage, target_width: u32, target_height: u32, background_color: [u8; 4]) -> RgbaImage {
     let (current_width, current_height) = image.dimensions();
-    let mut overlay_img = RgbaImage::new(target_width, target_height);
+    let mut overlay_img = RgbaImage::from_pixel(target_width, target_height, Rgba(background_color));
     let w_offset: i64 = (target_width as i64 - current_width as i64) / 2;
     let h_offset: i64 = (target_height as i64 - current_height as i64) / 2;
     overlay(&mut overlay_img, image, w_offset, h_offset);
     return overlay_img;
 }
 
-pub fn fit_image_to_screen(image: RgbaImage, screen_width: u32, screen_height: u32) -> RgbaImage {
+// The focal point, as fractions of the width and height of the image, is kept as close to the
+// center of the output as the crop allows
+pub fn fill_image(image: &RgbaImage, target_width: u32, target_height: u32, focal_point: (f64, f64)) -> RgbaImage {
+    let (current_width, current_height) = image.dimensions();
+    let ratio = f64::max(
+	target_width as f64 / current_width as f64,
+	target_height as f64 / current_height as f64,
+    );
+    let scaled_width = ((current_width as f64 * ratio).ceil() as u32).max(target_width);
+    let scaled_height = ((current_height as f64 * ratio).ceil() as u32).max(target_height);
+    let scaled = resize(image, scaled_width, scaled_height, image::imageops::FilterType::Lanczos3);
+    let x = (scaled_width as f64 * focal_point.0 - target_width as f64 / 2.0).clamp(0.0, (scaled_width - target_width) as f64) as u32;
+    let y = (scaled_height as f64 * focal_point.1 - target_height as f64 / 2.0).clamp(0.0, (scaled_height - target_height) as f64) as u32;
+    crop_imm(&scaled, x, y, target_width, target_height).to_image()
+}
+
+pub fn tile_image(image: &RgbaImage, target_width: u32, target_height: u32) -> RgbaImage {
+    let (current_width, current_height) = image.dimensions();
+    RgbaImage::from_fn(target_width, target_height, |x, y| {
+	*image.get_pixel(x % current_width, y % current_height)
+    })
+}
+
+pub fn fit_image_to_screen(
+    image: RgbaImage,
+    screen_width: u32,
+    screen_height: u32,
+    fit: FitMode,
+    background_color: [u8; 4],
+    focal_point: (f64, f64),
+) -> RgbaImage {
 
     let (current_width, current_height) = image.dimensions();
 
     let mut new_image: RgbaImage;
 
+    match fit {
+	FitMode::Fill => return fill_image(&image, screen_width, screen_height, focal_point),
+	FitMode::Stretch => return resize(&image, screen_width, screen_height, image::imageops::FilterType::Lanczos3),
+	FitMode::Tile => return tile_image(&image, screen_width, screen_height),
+	FitMode::Center => return overlay_image(&image, screen_width, screen_height, background_color),
+	FitMode::Fit => (),
+    }
+
     if current_width > screen_width || current_height > screen_height {
 	new_image = downsize_image(&image, screen_width, screen_height);
     } else {
@@ -105,22 +155,29 @@ pub fn fit_image_to_screen(image: RgbaImage, screen_width: u32, screen_height: u
 
     let (current_width, current_height) = new_image.dimensions();
     if current_width < screen_width || current_height < screen_height {
-	new_image = overlay_image(&new_image, screen_width, screen_height);
+	new_image = overlay_image(&new_image, screen_width, screen_height, background_color);
     }
     return new_image;
 }
 
 pub fn fill_buffer_with_image(
-    path: &PathBuf,
+    image_file: &ImageFile,
     screen_width: u32,
     screen_height: u32,
+    fit: FitMode,
+    background_color: [u8; 4],
     buf: &mut[u8]
 ) -> Result<(), BackgroundImageError> {
-    let mut image = match open_and_decode_image(path) {
+    let mut image = match open_and_decode_image(image_file) {
         Some(image) => image,
         None => return Err(BackgroundImageError::ImageOpenError),
     };
-    image = fit_image_to_screen(image, screen_width, screen_height);
+    let focal_point = image_file
+	.overrides
+	.as_ref()
+	.and_then(|overrides| overrides.focal_point)
+	.unwrap_or((0.5, 0.5));
+    image = fit_image_to_screen(image, screen_width, screen_height, fit, background_color, focal_point);
     assert!(image.len() == buf.len());
     let mut index_in_target = 0;
     for pixel in image.pixels() {
@@ -0,0 +1,105 @@
+use std::{
+    os::fd::{AsFd, BorrowedFd},
+    path::PathBuf,
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::sys::eventfd::{EfdFlags, EventFd};
+
+use crate::image_file::{get_dir_image_list, get_image_list, ImageFile, ScanOptions, ScanResult};
+use crate::metadata_cache::MetadataCache;
+
+// how often the images found by a scan are handed over, after the first one which is sent right away
+const FOUND_INTERVAL: Duration = Duration::from_millis(500);
+
+// What a scan sends back to the main loop. scan_id tells the scans of the same source apart, when
+// it is scanned again before the previous scan is over.
+pub enum ScanEvent {
+    // images found since the previous event, in no particular order
+    Found { scan_id: u64, image_files: Vec<ImageFile> },
+    // the complete result, along with what was probed on the way
+    Done { scan_id: u64, scan_result: ScanResult, metadata_cache: Box<MetadataCache> },
+}
+
+// Scans sources on their own threads so that the main loop keeps running meanwhile. Events are
+// passed through a channel, and an eventfd tells the epoll of the main loop when there are some.
+pub struct BackgroundScanner {
+    sender: mpsc::Sender<ScanEvent>,
+    receiver: mpsc::Receiver<ScanEvent>,
+    event_fd: Arc<EventFd>,
+}
+
+impl Default for BackgroundScanner {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl BackgroundScanner {
+    pub fn new() -> BackgroundScanner {
+	let event_fd = match EventFd::from_flags(EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC) {
+	    Ok(event_fd) => event_fd,
+	    Err(error) => panic!("failed to create eventfd: {}", error),
+	};
+	let (sender, receiver) = mpsc::channel();
+	BackgroundScanner {
+	    sender,
+	    receiver,
+	    event_fd: Arc::new(event_fd),
+	}
+    }
+
+    pub fn event_fd(&self) -> BorrowedFd<'_> {
+	self.event_fd.as_fd()
+    }
+
+    // Scans the source at root, or only dir when it is a directory inside it. metadata_cache is a
+    // snapshot of the cache, it comes back with the Done event.
+    pub fn start(&self, scan_id: u64, root: PathBuf, dir: PathBuf, scan_options: &ScanOptions, mut metadata_cache: MetadataCache) {
+	let scan_options = scan_options.to_owned_options();
+	let sender = self.sender.clone();
+	let event_fd = self.event_fd.clone();
+	let send = move |event: ScanEvent| {
+	    // the receiver is gone when the program exits
+	    if sender.send(event).is_ok() {
+		if let Err(error) = event_fd.write(1) {
+		    println!("failed to wake up the main loop: {}", error);
+		}
+	    }
+	};
+	let spawned = thread::Builder::new().name(String::from("scan")).spawn(move || {
+	    let mut pending = Vec::new();
+	    let mut last_sent: Option<Instant> = None;
+	    let scan_options = scan_options.scan_options();
+	    let mut found = |image_file: &mut ImageFile| {
+		pending.push(image_file.clone());
+		if last_sent.is_none_or(|last_sent| last_sent.elapsed() >= FOUND_INTERVAL) {
+		    send(ScanEvent::Found { scan_id, image_files: std::mem::take(&mut pending) });
+		    last_sent = Some(Instant::now());
+		}
+	    };
+	    let scan_result = if dir == root {
+		get_image_list(&root, &scan_options, &mut metadata_cache, &mut found)
+	    } else {
+		get_dir_image_list(&root, &dir, &scan_options, &mut metadata_cache, &mut found)
+	    };
+	    // the images that are still pending are part of the result
+	    send(ScanEvent::Done { scan_id, scan_result, metadata_cache: Box::new(metadata_cache) });
+	});
+	if let Err(error) = spawned {
+	    panic!("failed to start a scan thread: {}", error);
+	}
+    }
+
+    pub fn read_events(&self) -> Vec<ScanEvent> {
+	// resets the counter, the events themselves are in the channel
+	match self.event_fd.read() {
+	    Ok(_) => (),
+	    Err(nix::errno::Errno::EAGAIN) => (),
+	    Err(error) => println!("error when reading the scan eventfd: {}", error),
+	}
+	self.receiver.try_iter().collect()
+    }
+}
@@ -0,0 +1,64 @@
+use std::{env, path::PathBuf, process::exit};
+
+pub enum Command {
+    // run the daemon
+    Run,
+    // validate the config files and exit
+    CheckConfig,
+    // print the merged config and where each value comes from
+    ExplainConfig,
+}
+
+pub struct Args {
+    pub command: Command,
+    // replaces the config files that are normally searched for
+    pub config_path: Option<PathBuf>,
+}
+
+const USAGE: &str = "usage: wl-bg-gallery-rs [--config <file>] [check-config | explain-config]";
+
+const CONFIG_ENV_VAR: &str = "WL_BG_GALLERY_CONFIG";
+
+fn usage_error(message: String) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    exit(2);
+}
+
+pub fn parse_args() -> Args {
+    let mut args = Args {
+	command: Command::Run,
+	config_path: None,
+    };
+
+    let mut args_iter = env::args_os().skip(1);
+    while let Some(arg) = args_iter.next() {
+	let arg_str = arg.to_string_lossy();
+	match arg_str.as_ref() {
+	    "check-config" => args.command = Command::CheckConfig,
+	    "explain-config" => args.command = Command::ExplainConfig,
+	    "--config" | "-c" => match args_iter.next() {
+		Some(config_path) => args.config_path = Some(PathBuf::from(config_path)),
+		None => usage_error(format!("{} expects a file", arg_str)),
+	    },
+	    "-h" | "--help" => {
+		println!("{}", USAGE);
+		exit(0);
+	    }
+	    _ => match arg_str.strip_prefix("--config=") {
+		Some(config_path) => args.config_path = Some(PathBuf::from(config_path)),
+		None => usage_error(format!("unknown argument {}", arg_str)),
+	    },
+	}
+    }
+
+    // the command line takes precedence over the environment
+    if args.config_path.is_none() {
+	if let Some(config_path) = env::var_os(CONFIG_ENV_VAR) {
+	    if !config_path.is_empty() {
+		args.config_path = Some(PathBuf::from(config_path));
+	    }
+	}
+    }
+
+    args
+}
@@ -1,141 +1,1246 @@
-use std::{fs::File, io::Read};
+use std::{collections::HashMap, fs::File, io::Read, path::{Path, PathBuf}, time::Duration};
 
 use image::ImageFormat;
-use serde::Deserialize;
+use regex::Regex;
+use serde::{de::Error, Deserialize, Deserializer};
 
-#[derive(Deserialize, Debug)]
+use crate::cron::CronExpr;
+use crate::dedup::Deduplicate;
+use crate::duration::{format_duration, parse_duration};
+use crate::expand::expand_path;
+use crate::filter::{format_size, parse_aspect_ratio, parse_size, ImageFilter, ModifiedTime, Shape};
+use crate::glob::glob_match;
+use crate::image_file::{format_name, parse_format, ScanOptions};
+use crate::order::Order;
+use crate::output::Output;
+use crate::schedule::{sun_times, LocalTime, Location, ScheduleWindow, TimeOfDay};
+use crate::xdg;
+
+// How the images of a source shared by several outputs are handed out
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum FitType {
+    // each output gets the images that suit its resolution best
     BestFit,
+    // each output gets the next image of the rotation
     NextFile
 }
 
+// How an image is laid out on an output whose resolution it does not match
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FitMode {
+    // downscale images that are too large while keeping aspect ratio, center smaller ones
+    Fit,
+    // scale to cover the whole output while keeping aspect ratio, cropping what overflows
+    Fill,
+    // scale to the exact size of the output, ignoring aspect ratio
+    Stretch,
+    // never scale, center the image and crop what overflows
+    Center,
+    // repeat the image from the top left corner
+    Tile,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParsedSource {
+    #[serde(deserialize_with = "deserialize_path")]
+    pub path: PathBuf,
+    pub weight: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParsedOutputConfig {
+    pub name: Option<Matcher>,
+    pub make: Option<Matcher>,
+    pub model: Option<Matcher>,
+    pub description: Option<Matcher>,
+    #[serde(default, deserialize_with = "deserialize_optional_path")]
+    pub path: Option<PathBuf>,
+    #[serde(default, deserialize_with = "deserialize_sources")]
+    pub sources: Option<Vec<ParsedSource>>,
+    pub bg_duration_seconds: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_interval")]
+    pub interval: Option<u64>,
+    pub change_at: Option<CronExpr>,
+    pub fit: Option<FitMode>,
+    #[serde(default, deserialize_with = "deserialize_color")]
+    pub background_color: Option<[u8; 4]>,
+}
+
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParsedScheduleWindow {
+    pub start: TimeOfDay,
+    #[serde(default, deserialize_with = "deserialize_optional_path")]
+    pub path: Option<PathBuf>,
+    #[serde(default, deserialize_with = "deserialize_sources")]
+    pub sources: Option<Vec<ParsedSource>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct ParsedConfig {
-    pub path: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_path")]
+    pub path: Option<PathBuf>,
+    #[serde(default, deserialize_with = "deserialize_sources")]
+    pub sources: Option<Vec<ParsedSource>>,
     pub bg_duration_seconds: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_interval")]
+    pub interval: Option<u64>,
+    pub change_at: Option<CronExpr>,
+    pub order: Option<Order>,
+    pub seed: Option<u64>,
+    pub assignment: Option<FitType>,
+    pub fit: Option<FitMode>,
+    #[serde(default, deserialize_with = "deserialize_color")]
+    pub background_color: Option<[u8; 4]>,
+    #[serde(default, deserialize_with = "deserialize_optional_path")]
+    pub fallback_image: Option<PathBuf>,
+    #[serde(default, deserialize_with = "deserialize_formats")]
+    pub authorized_formats: Option<Vec<ImageFormat>>,
+    pub recursive: Option<bool>,
+    pub max_depth: Option<u32>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub extension_prefilter: Option<bool>,
+    pub scan_threads: Option<u32>,
+    pub deduplicate: Option<Deduplicate>,
+    pub similarity_threshold: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_aspect_ratio")]
+    pub min_aspect_ratio: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_aspect_ratio")]
+    pub max_aspect_ratio: Option<f64>,
+    pub orientation: Option<Shape>,
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub max_file_size: Option<u64>,
+    pub modified_after: Option<ModifiedTime>,
+    pub modified_before: Option<ModifiedTime>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub schedule: Option<Vec<ParsedScheduleWindow>>,
+    pub output: Option<Vec<ParsedOutputConfig>>,
 }
 
 enum ReadConfigError {
-    OpenError(String),
+    // the file does not exist, which is fine unless it was explicitly asked for
+    NotFound(String),
     ReadError(String),
-    ParseError(String)
+    ParseError(String),
 }
 
-impl ParsedConfig {
-    fn empty() -> ParsedConfig{
-	return ParsedConfig {
-	    path: None,
-	    bg_duration_seconds: None,
-	};
-    }
+// Turns the byte offset at which toml reported an error into a "path:line:column: message" string
+pub fn format_parse_error(config_path: &str, config_str: &str, error: &toml::de::Error) -> String {
+    let span = match error.span() {
+	Some(span) => span,
+	None => return format!("{}: {}", config_path, error.message()),
+    };
+    let before_error = &config_str[..span.start];
+    let line = before_error.matches('\n').count() + 1;
+    let line_start = match before_error.rfind('\n') {
+	Some(index) => index + 1,
+	None => 0,
+    };
+    let column = before_error[line_start..].chars().count() + 1;
+    format!("{}:{}:{}: {}", config_path, line, column, error.message())
+}
 
-    fn read_config(default_config_path: String) -> Result<ParsedConfig, ReadConfigError> {
-	let mut file = match File::open(&default_config_path) {
+impl ParsedConfig {
+    fn read_config(config_path: &Path) -> Result<ParsedConfig, ReadConfigError> {
+	let default_config_path = config_path.to_string_lossy();
+	let mut file = match File::open(config_path) {
             Ok(file) => file,
+	    Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+		return Err(ReadConfigError::NotFound(format!("failed to open {}: {}", default_config_path, error)));
+	    },
             Err(error) => {
-		println!();
-		return Err(ReadConfigError::OpenError(format!("failed to open {}: {}", default_config_path, error)));
+		return Err(ReadConfigError::ReadError(format!("failed to open {}: {}", default_config_path, error)));
 	    },
 	};
 
 	let mut config_str = String::new();
 	if let Err(error) = file.read_to_string(&mut config_str) {
-	    println!("failed to read {}: {}", default_config_path, error);
 	    return Err(ReadConfigError::ReadError(format!("failed to read {}: {}", default_config_path, error)));
 	}
 
-	match toml::from_str(config_str.as_str()) {
-            Ok(parsed_config) => Ok(parsed_config),
+	let parsed_config: ParsedConfig = match toml::from_str(config_str.as_str()) {
+	    Ok(parsed_config) => parsed_config,
             Err(error) => {
-		println!("failed to deserialize {}: {}", default_config_path, error);
-		return Err(ReadConfigError::ParseError(format!("failed to parse {}: {}", default_config_path, error)));
+		return Err(ReadConfigError::ParseError(format_parse_error(&default_config_path, &config_str, &error)));
 	    }
+	};
+	match parsed_config.check() {
+	    Ok(()) => Ok(parsed_config),
+	    Err(error) => Err(ReadConfigError::ParseError(format!("{}: {}", default_config_path, error))),
 	}
     }
+
+    // Checks what serde cannot, within a single file
+    fn check(&self) -> Result<(), String> {
+	both_path_and_sources(&self.path, &self.sources, "")?;
+	for window in self.schedule.iter().flatten() {
+	    both_path_and_sources(&window.path, &window.sources, &format!("[[schedule]] starting at {}: ", window.start))?;
+	}
+	for output in self.output.iter().flatten() {
+	    both_path_and_sources(&output.path, &output.sources, "[[output]]: ")?;
+	}
+	Ok(())
+    }
 }
 
-const DEFAULT_PATH: &str = "~/Pictures/wallpaper";
+const DEFAULT_PATH: &str = "$XDG_PICTURES_DIR/wallpaper";
 const DEFAULT_BG_DURATION_SECONDS: u64 = 15;
+const DEFAULT_WEIGHT: u32 = 1;
+const DEFAULT_MAX_DEPTH: u32 = 8;
+const DEFAULT_ORDER: Order = Order::Sequential;
+const DEFAULT_ASSIGNMENT: FitType = FitType::NextFile;
+const DEFAULT_FIT: FitMode = FitMode::Fit;
+const DEFAULT_BACKGROUND_COLOR: [u8; 4] = [0, 0, 0, 0];
+// out of the 64 bits of the perceptual hash
+const DEFAULT_SIMILARITY_THRESHOLD: u32 = 6;
+
+// the top-level keys, in the order in which explain-config prints them
+const CONFIG_KEYS: [&str; 15] = [
+    "sources",
+    "interval",
+    "order",
+    "assignment",
+    "fit",
+    "background_color",
+    "authorized_formats",
+    "recursive",
+    "max_depth",
+    "include",
+    "exclude",
+    "extension_prefilter",
+    "scan_threads",
+    "deduplicate",
+    "similarity_threshold",
+];
+
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    Glob(String),
+    Regex(Regex),
+}
+
+// Either a glob pattern or a { regex = "..." } table. Written by hand so that a mistake is reported
+// precisely rather than as a value that matches neither form.
+impl<'de> Deserialize<'de> for Matcher {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Matcher, D::Error> {
+	let mut table = match toml::Value::deserialize(deserializer)? {
+	    toml::Value::String(pattern) => return Ok(Matcher::Glob(pattern)),
+	    toml::Value::Table(table) => table,
+	    other => {
+		return Err(D::Error::custom(format!(
+		    "expected a glob pattern or {{ regex = \"...\" }}, found {}",
+		    other.type_str()
+		)))
+	    }
+	};
+	if let Some(key) = table.keys().find(|key| *key != "regex") {
+	    return Err(D::Error::unknown_field(key, &["regex"]));
+	}
+	match table.remove("regex") {
+	    Some(toml::Value::String(regex)) => match Regex::new(&regex) {
+		Ok(regex) => Ok(Matcher::Regex(regex)),
+		Err(error) => Err(D::Error::custom(format!("invalid regex {}: {}", regex, error))),
+	    },
+	    Some(other) => Err(D::Error::custom(format!("regex should be a string, found {}", other.type_str()))),
+	    None => Err(D::Error::missing_field("regex")),
+	}
+    }
+}
+
+impl std::fmt::Display for Matcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	match self {
+	    Matcher::Glob(pattern) => write!(f, "{:?}", pattern),
+	    Matcher::Regex(regex) => write!(f, "{{ regex = {:?} }}", regex.as_str()),
+	}
+    }
+}
+
+impl Matcher {
+    pub fn is_match(&self, value: &str) -> bool {
+	match self {
+	    Matcher::Glob(pattern) => glob_match(pattern, value),
+	    Matcher::Regex(regex) => regex.is_match(value),
+	}
+    }
+}
+
+// An [[output]] table: every matcher that is set must match for the overrides to apply
+#[derive(Debug)]
+pub struct OutputConfig {
+    pub name: Option<Matcher>,
+    pub make: Option<Matcher>,
+    pub model: Option<Matcher>,
+    pub description: Option<Matcher>,
+    pub sources: Option<Vec<Source>>,
+    pub bg_duration_seconds: Option<u64>,
+    pub change_at: Option<CronExpr>,
+    pub fit: Option<FitMode>,
+    pub background_color: Option<[u8; 4]>,
+    // the file in which the table was found
+    pub source: String,
+}
+
+impl OutputConfig {
+    pub fn matches(&self, output: &Output) -> bool {
+	let criteria = [
+	    (&self.name, &output.name),
+	    (&self.make, &output.make),
+	    (&self.model, &output.model),
+	    (&self.description, &output.description),
+	];
+	criteria.iter().all(|(matcher, value)| match matcher {
+	    Some(matcher) => matcher.is_match(value),
+	    None => true,
+	})
+    }
+}
+
+// A directory to take images from. Sources are picked in proportion to their weight, whatever
+// the number of images they contain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub path: PathBuf,
+    pub weight: u32,
+}
+
+impl Source {
+    fn from_path(path: PathBuf) -> Source {
+	Source {
+	    path,
+	    weight: DEFAULT_WEIGHT,
+	}
+    }
+}
+
+// Setting both is an error, see ParsedConfig::check
+fn both_path_and_sources(path: &Option<PathBuf>, sources: &Option<Vec<ParsedSource>>, table: &str) -> Result<(), String> {
+    if path.is_some() && sources.is_some() {
+	return Err(format!("{}both path and sources are set, only one of them can be", table));
+    }
+    Ok(())
+}
+
+// `path = "..."` is a shorthand for `sources = [{ path = "..." }]`
+fn parse_sources(path: Option<PathBuf>, sources: Option<Vec<ParsedSource>>) -> Option<Vec<Source>> {
+    match sources {
+	Some(sources) => Some(
+	    sources
+		.into_iter()
+		.map(|source| Source {
+		    path: source.path,
+		    weight: source.weight.unwrap_or(DEFAULT_WEIGHT),
+		})
+		.collect(),
+	),
+	None => path.map(|path| vec!(Source::from_path(path))),
+    }
+}
+
+fn format_sources(sources: &[Source]) -> String {
+    let sources: Vec<String> = sources
+	.iter()
+	.map(|source| format!("{{ path = {:?}, weight = {} }}", source.path.to_string_lossy(), source.weight))
+	.collect();
+    format!("[{}]", sources.join(", "))
+}
+
+// The settings that apply to one output once the [[output]] tables are resolved
+#[derive(Debug, Clone)]
+pub struct OutputSettings {
+    pub sources: Vec<Source>,
+    pub bg_duration_seconds: u64,
+    // when set, images change at the moments it matches instead of every bg_duration_seconds
+    pub change_at: Option<CronExpr>,
+    pub fit: FitMode,
+    pub background_color: [u8; 4],
+}
+
+impl OutputSettings {
+    // The time until the image should change again
+    pub fn until_next_change(&self) -> Duration {
+	let interval = Duration::new(self.bg_duration_seconds, 0);
+	match self.change_at {
+	    Some(ref change_at) => change_at.until_next().unwrap_or(interval),
+	    None => interval,
+	}
+    }
+}
 
 #[derive(Debug)]
 pub struct Config {
-    pub path: String,
+    pub sources: Vec<Source>,
     pub bg_duration_seconds: u64,
+    pub change_at: Option<CronExpr>,
+    pub order: Order,
+    // makes the shuffles and random draws the same every time, they are different otherwise
+    pub seed: Option<u64>,
+    pub assignment: FitType,
     pub authorized_formats: Vec<ImageFormat>,
+    pub fit: FitMode,
+    pub background_color: [u8; 4],
+    // what is shown while a source has no picture to show, background_color if not set
+    pub fallback_image: Option<PathBuf>,
+    pub recursive: bool,
+    pub max_depth: u32,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // skip the files whose extension is not that of an authorized format without opening them
+    pub extension_prefilter: bool,
+    // how many files are probed in parallel, 0 for one per CPU
+    pub scan_threads: u32,
+    // whether only one picture of each group of duplicates is used
+    pub deduplicate: Deduplicate,
+    // how many bits the perceptual hashes of two similar pictures can differ by
+    pub similarity_threshold: u32,
+    // the conditions that pictures must meet to be used
+    pub filter: ImageFilter,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    // the windows of the day during which other sources replace the global ones
+    pub schedule: Vec<ScheduleWindow>,
+    pub outputs: Vec<OutputConfig>,
+    // for each key, the file that set the value we use, or "default"
+    pub provenance: HashMap<&'static str, String>,
+}
+
+// Parses "#rrggbb" or "#rrggbbaa" into an RGBA color
+pub fn parse_color(color: &str) -> Result<[u8; 4], String> {
+    let hex = match color.strip_prefix('#') {
+	Some(hex) => hex,
+	None => return Err(format!("color {} should start with #", color)),
+    };
+    if (hex.len() != 6 && hex.len() != 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+	return Err(format!("color {} should be of the form #rrggbb or #rrggbbaa", color));
+    }
+    let mut rgba = [0, 0, 0, 255];
+    for (index, component) in rgba.iter_mut().enumerate().take(hex.len() / 2) {
+	*component = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).unwrap();
+    }
+    Ok(rgba)
+}
+
+pub fn format_color(color: &[u8; 4]) -> String {
+    format!("#{:02x}{:02x}{:02x}{:02x}", color[0], color[1], color[2], color[3])
+}
+
+fn deserialize_path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    let path = String::deserialize(deserializer)?;
+    expand_path(&path).map_err(D::Error::custom)
 }
 
-pub fn expand_tilde(mut str: String) -> String {
-    if str.len() <= 0 {
-	return str;
+fn deserialize_optional_path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PathBuf>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+	Some(path) => expand_path(&path).map(Some).map_err(D::Error::custom),
+	None => Ok(None),
     }
+}
 
-    if str.chars().nth(0).unwrap() != '~' {
-	return str;
+fn deserialize_sources<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<ParsedSource>>, D::Error> {
+    match Option::<Vec<ParsedSource>>::deserialize(deserializer)? {
+	Some(sources) if sources.is_empty() => Err(D::Error::custom("sources cannot be empty")),
+	sources => Ok(sources),
     }
+}
 
-    let mut home = match std::env::var("HOME") {
-        Ok(home) => home,
-        Err(_) => {
-	    return str;
-	}
+// Durations can be given as a number of seconds, or as a string like "1h30m"
+pub fn deserialize_interval<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ParsedInterval {
+	Seconds(u64),
+	Human(String),
+    }
+
+    match Option::<ParsedInterval>::deserialize(deserializer)? {
+	Some(ParsedInterval::Seconds(seconds)) => Ok(Some(seconds)),
+	Some(ParsedInterval::Human(duration)) => match parse_duration(&duration) {
+	    Ok(seconds) => Ok(Some(seconds)),
+	    Err(error) => Err(D::Error::custom(error)),
+	},
+	None => Ok(None),
+    }
+}
+
+fn deserialize_aspect_ratio<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ParsedAspectRatio {
+	Number(f64),
+	Ratio(String),
+    }
+
+    match Option::<ParsedAspectRatio>::deserialize(deserializer)? {
+	Some(ParsedAspectRatio::Number(ratio)) => match parse_aspect_ratio(&ratio.to_string()) {
+	    Ok(ratio) => Ok(Some(ratio)),
+	    Err(error) => Err(D::Error::custom(error)),
+	},
+	Some(ParsedAspectRatio::Ratio(ratio)) => match parse_aspect_ratio(&ratio) {
+	    Ok(ratio) => Ok(Some(ratio)),
+	    Err(error) => Err(D::Error::custom(error)),
+	},
+	None => Ok(None),
+    }
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ParsedSize {
+	Bytes(u64),
+	Human(String),
+    }
+
+    match Option::<ParsedSize>::deserialize(deserializer)? {
+	Some(ParsedSize::Bytes(bytes)) => Ok(Some(bytes)),
+	Some(ParsedSize::Human(size)) => match parse_size(&size) {
+	    Ok(bytes) => Ok(Some(bytes)),
+	    Err(error) => Err(D::Error::custom(error)),
+	},
+	None => Ok(None),
+    }
+}
+
+fn deserialize_formats<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<ImageFormat>>, D::Error> {
+    let names = match Option::<Vec<String>>::deserialize(deserializer)? {
+	Some(names) => names,
+	None => return Ok(None),
     };
-    home = format!("{home}/");
-    str.replace_range(0..1, &home);
-    return str;
+    let mut formats = Vec::new();
+    for name in names {
+	match parse_format(&name) {
+	    Some(format) => formats.push(format),
+	    None => return Err(D::Error::custom(format!("unknown image format {}", name))),
+	}
+    }
+    Ok(Some(formats))
+}
+
+pub fn deserialize_color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<[u8; 4]>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+	Some(color) => match parse_color(&color) {
+	    Ok(rgba) => Ok(Some(rgba)),
+	    Err(error) => Err(D::Error::custom(error)),
+	},
+	None => Ok(None),
+    }
 }
 
 impl Config {
     fn default() -> Config{
 	return Config {
-	    path: expand_tilde(String::from(DEFAULT_PATH)),
+	    sources: vec!(Source::from_path(
+		expand_path(DEFAULT_PATH).unwrap_or_else(|_| PathBuf::from(DEFAULT_PATH))
+	    )),
 	    bg_duration_seconds: DEFAULT_BG_DURATION_SECONDS,
+	    change_at: None,
+	    order: DEFAULT_ORDER,
+	    seed: None,
+	    assignment: DEFAULT_ASSIGNMENT,
 	    authorized_formats: vec!(
 		ImageFormat::Jpeg,
 		ImageFormat::WebP,
 		ImageFormat::Png,
 		ImageFormat::Tiff
 	    ),
+	    fit: DEFAULT_FIT,
+	    background_color: DEFAULT_BACKGROUND_COLOR,
+	    fallback_image: None,
+	    recursive: false,
+	    max_depth: DEFAULT_MAX_DEPTH,
+	    include: Vec::new(),
+	    exclude: Vec::new(),
+	    extension_prefilter: false,
+	    scan_threads: 0,
+	    deduplicate: Deduplicate::Off,
+	    similarity_threshold: DEFAULT_SIMILARITY_THRESHOLD,
+	    filter: ImageFilter::default(),
+	    latitude: None,
+	    longitude: None,
+	    schedule: Vec::new(),
+	    outputs: Vec::new(),
+	    provenance: CONFIG_KEYS.iter().map(|key| (*key, String::from("default"))).collect(),
 	};
     }
 
-    fn from(config: &mut Config, parsed_config: ParsedConfig) {
-	if let Some(path) = parsed_config.path {
-	    config.path = expand_tilde(path);
+    fn from(config: &mut Config, parsed_config: ParsedConfig, source: &str) {
+	if let Some(sources) = parse_sources(parsed_config.path, parsed_config.sources) {
+	    config.sources = sources;
+	    config.provenance.insert("sources", String::from(source));
 	}
 
-	if let Some(bg_duration_seconds) = parsed_config.bg_duration_seconds {
+	// `interval` wins over `bg_duration_seconds` when a file sets both
+	if let Some(bg_duration_seconds) = parsed_config.interval.or(parsed_config.bg_duration_seconds) {
 	    config.bg_duration_seconds = bg_duration_seconds;
+	    config.provenance.insert("interval", String::from(source));
+	}
+
+	if let Some(change_at) = parsed_config.change_at {
+	    config.change_at = Some(change_at);
+	    config.provenance.insert("change_at", String::from(source));
+	}
+
+	if let Some(order) = parsed_config.order {
+	    config.order = order;
+	    config.provenance.insert("order", String::from(source));
+	}
+
+	if let Some(seed) = parsed_config.seed {
+	    config.seed = Some(seed);
+	    config.provenance.insert("seed", String::from(source));
+	}
+
+	if let Some(assignment) = parsed_config.assignment {
+	    config.assignment = assignment;
+	    config.provenance.insert("assignment", String::from(source));
+	}
+
+	if let Some(fit) = parsed_config.fit {
+	    config.fit = fit;
+	    config.provenance.insert("fit", String::from(source));
+	}
+
+	if let Some(background_color) = parsed_config.background_color {
+	    config.background_color = background_color;
+	    config.provenance.insert("background_color", String::from(source));
+	}
+
+	if let Some(fallback_image) = parsed_config.fallback_image {
+	    config.fallback_image = Some(fallback_image);
+	    config.provenance.insert("fallback_image", String::from(source));
+	}
+
+	if let Some(authorized_formats) = parsed_config.authorized_formats {
+	    config.authorized_formats = authorized_formats;
+	    config.provenance.insert("authorized_formats", String::from(source));
+	}
+
+	if let Some(recursive) = parsed_config.recursive {
+	    config.recursive = recursive;
+	    config.provenance.insert("recursive", String::from(source));
+	}
+
+	if let Some(max_depth) = parsed_config.max_depth {
+	    config.max_depth = max_depth;
+	    config.provenance.insert("max_depth", String::from(source));
+	}
+
+	if let Some(include) = parsed_config.include {
+	    config.include = include;
+	    config.provenance.insert("include", String::from(source));
+	}
+
+	if let Some(exclude) = parsed_config.exclude {
+	    config.exclude = exclude;
+	    config.provenance.insert("exclude", String::from(source));
+	}
+
+	if let Some(extension_prefilter) = parsed_config.extension_prefilter {
+	    config.extension_prefilter = extension_prefilter;
+	    config.provenance.insert("extension_prefilter", String::from(source));
+	}
+
+	if let Some(scan_threads) = parsed_config.scan_threads {
+	    config.scan_threads = scan_threads;
+	    config.provenance.insert("scan_threads", String::from(source));
+	}
+
+	if let Some(deduplicate) = parsed_config.deduplicate {
+	    config.deduplicate = deduplicate;
+	    config.provenance.insert("deduplicate", String::from(source));
+	}
+
+	if let Some(similarity_threshold) = parsed_config.similarity_threshold {
+	    config.similarity_threshold = similarity_threshold;
+	    config.provenance.insert("similarity_threshold", String::from(source));
+	}
+
+	if let Some(min_aspect_ratio) = parsed_config.min_aspect_ratio {
+	    config.filter.min_aspect_ratio = Some(min_aspect_ratio);
+	    config.provenance.insert("min_aspect_ratio", String::from(source));
 	}
 
+	if let Some(max_aspect_ratio) = parsed_config.max_aspect_ratio {
+	    config.filter.max_aspect_ratio = Some(max_aspect_ratio);
+	    config.provenance.insert("max_aspect_ratio", String::from(source));
+	}
+
+	if let Some(orientation) = parsed_config.orientation {
+	    config.filter.orientation = Some(orientation);
+	    config.provenance.insert("orientation", String::from(source));
+	}
+
+	if let Some(min_width) = parsed_config.min_width {
+	    config.filter.min_width = Some(min_width);
+	    config.provenance.insert("min_width", String::from(source));
+	}
+
+	if let Some(min_height) = parsed_config.min_height {
+	    config.filter.min_height = Some(min_height);
+	    config.provenance.insert("min_height", String::from(source));
+	}
+
+	if let Some(max_file_size) = parsed_config.max_file_size {
+	    config.filter.max_file_size = Some(max_file_size);
+	    config.provenance.insert("max_file_size", String::from(source));
+	}
+
+	if let Some(modified_after) = parsed_config.modified_after {
+	    config.filter.modified_after = Some(modified_after);
+	    config.provenance.insert("modified_after", String::from(source));
+	}
+
+	if let Some(modified_before) = parsed_config.modified_before {
+	    config.filter.modified_before = Some(modified_before);
+	    config.provenance.insert("modified_before", String::from(source));
+	}
+
+	if let Some(latitude) = parsed_config.latitude {
+	    config.latitude = Some(latitude);
+	    config.provenance.insert("latitude", String::from(source));
+	}
+
+	if let Some(longitude) = parsed_config.longitude {
+	    config.longitude = Some(longitude);
+	    config.provenance.insert("longitude", String::from(source));
+	}
+
+	// a schedule only makes sense as a whole, so the one read last replaces the previous ones
+	if let Some(parsed_schedule) = parsed_config.schedule {
+	    config.schedule = parsed_schedule
+		.into_iter()
+		.map(|parsed_window| ScheduleWindow {
+		    start: parsed_window.start,
+		    sources: parse_sources(parsed_window.path, parsed_window.sources).unwrap_or_default(),
+		})
+		.collect();
+	    config.provenance.insert("schedule", String::from(source));
+	}
+
+	// tables are appended so that those read last take precedence when several of them match
+	for parsed_output in parsed_config.output.unwrap_or_default() {
+	    config.outputs.push(OutputConfig {
+		name: parsed_output.name,
+		make: parsed_output.make,
+		model: parsed_output.model,
+		description: parsed_output.description,
+		sources: parse_sources(parsed_output.path, parsed_output.sources),
+		bg_duration_seconds: parsed_output.interval.or(parsed_output.bg_duration_seconds),
+		change_at: parsed_output.change_at,
+		fit: parsed_output.fit,
+		background_color: parsed_output.background_color,
+		source: String::from(source),
+	    });
+	}
+    }
+
+    pub fn scan_options(&self) -> ScanOptions<'_> {
+	ScanOptions {
+	    authorized_formats: &self.authorized_formats,
+	    recursive: self.recursive,
+	    max_depth: self.max_depth,
+	    include: &self.include,
+	    exclude: &self.exclude,
+	    extension_prefilter: self.extension_prefilter,
+	    scan_threads: self.scan_threads,
+	    deduplicate: self.deduplicate,
+	    similarity_threshold: self.similarity_threshold,
+	    filter: &self.filter,
+	}
+    }
+
+    pub fn location(&self) -> Option<Location> {
+	match (self.latitude, self.longitude) {
+	    (Some(latitude), Some(longitude)) => Some(Location { latitude, longitude }),
+	    _ => None,
+	}
+    }
+
+    // The sources of the active schedule window replace the global ones, and are themselves
+    // overridden by those of the [[output]] tables
+    pub fn settings_for(&self, output: &Output, active_window: Option<usize>) -> OutputSettings {
+	let sources = match active_window {
+	    Some(index) => &self.schedule[index].sources,
+	    None => &self.sources,
+	};
+	let mut settings = OutputSettings {
+	    sources: sources.clone(),
+	    bg_duration_seconds: self.bg_duration_seconds,
+	    change_at: self.change_at.clone(),
+	    fit: self.fit,
+	    background_color: self.background_color,
+	};
+
+	for output_config in self.outputs.iter().filter(|output_config| output_config.matches(output)) {
+	    if let Some(ref sources) = output_config.sources {
+		settings.sources = sources.clone();
+	    }
+	    if let Some(bg_duration_seconds) = output_config.bg_duration_seconds {
+		settings.bg_duration_seconds = bg_duration_seconds;
+	    }
+	    if let Some(ref change_at) = output_config.change_at {
+		settings.change_at = Some(change_at.clone());
+	    }
+	    if let Some(fit) = output_config.fit {
+		settings.fit = fit;
+	    }
+	    if let Some(background_color) = output_config.background_color {
+		settings.background_color = background_color;
+	    }
+	}
+
+	settings
+    }
+
+    // The directories in which config.toml and config.d/ are looked for, from the least important
+    // to the most important one
+    fn config_dirs() -> Vec<PathBuf> {
+	Config::ordered_config_dirs(&xdg::config_dirs(), &xdg::config_home())
+    }
+
+    // xdg_config_dirs is ordered from the most important directory to the least important one, as
+    // in $XDG_CONFIG_DIRS
+    fn ordered_config_dirs(xdg_config_dirs: &[PathBuf], config_home: &Path) -> Vec<PathBuf> {
+	let mut config_dirs = vec!(PathBuf::from("/etc/wl-bg-gallery"));
+	for config_dir in xdg_config_dirs.iter().rev() {
+	    config_dirs.push(config_dir.join("wl-bg-gallery"));
+	}
+	config_dirs.push(config_home.join("wl-bg-gallery"));
+	config_dirs
     }
 
-    pub fn get_config() -> Config {
+    // The *.toml files of a config.d directory, sorted by name. Hidden files are skipped, since
+    // that is what editors use for their lock and backup files.
+    fn drop_in_paths(drop_in_dir: &Path) -> Vec<PathBuf> {
+	let dir = match std::fs::read_dir(drop_in_dir) {
+	    Ok(dir) => dir,
+	    Err(_) => return Vec::new(),
+	};
+	let mut drop_in_paths: Vec<PathBuf> = dir
+	    .filter_map(|dir_entry| dir_entry.ok())
+	    .filter(|dir_entry| {
+		let file_name = dir_entry.file_name();
+		let file_name = file_name.to_string_lossy();
+		!file_name.starts_with('.') && file_name.ends_with(".toml")
+	    })
+	    .map(|dir_entry| dir_entry.path())
+	    .collect();
+	drop_in_paths.sort();
+	drop_in_paths
+    }
+
+    // The files that are merged on top of the default config, in order. An explicit config file
+    // (--config or $WL_BG_GALLERY_CONFIG) replaces the search entirely.
+    pub fn config_paths(explicit_path: &Option<PathBuf>) -> Vec<PathBuf> {
+	if let Some(explicit_path) = explicit_path {
+	    return vec!(explicit_path.clone());
+	}
+
+	Config::config_paths_in(&Config::config_dirs())
+    }
+
+    fn config_paths_in(config_dirs: &[PathBuf]) -> Vec<PathBuf> {
+	let mut config_paths = Vec::new();
+	for config_dir in config_dirs {
+	    config_paths.push(config_dir.join("config.toml"));
+	    config_paths.append(&mut Config::drop_in_paths(&config_dir.join("config.d")));
+	}
+	config_paths
+    }
+
+    // The paths whose creation, modification or removal should trigger a reload. The last
+    // component can be a glob pattern.
+    pub fn watched_paths(explicit_path: &Option<PathBuf>) -> Vec<PathBuf> {
+	if let Some(explicit_path) = explicit_path {
+	    return vec!(explicit_path.clone());
+	}
+
+	let mut watched_paths = Vec::new();
+	for config_dir in Config::config_dirs() {
+	    watched_paths.push(config_dir.join("config.toml"));
+	    // so that the drop-in directory gets watched once it is created
+	    watched_paths.push(config_dir.join("config.d"));
+	    watched_paths.push(config_dir.join("config.d/*.toml"));
+	}
+	watched_paths
+    }
+
+    pub fn load(explicit_path: &Option<PathBuf>) -> Result<Config, String> {
+	Config::load_paths(&Config::config_paths(explicit_path), explicit_path.is_some())
+    }
+
+    // Merges the files on top of the default config, the later ones win. Missing files are
+    // skipped, unless they were explicitly asked for.
+    fn load_paths(config_paths: &[PathBuf], explicit: bool) -> Result<Config, String> {
 	let mut config = Config::default();
-	let etc_parsed_conf = ParsedConfig::read_config(String::from("/etc/wl-bg-gallery/config.toml"));
-	match etc_parsed_conf {
-	    Ok(etc_parsed_conf) => Config::from(&mut config, etc_parsed_conf),
-	    Err(error) => {
-		match error {
-		    ReadConfigError::ReadError(_) => panic!("failed to read config"),
-		    ReadConfigError::ParseError(_) => panic!("failed to read config"),
-		    ReadConfigError::OpenError(_) => (),
+	for config_path in config_paths {
+	    match ParsedConfig::read_config(config_path) {
+		Ok(parsed_config) => Config::from(&mut config, parsed_config, &config_path.to_string_lossy()),
+		Err(error) => {
+		    match error {
+			ReadConfigError::ReadError(error) => return Err(error),
+			ReadConfigError::ParseError(error) => return Err(error),
+			ReadConfigError::NotFound(error) if explicit => return Err(error),
+			ReadConfigError::NotFound(_) => (),
+		    }
 		}
 	    }
-	};
+	}
+
+	config.validate()?;
+	Ok(config)
+    }
 
-	let home_parsed_conf = ParsedConfig::read_config(expand_tilde(String::from("~/.config/wl-bg-gallery/config.toml")));
-	match home_parsed_conf {
-	    Ok(home_parsed_conf) => Config::from(&mut config, home_parsed_conf),
-	    Err(error) => {
-		match error {
-		    ReadConfigError::ReadError(_) => panic!("failed to read config"),
-		    ReadConfigError::ParseError(_) => panic!("failed to read config"),
-		    ReadConfigError::OpenError(_) => (),
+    // Checks what cannot be checked while parsing a single file
+    fn validate(&self) -> Result<(), String> {
+	// images would change in a busy loop
+	if self.bg_duration_seconds == 0 {
+	    return Err(String::from("interval cannot be 0"));
+	}
+	for output_config in self.outputs.iter() {
+	    if output_config.bg_duration_seconds == Some(0) {
+		return Err(format!("[[output]] in {}: interval cannot be 0", output_config.source));
+	    }
+	}
+	if let Some(latitude) = self.latitude {
+	    if !(-90.0..=90.0).contains(&latitude) {
+		return Err(format!("latitude {} is not between -90 and 90", latitude));
+	    }
+	}
+	if let Some(longitude) = self.longitude {
+	    if !(-180.0..=180.0).contains(&longitude) {
+		return Err(format!("longitude {} is not between -180 and 180", longitude));
+	    }
+	}
+	if self.similarity_threshold > 64 {
+	    return Err(format!("similarity_threshold {} is above 64, every picture would be similar", self.similarity_threshold));
+	}
+	if let (Some(min_aspect_ratio), Some(max_aspect_ratio)) = (self.filter.min_aspect_ratio, self.filter.max_aspect_ratio) {
+	    if min_aspect_ratio > max_aspect_ratio {
+		return Err(format!(
+		    "min_aspect_ratio {} is above max_aspect_ratio {}, no picture would be used",
+		    min_aspect_ratio, max_aspect_ratio
+		));
+	    }
+	}
+	for window in self.schedule.iter() {
+	    if window.sources.is_empty() {
+		return Err(format!("[[schedule]] starting at {} has neither path nor sources", window.start));
+	    }
+	    if window.start.needs_location() && self.location().is_none() {
+		return Err(format!("[[schedule]] starting at {} needs latitude and longitude to be set", window.start));
+	    }
+	}
+	Ok(())
+    }
+
+    fn format_value(&self, key: &str) -> String {
+	match key {
+	    "sources" => format_sources(&self.sources),
+	    "interval" => format!("\"{}\"", format_duration(self.bg_duration_seconds)),
+	    "order" => format!("\"{:?}\"", self.order),
+	    "assignment" => format!("\"{:?}\"", self.assignment),
+	    "fit" => format!("\"{:?}\"", self.fit),
+	    "background_color" => format!("\"{}\"", format_color(&self.background_color)),
+	    "authorized_formats" => {
+		let names: Vec<String> = self.authorized_formats.iter().map(|format| format_name(*format)).collect();
+		format!("{:?}", names)
+	    },
+	    "recursive" => format!("{}", self.recursive),
+	    "max_depth" => format!("{}", self.max_depth),
+	    "include" => format!("{:?}", self.include),
+	    "exclude" => format!("{:?}", self.exclude),
+	    "extension_prefilter" => format!("{}", self.extension_prefilter),
+	    "scan_threads" => format!("{}", self.scan_threads),
+	    "deduplicate" => format!("\"{:?}\"", self.deduplicate),
+	    "similarity_threshold" => format!("{}", self.similarity_threshold),
+	    _ => String::from("?"),
+	}
+    }
+
+    // The effective config in TOML form, annotated with where each value comes from
+    pub fn explain(&self) -> String {
+	let mut explanation = String::new();
+	for key in CONFIG_KEYS {
+	    explanation.push_str(&format!("{} = {} # {}\n", key, self.format_value(key), self.provenance[key]));
+	}
+	if let Some(ref change_at) = self.change_at {
+	    explanation.push_str(&format!("change_at = \"{}\" # {}\n", change_at, self.provenance["change_at"]));
+	    if let Some(until_next) = change_at.until_next() {
+		explanation.push_str(&format!("# the next change is in {}\n", format_duration(until_next.as_secs())));
+	    }
+	}
+	if let Some(seed) = self.seed {
+	    explanation.push_str(&format!("seed = {} # {}\n", seed, self.provenance["seed"]));
+	}
+	if let Some(ref fallback_image) = self.fallback_image {
+	    explanation.push_str(&format!(
+		"fallback_image = {:?} # {}\n",
+		fallback_image.to_string_lossy(),
+		self.provenance["fallback_image"]
+	    ));
+	}
+	let filters = [
+	    ("min_aspect_ratio", self.filter.min_aspect_ratio.map(|ratio| format!("{:.4}", ratio))),
+	    ("max_aspect_ratio", self.filter.max_aspect_ratio.map(|ratio| format!("{:.4}", ratio))),
+	    ("orientation", self.filter.orientation.map(|orientation| format!("\"{:?}\"", orientation))),
+	    ("min_width", self.filter.min_width.map(|min_width| format!("{}", min_width))),
+	    ("min_height", self.filter.min_height.map(|min_height| format!("{}", min_height))),
+	    ("max_file_size", self.filter.max_file_size.map(|size| format!("\"{}\"", format_size(size)))),
+	    ("modified_after", self.filter.modified_after.as_ref().map(|time| format!("\"{}\"", time))),
+	    ("modified_before", self.filter.modified_before.as_ref().map(|time| format!("\"{}\"", time))),
+	];
+	for (key, value) in filters {
+	    if let Some(value) = value {
+		explanation.push_str(&format!("{} = {} # {}\n", key, value, self.provenance[key]));
+	    }
+	}
+	if let Some(latitude) = self.latitude {
+	    explanation.push_str(&format!("latitude = {:?} # {}\n", latitude, self.provenance["latitude"]));
+	}
+	if let Some(longitude) = self.longitude {
+	    explanation.push_str(&format!("longitude = {:?} # {}\n", longitude, self.provenance["longitude"]));
+	}
+
+	if let Some(location) = self.location() {
+	    let now = LocalTime::now();
+	    match sun_times(now.day_of_year, now.utc_offset_seconds, &location) {
+		Some((sunrise, sunset)) => explanation.push_str(&format!(
+		    "# today, sunrise is at {} and sunset at {}\n",
+		    TimeOfDay::Clock { seconds: sunrise },
+		    TimeOfDay::Clock { seconds: sunset }
+		)),
+		None => explanation.push_str("# today, the sun does not rise or does not set\n"),
+	    }
+	}
+
+	for window in self.schedule.iter() {
+	    explanation.push_str(&format!("\n[[schedule]] # {}\n", self.provenance["schedule"]));
+	    explanation.push_str(&format!("start = \"{}\"\n", window.start));
+	    explanation.push_str(&format!("sources = {}\n", format_sources(&window.sources)));
+	}
+
+	for output_config in self.outputs.iter() {
+	    explanation.push_str(&format!("\n[[output]] # {}\n", output_config.source));
+	    let matchers = [
+		("name", &output_config.name),
+		("make", &output_config.make),
+		("model", &output_config.model),
+		("description", &output_config.description),
+	    ];
+	    for (key, matcher) in matchers {
+		if let Some(matcher) = matcher {
+		    explanation.push_str(&format!("{} = {}\n", key, matcher));
 		}
 	    }
-	};
+	    if let Some(ref sources) = output_config.sources {
+		explanation.push_str(&format!("sources = {}\n", format_sources(sources)));
+	    }
+	    if let Some(bg_duration_seconds) = output_config.bg_duration_seconds {
+		explanation.push_str(&format!("interval = \"{}\"\n", format_duration(bg_duration_seconds)));
+	    }
+	    if let Some(ref change_at) = output_config.change_at {
+		explanation.push_str(&format!("change_at = \"{}\"\n", change_at));
+	    }
+	    if let Some(fit) = output_config.fit {
+		explanation.push_str(&format!("fit = \"{:?}\"\n", fit));
+	    }
+	    if let Some(ref background_color) = output_config.background_color {
+		explanation.push_str(&format!("background_color = \"{}\"\n", format_color(background_color)));
+	    }
+	}
+
+	explanation
+    }
+
+    pub fn get_config(explicit_path: &Option<PathBuf>) -> Config {
+	match Config::load(explicit_path) {
+	    Ok(config) => config,
+	    Err(error) => panic!("failed to read config: {}", error),
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory of config files for one test, removed when the test is over
+    struct ConfigDir(PathBuf);
+
+    impl ConfigDir {
+	fn new(name: &str) -> ConfigDir {
+	    let dir = std::env::temp_dir().join(format!("wl-bg-gallery-{}-{}", name, std::process::id()));
+	    let _ = std::fs::remove_dir_all(&dir);
+	    std::fs::create_dir_all(&dir).unwrap();
+	    ConfigDir(dir)
+	}
+
+	fn write(&self, name: &str, contents: &str) -> PathBuf {
+	    let path = self.0.join(name);
+	    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+	    std::fs::write(&path, contents).unwrap();
+	    path
+	}
+    }
+
+    impl Drop for ConfigDir {
+	fn drop(&mut self) {
+	    let _ = std::fs::remove_dir_all(&self.0);
+	}
+    }
 
-	return config;
+    #[test]
+    fn config_dirs_from_least_to_most_important() {
+	let xdg_config_dirs = [PathBuf::from("/etc/xdg/first"), PathBuf::from("/etc/xdg/second")];
+	assert_eq!(
+	    Config::ordered_config_dirs(&xdg_config_dirs, Path::new("/home/user/.config")),
+	    [
+		PathBuf::from("/etc/wl-bg-gallery"),
+		PathBuf::from("/etc/xdg/second/wl-bg-gallery"),
+		PathBuf::from("/etc/xdg/first/wl-bg-gallery"),
+		PathBuf::from("/home/user/.config/wl-bg-gallery"),
+	    ]
+	);
+    }
+
+    #[test]
+    fn later_files_win() {
+	let dir = ConfigDir::new("config-layers");
+	let system = dir.write("etc/config.toml", "interval = \"1m\"\nfit = \"Fill\"\nrecursive = true\n");
+	let user = dir.write("home/config.toml", "interval = \"5m\"\n");
+	let config = Config::load_paths(&[system.clone(), dir.0.join("missing/config.toml"), user.clone()], false).unwrap();
+	assert_eq!(config.bg_duration_seconds, 300);
+	assert_eq!(config.provenance["interval"], user.to_string_lossy());
+	// what the user config does not set comes from the system one, or is the default
+	assert_eq!(config.fit, FitMode::Fill);
+	assert_eq!(config.provenance["fit"], system.to_string_lossy());
+	assert!(config.recursive);
+	assert_eq!(config.order, DEFAULT_ORDER);
+	assert_eq!(config.provenance["order"], "default");
+    }
+
+    #[test]
+    fn config_read_errors() {
+	let dir = ConfigDir::new("config-errors");
+	// a missing file is only an error when it was asked for
+	let missing = dir.0.join("missing.toml");
+	assert!(Config::load_paths(std::slice::from_ref(&missing), false).is_ok());
+	assert!(Config::load_paths(std::slice::from_ref(&missing), true).unwrap_err().contains("missing.toml"));
+	// one that cannot be read is always an error
+	let unreadable = dir.0.join("directory.toml");
+	std::fs::create_dir_all(&unreadable).unwrap();
+	let error = Config::load_paths(&[unreadable], false).unwrap_err();
+	assert!(error.starts_with("failed to read ") && error.contains("directory.toml"), "{}", error);
+	let zero = dir.write("zero.toml", "interval = 0\n");
+	assert_eq!(Config::load_paths(&[zero], false).unwrap_err(), "interval cannot be 0");
+    }
+
+    #[test]
+    fn drop_ins_come_after_their_config_file() {
+	let dir = ConfigDir::new("config-drop-ins");
+	let system = dir.0.join("etc");
+	let user = dir.0.join("home");
+	dir.write("etc/config.toml", "");
+	dir.write("etc/config.d/20-second.toml", "");
+	dir.write("etc/config.d/10-first.toml", "");
+	dir.write("etc/config.d/.10-first.toml.swp", "");
+	dir.write("etc/config.d/.hidden.toml", "");
+	dir.write("etc/config.d/notes.txt", "");
+	std::fs::create_dir_all(user.join("config.d")).unwrap();
+	dir.write("home/config.d/00-early.toml", "");
+	assert_eq!(
+	    Config::config_paths_in(&[system.clone(), user.clone()]),
+	    [
+		system.join("config.toml"),
+		system.join("config.d/10-first.toml"),
+		system.join("config.d/20-second.toml"),
+		// a config.toml is looked for even if it does not exist
+		user.join("config.toml"),
+		user.join("config.d/00-early.toml"),
+	    ]
+	);
+	assert_eq!(
+	    Config::config_paths(&Some(PathBuf::from("/tmp/config.toml"))),
+	    [PathBuf::from("/tmp/config.toml")]
+	);
+    }
+
+    #[test]
+    fn drop_ins_override_in_order() {
+	let dir = ConfigDir::new("config-drop-in-order");
+	dir.write("etc/config.toml", "interval = \"1m\"\nfit = \"Fill\"\norder = \"Shuffle\"\n");
+	dir.write("etc/config.d/10-fit.toml", "fit = \"Center\"\ninterval = \"2m\"\n");
+	let last = dir.write("etc/config.d/20-interval.toml", "interval = \"3m\"\n");
+	let user = dir.write("home/config.toml", "order = \"Random\"\n");
+	let config_paths = Config::config_paths_in(&[dir.0.join("etc"), dir.0.join("home")]);
+	let config = Config::load_paths(&config_paths, false).unwrap();
+	assert_eq!(config.bg_duration_seconds, 180);
+	assert_eq!(config.provenance["interval"], last.to_string_lossy());
+	assert_eq!(config.fit, FitMode::Center);
+	assert_eq!(config.order, Order::Random);
+	assert_eq!(config.provenance["order"], user.to_string_lossy());
+    }
 
+    fn parse_error(config_str: &str) -> String {
+	let error = toml::from_str::<ParsedConfig>(config_str).err().unwrap();
+	format_parse_error("/etc/wl-bg-gallery/config.toml", config_str, &error)
+    }
+
+    #[test]
+    fn parse_error_locations() {
+	let error = parse_error("interval = \"5m\"\n\nrecursiv = true\n");
+	assert!(error.starts_with("/etc/wl-bg-gallery/config.toml:3:1: unknown field `recursiv`"), "{}", error);
+	// columns count characters, not bytes
+	let error = parse_error("# éè\nfit = \"Fill\"\nbackground_color = \"#12345\"\n");
+	assert!(error.starts_with("/etc/wl-bg-gallery/config.toml:3:20: "), "{}", error);
+	let error = parse_error("path = \"/é/\"\norder = Shuffle\n");
+	assert!(error.starts_with("/etc/wl-bg-gallery/config.toml:2:9: "), "{}", error);
+	// and the first line is line 1
+	let error = parse_error("interval = \"soon\"");
+	assert!(error.starts_with("/etc/wl-bg-gallery/config.toml:1:12: "), "{}", error);
+    }
+
+    #[test]
+    fn file_errors_have_a_location() {
+	let dir = ConfigDir::new("config-parse-errors");
+	let good = dir.write("config.toml", "interval = \"5m\"\n");
+	let bad = dir.write("config.d/10-typo.toml", "\nfit = \"Streched\"\n");
+	let error = Config::load_paths(&[good, bad.clone()], false).unwrap_err();
+	assert!(error.starts_with(&format!("{}:2:7: ", bad.to_string_lossy())), "{}", error);
+	// what serde cannot check is reported with the file too
+	let both = dir.write("both.toml", "path = \"/pictures\"\nsources = [{ path = \"/photos\" }]\n");
+	let error = Config::load_paths(std::slice::from_ref(&both), false).unwrap_err();
+	assert_eq!(error, format!("{}: both path and sources are set, only one of them can be", both.to_string_lossy()));
+    }
+
+    #[test]
+    fn explain_names_the_layer_of_each_key() {
+	let dir = ConfigDir::new("config-explain");
+	let system = dir.write("etc/config.toml", "interval = \"1m\"\nfit = \"Fill\"\nmin_width = 1920\n");
+	let user = dir.write("home/config.toml", "interval = \"5m\"\nexclude = [\"*.gif\"]\n");
+	let explanation = Config::load_paths(&[system.clone(), user.clone()], false).unwrap().explain();
+	let line = |key: &str| {
+	    let prefix = format!("{} = ", key);
+	    explanation.lines().find(|line| line.starts_with(&prefix)).map(String::from)
+	};
+	assert_eq!(line("interval"), Some(format!("interval = \"5m\" # {}", user.to_string_lossy())));
+	assert_eq!(line("exclude"), Some(format!("exclude = [\"*.gif\"] # {}", user.to_string_lossy())));
+	assert_eq!(line("fit"), Some(format!("fit = \"Fill\" # {}", system.to_string_lossy())));
+	assert_eq!(line("min_width"), Some(format!("min_width = 1920 # {}", system.to_string_lossy())));
+	assert_eq!(line("order"), Some(String::from("order = \"Sequential\" # default")));
+	// the filters that are not set are left out
+	assert_eq!(line("min_height"), None);
+	// every key the config always has is there
+	for key in CONFIG_KEYS {
+	    assert!(line(key).is_some(), "{}", key);
+	}
     }
 }
@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+// How far ahead we look for a matching minute, "0 0 29 2 *" may only match every four years
+const MAX_DAYS_AHEAD: i32 = 4 * 366 + 1;
+
+const MONTH_NAMES: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// A cron expression with the usual five fields (minute, hour, day of month, month, day of week),
+// evaluated in local time. Each field is stored as a bit mask of the values it matches.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct CronExpr {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    // when both day fields are restricted, a day matches if either of them does, as in cron(8)
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+fn parse_value(value: &str, names: &[&str], first_name_value: u32) -> Option<u32> {
+    if let Ok(value) = value.parse() {
+	return Some(value);
+    }
+    let value = value.to_lowercase();
+    names
+	.iter()
+	.position(|name| *name == value)
+	.map(|index| index as u32 + first_name_value)
+}
+
+// Parses one field, e.g. "*", "*/15", "1-5", "mon-fri" or "0,30"
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str], first_name_value: u32) -> Option<u64> {
+    let mut mask = 0;
+    for item in field.split(',') {
+	let (range, step) = match item.split_once('/') {
+	    Some((range, step)) => match step.parse::<u32>() {
+		Ok(step) if step > 0 => (range, step),
+		_ => return None,
+	    },
+	    None => (item, 1),
+	};
+	let (start, end) = if range == "*" {
+	    (min, max)
+	} else if let Some((start, end)) = range.split_once('-') {
+	    (parse_value(start, names, first_name_value)?, parse_value(end, names, first_name_value)?)
+	} else {
+	    let start = parse_value(range, names, first_name_value)?;
+	    // "5/10" means from 5 to the end, every 10
+	    if step > 1 { (start, max) } else { (start, start) }
+	};
+	if start < min || end > max || start > end {
+	    return None;
+	}
+	for value in (start..=end).step_by(step as usize) {
+	    mask |= 1 << value;
+	}
+    }
+    Some(mask)
+}
+
+impl TryFrom<String> for CronExpr {
+    type Error = String;
+
+    fn try_from(expression: String) -> Result<CronExpr, String> {
+	let expanded = String::from(match expression.as_str() {
+	    "@hourly" => "0 * * * *",
+	    "@daily" | "@midnight" => "0 0 * * *",
+	    "@weekly" => "0 0 * * 0",
+	    "@monthly" => "0 0 1 * *",
+	    "@yearly" | "@annually" => "0 0 1 1 *",
+	    expression => expression,
+	});
+	let fields: Vec<&str> = expanded.split_whitespace().collect();
+	if fields.len() != 5 {
+	    return Err(format!(
+		"invalid cron expression {}, expected 5 fields (minute hour day-of-month month day-of-week)",
+		expression
+	    ));
+	}
+	let invalid_field = |name: &str, field: &str| format!("invalid {} field {} in cron expression {}", name, field, expression);
+
+	let minutes = parse_field(fields[0], 0, 59, &[], 0).ok_or_else(|| invalid_field("minute", fields[0]))?;
+	let hours = parse_field(fields[1], 0, 23, &[], 0).ok_or_else(|| invalid_field("hour", fields[1]))?;
+	let days_of_month = parse_field(fields[2], 1, 31, &[], 0).ok_or_else(|| invalid_field("day of month", fields[2]))?;
+	let months = parse_field(fields[3], 1, 12, &MONTH_NAMES, 1).ok_or_else(|| invalid_field("month", fields[3]))?;
+	let mut days_of_week = parse_field(fields[4], 0, 7, &DAY_NAMES, 0).ok_or_else(|| invalid_field("day of week", fields[4]))?;
+	// 7 is another name for sunday
+	if days_of_week & (1 << 7) != 0 {
+	    days_of_week |= 1;
+	}
+
+	Ok(CronExpr {
+	    expression,
+	    minutes,
+	    hours,
+	    days_of_month,
+	    months,
+	    days_of_week,
+	    days_of_month_restricted: !fields[2].starts_with('*'),
+	    days_of_week_restricted: !fields[4].starts_with('*'),
+	})
+    }
+}
+
+impl std::fmt::Display for CronExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	write!(f, "{}", self.expression)
+    }
+}
+
+impl CronExpr {
+    fn day_matches(&self, day: &libc::tm) -> bool {
+	if self.months & (1 << (day.tm_mon + 1)) == 0 {
+	    return false;
+	}
+	let day_of_month_matches = self.days_of_month & (1 << day.tm_mday) != 0;
+	let day_of_week_matches = self.days_of_week & (1 << day.tm_wday) != 0;
+	if self.days_of_month_restricted && self.days_of_week_restricted {
+	    day_of_month_matches || day_of_week_matches
+	} else {
+	    day_of_month_matches && day_of_week_matches
+	}
+    }
+
+    // The time until the next minute matched by the expression. Matches in the coming second are
+    // skipped, so that a change made right on time does not immediately schedule itself again.
+    pub fn until_next(&self) -> Option<Duration> {
+	unsafe {
+	    let now = libc::time(std::ptr::null_mut());
+	    let mut today: libc::tm = std::mem::zeroed();
+	    if libc::localtime_r(&now, &mut today).is_null() {
+		panic!("localtime_r failed");
+	    }
+
+	    for day_offset in 0..MAX_DAYS_AHEAD {
+		// mktime normalizes the day of month, and computes the day of week for us
+		let mut day = today;
+		day.tm_mday += day_offset;
+		day.tm_hour = 0;
+		day.tm_min = 0;
+		day.tm_sec = 0;
+		day.tm_isdst = -1;
+		if libc::mktime(&mut day) == -1 || !self.day_matches(&day) {
+		    continue;
+		}
+
+		for hour in (0..24).filter(|hour| self.hours & (1 << hour) != 0) {
+		    for minute in (0..60).filter(|minute| self.minutes & (1 << minute) != 0) {
+			let mut candidate = day;
+			candidate.tm_hour = hour;
+			candidate.tm_min = minute;
+			candidate.tm_sec = 0;
+			candidate.tm_isdst = -1;
+			let time = libc::mktime(&mut candidate);
+			if time > now + 1 {
+			    return Some(Duration::from_secs((time - now) as u64));
+			}
+		    }
+		}
+	    }
+	}
+	None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask(values: &[u32]) -> u64 {
+	values.iter().fold(0, |mask, value| mask | 1 << value)
+    }
+
+    #[test]
+    fn fields() {
+	assert_eq!(parse_field("*", 0, 59, &[], 0), Some(mask(&(0..=59).collect::<Vec<u32>>())));
+	assert_eq!(parse_field("5", 0, 59, &[], 0), Some(mask(&[5])));
+	assert_eq!(parse_field("1-3,7", 0, 23, &[], 0), Some(mask(&[1, 2, 3, 7])));
+	assert_eq!(parse_field("mon-fri", 0, 7, &DAY_NAMES, 0), Some(mask(&[1, 2, 3, 4, 5])));
+	assert_eq!(parse_field("Jan,dec", 1, 12, &MONTH_NAMES, 1), Some(mask(&[1, 12])));
+    }
+
+    #[test]
+    fn steps() {
+	assert_eq!(parse_field("*/15", 0, 59, &[], 0), Some(mask(&[0, 15, 30, 45])));
+	assert_eq!(parse_field("10-20/5", 0, 59, &[], 0), Some(mask(&[10, 15, 20])));
+	assert_eq!(parse_field("50/5", 0, 59, &[], 0), Some(mask(&[50, 55])));
+	assert_eq!(parse_field("*/0", 0, 59, &[], 0), None);
+	assert_eq!(parse_field("*/x", 0, 59, &[], 0), None);
+    }
+
+    #[test]
+    fn out_of_range_fields() {
+	assert_eq!(parse_field("60", 0, 59, &[], 0), None);
+	assert_eq!(parse_field("0", 1, 31, &[], 0), None);
+	assert_eq!(parse_field("5-2", 0, 23, &[], 0), None);
+	assert_eq!(parse_field("13", 1, 12, &MONTH_NAMES, 1), None);
+	assert_eq!(parse_field("monday", 0, 7, &DAY_NAMES, 0), None);
+    }
+
+    #[test]
+    fn expressions() {
+	let expression = CronExpr::try_from(String::from("0 9 * * 7")).unwrap();
+	// 7 is sunday too
+	assert_eq!(expression.days_of_week, mask(&[0, 7]));
+	assert!(!expression.days_of_month_restricted);
+	assert!(expression.days_of_week_restricted);
+	assert_eq!(CronExpr::try_from(String::from("@hourly")).unwrap().minutes, mask(&[0]));
+	assert!(CronExpr::try_from(String::from("0 9 * *")).is_err());
+	assert!(CronExpr::try_from(String::from("0 24 * * *")).is_err());
+	assert!(CronExpr::try_from(String::from("* * * * *")).unwrap().until_next().unwrap() <= Duration::from_secs(61));
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use image::{imageops::FilterType, DynamicImage, GrayImage, ImageFormat, ImageReader, Limits, RgbImage};
+use jpeg_decoder::PixelFormat;
+use serde::Deserialize;
+
+use crate::image_file::{ImageFile, ScanOptions};
+use crate::metadata_cache::MetadataCache;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+// the size of the thumbnail the perceptual hash is computed from
+const THUMBNAIL_SIZE: u16 = 64;
+// the pictures that need more memory than that to be decoded are only deduplicated by content,
+// several of them can be decoded at once by the scan threads
+const HASH_MAX_ALLOC: u64 = 128 * 1024 * 1024;
+
+// Which pictures are considered to be the same
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Deduplicate {
+    Off,
+    // files with the same contents
+    Exact,
+    // pictures that look alike, e.g. the same picture saved at another size or quality
+    Similar,
+}
+
+// The hashes of a picture, those that were not needed yet are not computed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImageHashes {
+    // FNV-1a of the contents of the file
+    pub content: Option<u64>,
+    // dHash: whether each pixel of a 9x8 grayscale thumbnail is brighter than its right neighbour
+    pub perceptual: Option<u64>,
+}
+
+// path is only used in messages
+pub fn content_hash<R: Read>(path: &Path, mut reader: R) -> Option<u64> {
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+	let length = match reader.read(&mut buffer) {
+	    Ok(0) => return Some(hash),
+	    Ok(length) => length,
+	    Err(error) => {
+		println!("could not hash {} : {}", path.to_string_lossy(), error);
+		return None;
+	    }
+	};
+	for byte in &buffer[..length] {
+	    hash ^= *byte as u64;
+	    hash = hash.wrapping_mul(FNV_PRIME);
+	}
+    }
+}
+
+// JPEG pictures are decoded at down to an eighth of their size, straight from their DCT
+// coefficients. None if the picture has to go through the usual decoder.
+fn decode_scaled_jpeg<R: Read>(reader: R) -> Option<DynamicImage> {
+    let mut decoder = jpeg_decoder::Decoder::new(reader);
+    decoder.scale(THUMBNAIL_SIZE, THUMBNAIL_SIZE).ok()?;
+    let pixels = decoder.decode().ok()?;
+    let info = decoder.info()?;
+    let (width, height) = (info.width as u32, info.height as u32);
+    match info.pixel_format {
+	PixelFormat::L8 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+	PixelFormat::RGB24 => RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+	PixelFormat::L16 | PixelFormat::CMYK32 => None,
+    }
+}
+
+// Decodes the picture as small as its format allows
+fn decode_small<R: BufRead + Seek>(image_reader: ImageReader<R>) -> Result<DynamicImage, String> {
+    let mut image_reader = image_reader.with_guessed_format().map_err(|error| error.to_string())?;
+    if image_reader.format() == Some(ImageFormat::Jpeg) {
+	let mut reader = image_reader.into_inner();
+	let start = reader.stream_position().map_err(|error| error.to_string())?;
+	if let Some(image) = decode_scaled_jpeg(&mut reader) {
+	    return Ok(image);
+	}
+	reader.seek(SeekFrom::Start(start)).map_err(|error| error.to_string())?;
+	image_reader = ImageReader::with_format(reader, ImageFormat::Jpeg);
+    }
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(HASH_MAX_ALLOC);
+    image_reader.limits(limits);
+    image_reader.decode().map_err(|error| error.to_string())
+}
+
+fn perceptual_hash<R: BufRead + Seek>(path: &Path, image_reader: ImageReader<R>) -> Option<u64> {
+    let image = match decode_small(image_reader) {
+	Ok(image) => image,
+	Err(error) => {
+	    println!("could not hash {} : {}", path.to_string_lossy(), error);
+	    return None;
+	}
+    };
+    // a cheap downscale first, the resize that follows is then fast whatever the size of the picture
+    let thumbnail = image
+	.thumbnail(THUMBNAIL_SIZE as u32, THUMBNAIL_SIZE as u32)
+	.resize_exact(9, 8, FilterType::Triangle)
+	.to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+	for x in 0..8 {
+	    hash <<= 1;
+	    if thumbnail.get_pixel(x, y)[0] > thumbnail.get_pixel(x + 1, y)[0] {
+		hash |= 1;
+	    }
+	}
+    }
+    Some(hash)
+}
+
+impl ImageHashes {
+    pub fn is_complete(&self, deduplicate: Deduplicate) -> bool {
+	match deduplicate {
+	    Deduplicate::Off => true,
+	    Deduplicate::Exact => self.content.is_some(),
+	    Deduplicate::Similar => self.content.is_some() && self.perceptual.is_some(),
+	}
+    }
+
+    // Computes the hashes that deduplicate needs and that are missing
+    pub fn complete(&mut self, path: &Path, deduplicate: Deduplicate) {
+	if deduplicate == Deduplicate::Off {
+	    return;
+	}
+	if self.content.is_none() {
+	    self.content = match File::open(path) {
+		Ok(file) => content_hash(path, file),
+		Err(error) => {
+		    println!("could not hash {} : {}", path.to_string_lossy(), error);
+		    None
+		}
+	    };
+	}
+	if deduplicate == Deduplicate::Similar && self.perceptual.is_none() {
+	    self.perceptual = match ImageReader::open(path) {
+		Ok(image_reader) => perceptual_hash(path, image_reader),
+		Err(error) => {
+		    println!("could not hash {} : {}", path.to_string_lossy(), error);
+		    None
+		}
+	    };
+	}
+    }
+
+    // Same as complete, for an image that was read in memory
+    pub fn complete_from_data(&mut self, path: &Path, data: &[u8], deduplicate: Deduplicate) {
+	if deduplicate == Deduplicate::Off {
+	    return;
+	}
+	if self.content.is_none() {
+	    self.content = content_hash(path, data);
+	}
+	if deduplicate == Deduplicate::Similar && self.perceptual.is_none() {
+	    self.perceptual = perceptual_hash(path, ImageReader::new(Cursor::new(data)));
+	}
+    }
+
+    fn matches(&self, other: &ImageHashes, scan_options: &ScanOptions) -> bool {
+	if self.content.is_some() && self.content == other.content {
+	    return true;
+	}
+	match (scan_options.deduplicate, self.perceptual, other.perceptual) {
+	    (Deduplicate::Similar, Some(perceptual), Some(other_perceptual)) => {
+		(perceptual ^ other_perceptual).count_ones() <= scan_options.similarity_threshold
+	    }
+	    _ => false,
+	}
+    }
+}
+
+// The hashes of the pictures kept so far, in the same order. Exact duplicates are looked up by
+// content hash, only similar pictures have to be compared with every kept one.
+#[derive(Default)]
+struct KeptHashes {
+    hashes: Vec<ImageHashes>,
+    by_content: HashMap<u64, usize>,
+}
+
+impl KeptHashes {
+    fn new(kept: &[ImageFile], metadata_cache: &MetadataCache) -> Self {
+	let mut kept_hashes = KeptHashes::default();
+	for (index, image_file) in kept.iter().enumerate() {
+	    kept_hashes.set(index, metadata_cache.hashes(&image_file.path));
+	}
+	kept_hashes
+    }
+
+    // The index of the kept picture that hashes is a duplicate of
+    fn find(&self, hashes: &ImageHashes, scan_options: &ScanOptions) -> Option<usize> {
+	if let Some(index) = hashes.content.and_then(|content| self.by_content.get(&content)) {
+	    return Some(*index);
+	}
+	if scan_options.deduplicate != Deduplicate::Similar || hashes.perceptual.is_none() {
+	    return None;
+	}
+	self.hashes.iter().position(|kept_hashes| kept_hashes.matches(hashes, scan_options))
+    }
+
+    // Records the hashes of the picture kept at index, which may be the next one
+    fn set(&mut self, index: usize, hashes: ImageHashes) {
+	if index == self.hashes.len() {
+	    self.hashes.push(hashes);
+	} else {
+	    self.hashes[index] = hashes;
+	}
+	if let Some(content) = hashes.content {
+	    self.by_content.insert(content, index);
+	}
+    }
+}
+
+// Adds image_file to the images that were kept so far, unless it is a duplicate of one of them. Of
+// two duplicates, the one with the most pixels is kept, at the position of the first one.
+fn keep_unique(
+    kept: &mut Vec<ImageFile>,
+    kept_hashes: &mut KeptHashes,
+    image_file: ImageFile,
+    hashes: ImageHashes,
+    scan_options: &ScanOptions,
+) {
+    let index = match kept_hashes.find(&hashes, scan_options) {
+	Some(index) => index,
+	None => {
+	    kept_hashes.set(kept.len(), hashes);
+	    kept.push(image_file);
+	    return;
+	}
+    };
+    let pixels = |image_file: &ImageFile| image_file.width as u64 * image_file.height as u64;
+    if pixels(&image_file) > pixels(&kept[index]) {
+	println!(
+	    "{} is a duplicate of {}, which is larger",
+	    kept[index].path.to_string_lossy(),
+	    image_file.path.to_string_lossy()
+	);
+	kept[index] = image_file;
+	kept_hashes.set(index, hashes);
+    } else {
+	println!("{} is a duplicate of {}", image_file.path.to_string_lossy(), kept[index].path.to_string_lossy());
+    }
+}
+
+// Keeps one picture out of each group of duplicates
+pub fn deduplicate(image_list: Vec<ImageFile>, metadata_cache: &MetadataCache, scan_options: &ScanOptions) -> Vec<ImageFile> {
+    if scan_options.deduplicate == Deduplicate::Off {
+	return image_list;
+    }
+    let mut kept = Vec::new();
+    let mut kept_hashes = KeptHashes::default();
+    for image_file in image_list {
+	let hashes = metadata_cache.hashes(&image_file.path);
+	keep_unique(&mut kept, &mut kept_hashes, image_file, hashes, scan_options);
+    }
+    kept
+}
+
+// Same as deduplicate, for pictures added to a list that already has no duplicates
+pub fn add_unique(
+    image_list: &mut Vec<ImageFile>,
+    image_files: Vec<ImageFile>,
+    metadata_cache: &MetadataCache,
+    scan_options: &ScanOptions,
+) {
+    if scan_options.deduplicate == Deduplicate::Off {
+	image_list.extend(image_files);
+	return;
+    }
+    let mut kept_hashes = KeptHashes::new(image_list, metadata_cache);
+    for image_file in image_files {
+	let hashes = metadata_cache.hashes(&image_file.path);
+	keep_unique(image_list, &mut kept_hashes, image_file, hashes, scan_options);
+    }
+}
@@ -0,0 +1,95 @@
+// Human-friendly durations: "90s", "5m", "1h30m", "1d"... A plain number is a number of seconds.
+
+const UNITS: [(char, u64); 4] = [('d', 24 * 60 * 60), ('h', 60 * 60), ('m', 60), ('s', 1)];
+
+pub fn parse_duration(duration: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid duration {}, expected something like \"90s\", \"5m\" or \"1h30m\"", duration);
+    let duration = duration.trim();
+    if duration.is_empty() {
+	return Err(invalid());
+    }
+    if let Ok(seconds) = duration.parse::<u64>() {
+	return Ok(seconds);
+    }
+
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for c in duration.chars() {
+	if c.is_ascii_digit() {
+	    number.push(c);
+	    continue;
+	}
+	let unit_seconds = match UNITS.iter().find(|(unit, _)| *unit == c) {
+	    Some((_, unit_seconds)) => *unit_seconds,
+	    None => return Err(invalid()),
+	};
+	let value: u64 = match number.parse() {
+	    Ok(value) => value,
+	    Err(_) => return Err(invalid()),
+	};
+	seconds = match value.checked_mul(unit_seconds).and_then(|value| seconds.checked_add(value)) {
+	    Some(seconds) => seconds,
+	    None => return Err(invalid()),
+	};
+	number.clear();
+    }
+    // a number without unit at the end, as in "1h30"
+    if !number.is_empty() {
+	return Err(invalid());
+    }
+    Ok(seconds)
+}
+
+pub fn format_duration(seconds: u64) -> String {
+    if seconds == 0 {
+	return String::from("0s");
+    }
+    let mut formatted = String::new();
+    let mut remaining = seconds;
+    for (unit, unit_seconds) in UNITS {
+	if remaining >= unit_seconds {
+	    formatted.push_str(&format!("{}{}", remaining / unit_seconds, unit));
+	    remaining %= unit_seconds;
+	}
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+	assert_eq!(parse_duration("1h30m"), Ok(5400));
+	assert_eq!(parse_duration("90s"), Ok(90));
+	assert_eq!(parse_duration("1d"), Ok(86400));
+	assert_eq!(parse_duration(" 600 "), Ok(600));
+	assert_eq!(parse_duration("0"), Ok(0));
+	assert_eq!(parse_duration("0s"), Ok(0));
+    }
+
+    #[test]
+    fn invalid_durations() {
+	for duration in ["", "h", "1x", "1h30", "1.5h", "-5m", "1 h"] {
+	    assert!(parse_duration(duration).is_err(), "{:?} was accepted", duration);
+	}
+    }
+
+    #[test]
+    fn overflowing_durations() {
+	assert!(parse_duration("18446744073709551616").is_err());
+	assert!(parse_duration("999999999999999999d").is_err());
+	assert!(parse_duration("18446744073709551615s1s").is_err());
+    }
+
+    #[test]
+    fn formatted_durations_parse_back() {
+	assert_eq!(format_duration(0), "0s");
+	assert_eq!(format_duration(5400), "1h30m");
+	assert_eq!(format_duration(90061), "1d1h1m1s");
+	for seconds in [0, 1, 59, 60, 61, 3599, 3600, 5400, 86399, 86400, 90061, u64::MAX] {
+	    assert_eq!(parse_duration(&format_duration(seconds)), Ok(seconds));
+	}
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    env,
+    ffi::{CStr, CString, OsStr, OsString},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::PathBuf,
+};
+
+use crate::xdg;
+
+// Expansion of the paths found in the config, the way a shell would do it: a leading `~` or
+// `~user`, `$VAR` and `${VAR}` (`$$` for a literal `$`). The xdg-user-dirs variables, such as
+// $XDG_PICTURES_DIR, are read from user-dirs.dirs when they are not in the environment. Values are
+// handled as bytes, so that directory names do not have to be valid UTF-8.
+
+// The home directory of another user, from the password database
+fn user_home(user: &str) -> Option<PathBuf> {
+    let name = CString::new(user).ok()?;
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    unsafe {
+	let mut passwd: libc::passwd = std::mem::zeroed();
+	let mut result: *mut libc::passwd = std::ptr::null_mut();
+	let error = libc::getpwnam_r(name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result);
+	if error != 0 || result.is_null() {
+	    return None;
+	}
+	let dir = CStr::from_ptr(passwd.pw_dir);
+	Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())))
+    }
+}
+
+fn variable(name: &str) -> Option<OsString> {
+    match env::var_os(name) {
+	Some(value) if !value.is_empty() => Some(value),
+	_ if name.starts_with("XDG_") && name.ends_with("_DIR") => xdg::user_dir(name).map(|path| path.into_os_string()),
+	_ => None,
+    }
+}
+
+pub fn expand_path(path: &str) -> Result<PathBuf, String> {
+    let mut expanded: Vec<u8> = Vec::new();
+    let mut rest = path;
+
+    if let Some(after_tilde) = path.strip_prefix('~') {
+	let (user, after_user) = match after_tilde.find('/') {
+	    Some(index) => after_tilde.split_at(index),
+	    None => (after_tilde, ""),
+	};
+	let home = if user.is_empty() {
+	    xdg::home()
+	} else {
+	    match user_home(user) {
+		Some(home) => home,
+		None => return Err(format!("unknown user {} in path {}", user, path)),
+	    }
+	};
+	expanded.extend_from_slice(home.as_os_str().as_bytes());
+	// so that a home of "/" does not give "//..."
+	if expanded.ends_with(b"/") && after_user.starts_with('/') {
+	    expanded.pop();
+	}
+	rest = after_user;
+    }
+
+    let mut chars = rest.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+	if c != '$' {
+	    let mut buffer = [0; 4];
+	    expanded.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+	    continue;
+	}
+
+	let name = match chars.peek() {
+	    Some((_, '$')) => {
+		chars.next();
+		expanded.push(b'$');
+		continue;
+	    }
+	    Some((_, '{')) => match rest[index..].find('}') {
+		Some(end) => {
+		    let name = &rest[index + 2..index + end];
+		    if name.is_empty() {
+			return Err(format!("empty ${{}} in path {}", path));
+		    }
+		    for (next_index, _) in chars.by_ref() {
+			if next_index == index + end {
+			    break;
+			}
+		    }
+		    name
+		}
+		None => return Err(format!("unterminated ${{ in path {}", path)),
+	    },
+	    _ => {
+		let start = index + 1;
+		let mut end = start;
+		while let Some((next_index, next)) = chars.peek() {
+		    if !(next.is_ascii_alphanumeric() || *next == '_') {
+			break;
+		    }
+		    end = next_index + next.len_utf8();
+		    chars.next();
+		}
+		&rest[start..end]
+	    }
+	};
+	if name.is_empty() {
+	    // a lone `$` is kept as is
+	    expanded.push(b'$');
+	    continue;
+	}
+	match variable(name) {
+	    Some(value) => expanded.extend_from_slice(value.as_bytes()),
+	    None => return Err(format!("undefined variable {} in path {}", name, path)),
+	}
+    }
+
+    Ok(PathBuf::from(OsString::from_vec(expanded)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every test sets its own variables, tests run in parallel
+    fn set(name: &str, value: &[u8]) {
+	env::set_var(name, OsStr::from_bytes(value));
+    }
+
+    #[test]
+    fn home() {
+	let home = xdg::home();
+	assert_eq!(expand_path("~").unwrap(), home);
+	assert_eq!(expand_path("~/Pictures").unwrap(), home.join("Pictures"));
+	// only a leading ~ is expanded
+	assert_eq!(expand_path("/pictures/~/a").unwrap(), PathBuf::from("/pictures/~/a"));
+	assert_eq!(expand_path("~root/Pictures").unwrap(), user_home("root").unwrap().join("Pictures"));
+	assert!(expand_path("~no-such-user-here/Pictures").unwrap_err().contains("unknown user"));
+    }
+
+    #[test]
+    fn variables() {
+	set("WL_BG_TEST_DIR", b"/data/pictures");
+	set("WL_BG_TEST_EMPTY", b"");
+	assert_eq!(expand_path("$WL_BG_TEST_DIR/wallpapers").unwrap(), PathBuf::from("/data/pictures/wallpapers"));
+	assert_eq!(expand_path("${WL_BG_TEST_DIR}_old").unwrap(), PathBuf::from("/data/pictures_old"));
+	// the name stops at the first character that cannot be in it
+	assert_eq!(expand_path("$WL_BG_TEST_DIR.d").unwrap(), PathBuf::from("/data/pictures.d"));
+	assert_eq!(expand_path("~/${WL_BG_TEST_DIR}").unwrap(), xdg::home().join("data/pictures"));
+	// an empty variable is as good as an unset one
+	assert!(expand_path("$WL_BG_TEST_EMPTY/pictures").is_err());
+    }
+
+    #[test]
+    fn unset_variables() {
+	env::remove_var("WL_BG_TEST_UNSET");
+	let error = expand_path("/pictures/$WL_BG_TEST_UNSET/a").unwrap_err();
+	assert_eq!(error, "undefined variable WL_BG_TEST_UNSET in path /pictures/$WL_BG_TEST_UNSET/a");
+	assert!(expand_path("${WL_BG_TEST_UNSET}").is_err());
+	assert!(expand_path("${WL_BG_TEST_UNSET").unwrap_err().contains("unterminated"));
+	assert!(expand_path("/pictures/${}").unwrap_err().contains("empty"));
+    }
+
+    #[test]
+    fn literal_dollars() {
+	assert_eq!(expand_path("/pictures/$$HOME").unwrap(), PathBuf::from("/pictures/$HOME"));
+	assert_eq!(expand_path("/pictures/$").unwrap(), PathBuf::from("/pictures/$"));
+	assert_eq!(expand_path("/pictures/$ and $-").unwrap(), PathBuf::from("/pictures/$ and $-"));
+	assert_eq!(expand_path("/pictures/€$$").unwrap(), PathBuf::from("/pictures/€$"));
+    }
+
+    #[test]
+    fn non_utf8_values() {
+	set("WL_BG_TEST_LATIN1", b"/donn\xe9es");
+	let expanded = expand_path("$WL_BG_TEST_LATIN1/images").unwrap();
+	assert_eq!(expanded.as_os_str().as_bytes(), b"/donn\xe9es/images");
+    }
+}
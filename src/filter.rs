@@ -0,0 +1,347 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::duration::parse_duration;
+use crate::image_file::ImageFile;
+
+const SIZE_UNITS: [(char, u64); 3] = [('K', 1 << 10), ('M', 1 << 20), ('G', 1 << 30)];
+
+// Which pictures are kept, whatever their orientation
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Landscape,
+    Portrait,
+}
+
+// A bound on the modification time of the pictures: either a date, or how long ago
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct ModifiedTime {
+    value: String,
+    time: ModifiedTimeValue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ModifiedTimeValue {
+    // seconds since the epoch
+    Date(i64),
+    // seconds before now
+    Ago(u64),
+}
+
+// "2024-05-01", "2024-05-01 18:30" or "2024-05-01T18:30:00", in local time
+fn parse_date(value: &str) -> Option<i64> {
+    let (date, time) = match value.split_once([' ', 'T']) {
+	Some((date, time)) => (date, Some(time)),
+	None => (value, None),
+    };
+    let date: Vec<&str> = date.split('-').collect();
+    let (year, month, day): (i32, i32, i32) = match date[..] {
+	[year, month, day] => (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?),
+	_ => return None,
+    };
+    let (hour, minute, second): (i32, i32, i32) = match time.map(|time| time.split(':').collect::<Vec<&str>>()) {
+	None => (0, 0, 0),
+	Some(time) => match time[..] {
+	    [hour, minute] => (hour.parse().ok()?, minute.parse().ok()?, 0),
+	    [hour, minute, second] => (hour.parse().ok()?, minute.parse().ok()?, second.parse().ok()?),
+	    _ => return None,
+	},
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+	return None;
+    }
+    unsafe {
+	let mut tm: libc::tm = std::mem::zeroed();
+	tm.tm_year = year - 1900;
+	tm.tm_mon = month - 1;
+	tm.tm_mday = day;
+	tm.tm_hour = hour;
+	tm.tm_min = minute;
+	tm.tm_sec = second;
+	tm.tm_isdst = -1;
+	let time = libc::mktime(&mut tm);
+	// mktime normalizes days past the end of the month, "2024-02-31" would be March 2nd
+	if time == -1 || tm.tm_year != year - 1900 || tm.tm_mon != month - 1 || tm.tm_mday != day {
+	    return None;
+	}
+	Some(time)
+    }
+}
+
+impl TryFrom<String> for ModifiedTime {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<ModifiedTime, String> {
+	let time = if let Some(date) = parse_date(value.trim()) {
+	    ModifiedTimeValue::Date(date)
+	} else if let Ok(seconds) = parse_duration(&value) {
+	    ModifiedTimeValue::Ago(seconds)
+	} else {
+	    return Err(format!(
+		"invalid modification time {}, expected a date like \"2024-05-01\" or a duration like \"30d\"",
+		value
+	    ));
+	};
+	Ok(ModifiedTime { value, time })
+    }
+}
+
+impl std::fmt::Display for ModifiedTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	write!(f, "{}", self.value)
+    }
+}
+
+impl ModifiedTime {
+    fn seconds_since_epoch(&self, now: i64) -> i64 {
+	match self.time {
+	    ModifiedTimeValue::Date(date) => date,
+	    ModifiedTimeValue::Ago(seconds) => now.saturating_sub(seconds as i64),
+	}
+    }
+}
+
+// "16:9", "16/9" or "1.78"
+pub fn parse_aspect_ratio(ratio: &str) -> Result<f64, String> {
+    let invalid = || format!("invalid aspect ratio {}, expected something like \"16:9\" or 1.78", ratio);
+    let parsed = match ratio.split_once([':', '/']) {
+	Some((width, height)) => match (width.trim().parse::<f64>(), height.trim().parse::<f64>()) {
+	    (Ok(width), Ok(height)) => width / height,
+	    _ => return Err(invalid()),
+	},
+	None => ratio.trim().parse::<f64>().map_err(|_| invalid())?,
+    };
+    if !parsed.is_finite() || parsed <= 0.0 {
+	return Err(invalid());
+    }
+    Ok(parsed)
+}
+
+// A number of bytes, optionally followed by K, M or G (powers of 1024), e.g. "500K" or "20M"
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid file size {}, expected something like \"500K\" or \"20M\"", size);
+    let number = size.trim();
+    let number = number.strip_suffix(['B', 'b']).unwrap_or(number);
+    let (number, unit) = match SIZE_UNITS.iter().find(|(unit, _)| number.ends_with([*unit, unit.to_ascii_lowercase()])) {
+	Some((_, unit_bytes)) => (&number[..number.len() - 1], *unit_bytes),
+	None => (number, 1),
+    };
+    match number.trim().parse::<f64>() {
+	Ok(number) if number.is_finite() && number >= 0.0 => Ok((number * unit as f64) as u64),
+	_ => Err(invalid()),
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    for (unit, unit_bytes) in SIZE_UNITS.iter().rev() {
+	if bytes >= *unit_bytes && bytes.is_multiple_of(*unit_bytes) {
+	    return format!("{}{}", bytes / unit_bytes, unit);
+	}
+    }
+    format!("{}", bytes)
+}
+
+// The conditions that pictures must meet to be used, every one that is set must be met
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImageFilter {
+    // width divided by height
+    pub min_aspect_ratio: Option<f64>,
+    pub max_aspect_ratio: Option<f64>,
+    pub orientation: Option<Shape>,
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    // in bytes
+    pub max_file_size: Option<u64>,
+    pub modified_after: Option<ModifiedTime>,
+    pub modified_before: Option<ModifiedTime>,
+}
+
+impl ImageFilter {
+    // Why the picture is not used, if it is not
+    pub fn rejection(&self, image_file: &ImageFile, file_size: u64, mtime: i64) -> Option<String> {
+	let aspect_ratio = image_file.width as f64 / image_file.height.max(1) as f64;
+	if let Some(min_aspect_ratio) = self.min_aspect_ratio {
+	    if aspect_ratio < min_aspect_ratio {
+		return Some(format!("its aspect ratio {:.2} is below min_aspect_ratio {:.2}", aspect_ratio, min_aspect_ratio));
+	    }
+	}
+	if let Some(max_aspect_ratio) = self.max_aspect_ratio {
+	    if aspect_ratio > max_aspect_ratio {
+		return Some(format!("its aspect ratio {:.2} is above max_aspect_ratio {:.2}", aspect_ratio, max_aspect_ratio));
+	    }
+	}
+	// square pictures are both landscape and portrait
+	match self.orientation {
+	    Some(Shape::Landscape) if image_file.width < image_file.height => {
+		return Some(String::from("it is not in landscape orientation"));
+	    }
+	    Some(Shape::Portrait) if image_file.height < image_file.width => {
+		return Some(String::from("it is not in portrait orientation"));
+	    }
+	    _ => (),
+	}
+	if let Some(min_width) = self.min_width {
+	    if image_file.width < min_width {
+		return Some(format!("its width {} is below min_width {}", image_file.width, min_width));
+	    }
+	}
+	if let Some(min_height) = self.min_height {
+	    if image_file.height < min_height {
+		return Some(format!("its height {} is below min_height {}", image_file.height, min_height));
+	    }
+	}
+	if let Some(max_file_size) = self.max_file_size {
+	    if file_size > max_file_size {
+		return Some(format!("its size {} is above max_file_size {}", file_size, format_size(max_file_size)));
+	    }
+	}
+
+	let now = SystemTime::now()
+	    .duration_since(UNIX_EPOCH)
+	    .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+	if let Some(ref modified_after) = self.modified_after {
+	    if mtime < modified_after.seconds_since_epoch(now) {
+		return Some(format!("it was modified before modified_after {}", modified_after));
+	    }
+	}
+	if let Some(ref modified_before) = self.modified_before {
+	    if mtime > modified_before.seconds_since_epoch(now) {
+		return Some(format!("it was modified after modified_before {}", modified_before));
+	    }
+	}
+	None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{metadata::Orientation, ImageFormat};
+    use std::path::PathBuf;
+
+    fn image(width: u32, height: u32) -> ImageFile {
+	ImageFile::new(width, height, ImageFormat::Png, Orientation::NoTransforms, PathBuf::from("/pictures/image.png"))
+    }
+
+    fn modified_time(value: &str) -> ModifiedTime {
+	ModifiedTime::try_from(String::from(value)).unwrap()
+    }
+
+    #[test]
+    fn dates() {
+	let day = parse_date("2024-05-10").unwrap();
+	assert_eq!(parse_date("2024-05-11").unwrap() - day, 86400);
+	assert_eq!(parse_date("2024-05-10 18:30").unwrap() - day, 18 * 3600 + 30 * 60);
+	assert_eq!(parse_date("2024-05-10T18:30:15"), parse_date("2024-05-10 18:30").map(|time| time + 15));
+	// a leap day, and the last day of the month
+	assert_eq!(parse_date("2024-03-01").unwrap() - parse_date("2024-02-29").unwrap(), 86400);
+	assert!(parse_date("2024-04-30").is_some());
+	assert!(parse_date("1999-12-31 23:59:59").is_some());
+    }
+
+    #[test]
+    fn invalid_dates() {
+	for date in [
+	    "2024-02-30",
+	    "2023-02-29",
+	    "2024-04-31",
+	    "2024-13-01",
+	    "2024-00-10",
+	    "2024-05-00",
+	    "2024-05-10 24:00",
+	    "2024-05-10 12:60",
+	    "2024-05-10 12",
+	    "2024-05-10 12:00:00:00",
+	    "2024-05",
+	    "2024/05/10",
+	    "yesterday",
+	    "",
+	] {
+	    assert_eq!(parse_date(date), None, "{}", date);
+	}
+    }
+
+    #[test]
+    fn modified_times() {
+	assert_eq!(modified_time("2024-05-10").time, ModifiedTimeValue::Date(parse_date("2024-05-10").unwrap()));
+	assert_eq!(modified_time("30d").time, ModifiedTimeValue::Ago(30 * 86400));
+	assert_eq!(modified_time("30d").seconds_since_epoch(1000000000), 1000000000 - 30 * 86400);
+	assert_eq!(modified_time("2024-05-10").to_string(), "2024-05-10");
+	assert!(ModifiedTime::try_from(String::from("2024-02-30")).is_err());
+	assert!(ModifiedTime::try_from(String::from("last week")).is_err());
+    }
+
+    #[test]
+    fn aspect_ratios() {
+	assert_eq!(parse_aspect_ratio("16:9"), Ok(16.0 / 9.0));
+	assert_eq!(parse_aspect_ratio("21 / 9"), Ok(21.0 / 9.0));
+	assert_eq!(parse_aspect_ratio("1.78"), Ok(1.78));
+	for ratio in ["16:0", "0:9", "-16:9", "0", "wide", "16:", "inf"] {
+	    assert!(parse_aspect_ratio(ratio).is_err(), "{}", ratio);
+	}
+    }
+
+    #[test]
+    fn sizes() {
+	assert_eq!(parse_size("1234"), Ok(1234));
+	assert_eq!(parse_size("500K"), Ok(500 * 1024));
+	assert_eq!(parse_size("20M"), Ok(20 * 1024 * 1024));
+	assert_eq!(parse_size("1.5G"), Ok(3 * 512 * 1024 * 1024));
+	assert_eq!(parse_size("20mb"), Ok(20 * 1024 * 1024));
+	assert_eq!(parse_size(" 8 KB "), Ok(8 * 1024));
+	for size in ["", "M", "-1K", "20T", "twenty"] {
+	    assert!(parse_size(size).is_err(), "{}", size);
+	}
+	assert_eq!(format_size(20 * 1024 * 1024), "20M");
+	assert_eq!(format_size(1536), "1536");
+	assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3G");
+    }
+
+    #[test]
+    fn filters() {
+	let filter = ImageFilter {
+	    min_aspect_ratio: Some(1.5),
+	    max_aspect_ratio: Some(2.5),
+	    min_width: Some(1280),
+	    max_file_size: Some(10 * 1024 * 1024),
+	    ..ImageFilter::default()
+	};
+	assert_eq!(filter.rejection(&image(1920, 1080), 1024, 0), None);
+	// a square picture, a panorama, a small one and a large file
+	assert!(filter.rejection(&image(2000, 2000), 1024, 0).unwrap().contains("min_aspect_ratio"));
+	assert!(filter.rejection(&image(6000, 1000), 1024, 0).unwrap().contains("max_aspect_ratio"));
+	assert!(filter.rejection(&image(1024, 576), 1024, 0).unwrap().contains("min_width"));
+	assert!(filter.rejection(&image(1920, 1080), 11 * 1024 * 1024, 0).unwrap().contains("max_file_size"));
+
+	let landscape = ImageFilter { orientation: Some(Shape::Landscape), ..ImageFilter::default() };
+	let portrait = ImageFilter { orientation: Some(Shape::Portrait), min_height: Some(1000), ..ImageFilter::default() };
+	assert_eq!(landscape.rejection(&image(1920, 1080), 0, 0), None);
+	assert!(landscape.rejection(&image(1080, 1920), 0, 0).is_some());
+	assert_eq!(portrait.rejection(&image(1080, 1920), 0, 0), None);
+	assert!(portrait.rejection(&image(1920, 1080), 0, 0).is_some());
+	assert!(portrait.rejection(&image(800, 900), 0, 0).unwrap().contains("min_height"));
+	// square pictures are both
+	assert_eq!(landscape.rejection(&image(1000, 1000), 0, 0), None);
+	assert_eq!(portrait.rejection(&image(1000, 1000), 0, 0), None);
+    }
+
+    #[test]
+    fn modification_time_filters() {
+	let filter = ImageFilter {
+	    modified_after: Some(modified_time("2024-01-01")),
+	    modified_before: Some(modified_time("2024-12-31")),
+	    ..ImageFilter::default()
+	};
+	let start = parse_date("2024-01-01").unwrap();
+	assert_eq!(filter.rejection(&image(1920, 1080), 0, start), None);
+	assert!(filter.rejection(&image(1920, 1080), 0, start - 1).unwrap().contains("modified_after"));
+	assert!(filter.rejection(&image(1920, 1080), 0, parse_date("2025-01-01").unwrap()).unwrap().contains("modified_before"));
+
+	let recent = ImageFilter { modified_after: Some(modified_time("1d")), ..ImageFilter::default() };
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+	assert_eq!(recent.rejection(&image(1920, 1080), 0, now - 3600), None);
+	assert!(recent.rejection(&image(1920, 1080), 0, now - 2 * 86400).is_some());
+    }
+}
@@ -0,0 +1,910 @@
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::archive::{is_archive, retain_decompressed_copies};
+use crate::background_scan::{BackgroundScanner, ScanEvent};
+use crate::config::{FitType, Source};
+use crate::dedup::{add_unique, deduplicate};
+use crate::duration::format_duration;
+use crate::ignore::IGNORE_FILE_NAME;
+use crate::image_file::{assign_best_fit, is_scanned, ImageFile, ScanOptions, ScanResult};
+use crate::metadata_cache::MetadataCache;
+use crate::order::{pick_weighted, shuffle, sort, Order, ShuffleState};
+use crate::output::Output;
+use crate::playlist::is_playlist;
+use crate::rotation_state::RotationState;
+use crate::sidecar::{image_path, ImageOverrides};
+use crate::source_watcher::SourceChange;
+
+// how long to wait before looking again for the pictures of a source that has none, the delay
+// doubles every time up to the maximum
+const RETRY_DELAY_MIN: Duration = Duration::from_secs(5);
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(300);
+
+// Playlists are used as they are written, even when they list the same picture twice
+fn unique_images(
+    path: &Path,
+    image_list: Vec<ImageFile>,
+    scan_options: &ScanOptions,
+    metadata_cache: &MetadataCache,
+) -> Vec<ImageFile> {
+    if is_playlist(path) {
+	return image_list;
+    }
+    let image_count = image_list.len();
+    let image_list = deduplicate(image_list, metadata_cache, scan_options);
+    if image_list.len() < image_count {
+	println!("{} duplicates in {}", image_count - image_list.len(), path.to_string_lossy());
+    }
+    image_list
+}
+
+// A background scan of a directory of a set that is in use meanwhile, or of the whole set when dir
+// is its path
+#[derive(Debug)]
+struct Update {
+    dir: PathBuf,
+    // the directory changed during the scan, which may have missed it
+    stale: bool,
+}
+
+// The images found in one directory, and the position of the rotation through them
+#[derive(Debug)]
+pub struct ImageSet {
+    pub path: PathBuf,
+    pub image_list: Vec<ImageFile>,
+    pub index: usize,
+    // the directories that were scanned, which are watched for changes
+    pub dirs: Vec<PathBuf>,
+    // dirs changed since the gallery last collected the directories to watch
+    dirs_changed: bool,
+    // the background scan that fills the set, until it is over
+    pub scan_id: Option<u64>,
+    // the source changed during that scan
+    scan_stale: bool,
+    // the background scans that bring the set up to date, by scan id
+    updates: HashMap<u64, Update>,
+    // when the set is empty, when it is scanned again
+    retry_at: Option<Instant>,
+    retry_delay: Duration,
+    // the image the rotation was at when the program last stopped, until the rotation moves on
+    resume_at: Option<PathBuf>,
+    order: Order,
+    shuffle_state: ShuffleState,
+    // draws the images when the order is Random
+    rng: StdRng,
+    // by path, the output each image goes to when they are assigned by best fit. The images that
+    // are not there go to any output.
+    assignment: HashMap<PathBuf, String>,
+    // the name and mode of the outputs the assignment was made for, empty when it has to be made
+    // again
+    assigned_for: Vec<(String, u32, u32)>,
+}
+
+impl ImageSet {
+    // An empty set, that a background scan fills as it finds images
+    fn scanning(path: PathBuf, scan_id: u64, resume_at: Option<PathBuf>, order: Order, shuffle_state: ShuffleState) -> Self {
+	ImageSet {
+	    path,
+	    image_list: Vec::new(),
+	    index: 0,
+	    dirs: Vec::new(),
+	    dirs_changed: false,
+	    scan_id: Some(scan_id),
+	    scan_stale: false,
+	    updates: HashMap::new(),
+	    retry_at: None,
+	    retry_delay: RETRY_DELAY_MIN,
+	    resume_at,
+	    order,
+	    shuffle_state,
+	    rng: StdRng::seed_from_u64(shuffle_state.seed),
+	    assignment: HashMap::new(),
+	    assigned_for: Vec::new(),
+	}
+    }
+
+    // Puts the images in the order of the rotation, from the first one of the current cycle
+    fn arrange(&mut self) {
+	match self.order {
+	    // playlists are in the order they are written in
+	    Order::Sequential | Order::Random if is_playlist(&self.path) => (),
+	    Order::Sequential | Order::Random => sort(&mut self.image_list),
+	    Order::Shuffle => shuffle(&mut self.image_list, self.shuffle_state, None),
+	}
+    }
+
+    // Changes the order of the rotation, which goes on from the same image
+    fn set_order(&mut self, order: Order, shuffle_state: ShuffleState) {
+	self.order = order;
+	self.shuffle_state = shuffle_state;
+	self.rng = StdRng::seed_from_u64(shuffle_state.seed);
+	// a set that is being scanned is put in order when the scan is over
+	if self.scan_id.is_none() {
+	    let image_list = std::mem::take(&mut self.image_list);
+	    self.replace_images(image_list);
+	}
+    }
+
+    // The rotation goes on from the same image, or from where it was when the program last
+    // stopped
+    fn replace_images(&mut self, image_list: Vec<ImageFile>) {
+	let current = self.image_list.get(self.index).map(|image_file| image_file.path.clone());
+	self.image_list = image_list;
+	self.arrange();
+	self.assigned_for.clear();
+	let position = |path: &PathBuf| self.image_list.iter().position(|image_file| image_file.path == *path);
+	self.index = self
+	    .resume_at
+	    .take()
+	    .and_then(|resume_at| position(&resume_at))
+	    .or_else(|| current.and_then(|current| position(&current)))
+	    .unwrap_or(0);
+    }
+
+    // Replaces the images with those of a complete scan of the set
+    fn apply_scan(&mut self, scan_result: ScanResult, scan_options: &ScanOptions, metadata_cache: &MetadataCache) {
+	let image_list = unique_images(&self.path, scan_result.image_list, scan_options, metadata_cache);
+	self.replace_images(image_list);
+	self.dirs = scan_result.dirs;
+	self.dirs_changed = true;
+    }
+
+    // Replaces the images found so far with the complete list. Returns true if the source changed
+    // during the scan, which has to be done again.
+    fn finish_scan(&mut self, scan_result: ScanResult, scan_options: &ScanOptions, metadata_cache: &MetadataCache) -> bool {
+	self.apply_scan(scan_result, scan_options, metadata_cache);
+	self.scan_id = None;
+	std::mem::take(&mut self.scan_stale)
+    }
+
+    // Applies the result of an update. Returns the directory to scan again if it changed during
+    // the scan.
+    fn finish_update(
+	&mut self,
+	scan_id: u64,
+	scan_result: ScanResult,
+	scan_options: &ScanOptions,
+	metadata_cache: &MetadataCache,
+    ) -> Option<PathBuf> {
+	let update = self.updates.remove(&scan_id)?;
+	if update.dir == self.path {
+	    self.apply_scan(scan_result, scan_options, metadata_cache);
+	} else {
+	    self.add_dir(&update.dir, scan_result, scan_options, metadata_cache);
+	}
+	if update.stale {
+	    Some(update.dir)
+	} else {
+	    None
+	}
+    }
+
+    // Marks the scans that cover path as stale, so that they are done again once over. Returns
+    // false if there is none, the change has to be dealt with otherwise.
+    fn mark_stale(&mut self, path: &Path) -> bool {
+	let mut covered = false;
+	if self.scan_id.is_some() && path.starts_with(&self.path) {
+	    self.scan_stale = true;
+	    covered = true;
+	}
+	for update in self.updates.values_mut().filter(|update| path.starts_with(&update.dir)) {
+	    update.stale = true;
+	    covered = true;
+	}
+	covered
+    }
+
+    pub fn current(&self) -> &ImageFile {
+	self.image_list.get(self.index).unwrap()
+    }
+
+    // Drops the current image, which could not be shown. When the whole source is gone, e.g. an
+    // unplugged drive, its other images go with it instead of being tried one by one.
+    pub fn remove_current(&mut self) {
+	if !self.path.exists() {
+	    println!("{} is gone, waiting for it to come back", self.path.to_string_lossy());
+	    let path = self.path.clone();
+	    self.remove_path(&path);
+	    // the images of a playlist are not below its path
+	    self.image_list.clear();
+	    self.index = 0;
+	    return;
+	}
+	// the next image takes its place, the order of the rotation is kept
+	self.image_list.remove(self.index);
+	if self.index >= self.image_list.len() {
+	    self.index = 0;
+	}
+    }
+
+    // Makes the next image that can be shown on the output current, starting from the current one.
+    // The images assigned to other outputs are only used when there is none left for this one.
+    // False if there is none.
+    pub fn seek(&mut self, output_name: &str) -> bool {
+	let is_assigned = |assignment: &HashMap<PathBuf, String>, image_file: &ImageFile| {
+	    assignment.get(&image_file.path).is_none_or(|assigned_to| assigned_to == output_name)
+	};
+	self.seek_matching(|assignment, image_file| image_file.shows_on(output_name) && is_assigned(assignment, image_file))
+	    || self.seek_matching(|_, image_file| image_file.shows_on(output_name))
+    }
+
+    fn seek_matching<F>(&mut self, matches: F) -> bool
+    where
+	F: Fn(&HashMap<PathBuf, String>, &ImageFile) -> bool,
+    {
+	if self.order == Order::Random && !self.image_list.is_empty() {
+	    if matches(&self.assignment, self.current()) {
+		return true;
+	    }
+	    let assignment = &self.assignment;
+	    return match pick_weighted(&self.image_list, &mut self.rng, |_, image_file| matches(assignment, image_file)) {
+		Some(index) => {
+		    self.index = index;
+		    true
+		}
+		None => false,
+	    };
+	}
+	// the images the output skips stay next in line for the other outputs, the one it takes is
+	// moved in front of them
+	let assignment = &self.assignment;
+	let next = self.image_list[self.index..].iter().position(|image_file| matches(assignment, image_file));
+	if let Some(offset) = next {
+	    self.image_list[self.index..=self.index + offset].rotate_right(1);
+	    return true;
+	}
+	if !self.image_list.iter().any(|image_file| matches(assignment, image_file)) {
+	    return false;
+	}
+	// there is none left for the output in this cycle: the next one starts. The images that were
+	// left for the others have not been shown in this one, they stay in front of the others, which
+	// are in the order of the new cycle.
+	let previous = self.image_list[..self.index]
+	    .iter()
+	    .rfind(|image_file| matches(assignment, image_file))
+	    .map(|image_file| image_file.path.clone());
+	let mut shown = std::mem::take(&mut self.image_list);
+	self.image_list = shown.split_off(self.index);
+	self.index = 0;
+	self.resume_at = None;
+	if self.order == Order::Shuffle {
+	    self.shuffle_state.cycle += 1;
+	    shuffle(&mut shown, self.shuffle_state, previous.as_deref());
+	}
+	self.image_list.extend(shown);
+	// the output does not get the image it ended the cycle with first, unless it has no other one
+	let assignment = &self.assignment;
+	let next: Vec<usize> = (0..self.image_list.len())
+	    .filter(|index| matches(assignment, &self.image_list[*index]))
+	    .take(2)
+	    .collect();
+	if let [first, second] = next[..] {
+	    if previous.as_ref() == Some(&self.image_list[first].path) {
+		self.image_list.swap(first, second);
+	    }
+	}
+	self.seek_matching(matches)
+    }
+
+    // Shares the images out between the outputs that use the set. This is done again when the
+    // outputs or their modes change, and when a scan is over; the images found in between go to
+    // any output.
+    fn assign(&mut self, assignment: FitType, outputs: &[&Output]) {
+	let assigned_for: Vec<(String, u32, u32)> = match assignment {
+	    FitType::BestFit => outputs
+		.iter()
+		.map(|output| (output.name.clone(), output.mode_width, output.mode_height))
+		.collect(),
+	    FitType::NextFile => Vec::new(),
+	};
+	if assigned_for == self.assigned_for {
+	    return;
+	}
+	self.assignment = match assignment {
+	    FitType::BestFit if outputs.len() > 1 => {
+		println!(
+		    "sharing the {} images of {} between {} outputs",
+		    self.image_list.len(),
+		    self.path.to_string_lossy(),
+		    outputs.len()
+		);
+		assign_best_fit(&self.image_list, outputs)
+	    }
+	    _ => HashMap::new(),
+	};
+	self.assigned_for = assigned_for;
+    }
+
+    // The sets are kept up to date by the source watcher, so the rotation simply starts over, in
+    // a new order when it is shuffled. A random draw never picks the current image again.
+    pub fn advance(&mut self) {
+	self.resume_at = None;
+	if self.order == Order::Random && self.image_list.len() > 1 {
+	    let current = self.index;
+	    // when only the current image has a weight, the others are shown in turn
+	    if let Some(index) = pick_weighted(&self.image_list, &mut self.rng, |index, _| index != current) {
+		self.index = index;
+		return;
+	    }
+	}
+	self.index += 1;
+	if self.index >= self.image_list.len() {
+	    self.index = 0;
+	    if self.order == Order::Shuffle {
+		let previous = self.image_list.last().map(|image_file| image_file.path.clone());
+		self.shuffle_state.cycle += 1;
+		shuffle(&mut self.image_list, self.shuffle_state, previous.as_deref());
+	    }
+	}
+    }
+
+    // Removes path and, if it is a directory, everything below it. The rotation goes on from the
+    // same image.
+    fn remove_path(&mut self, path: &Path) -> Vec<ImageFile> {
+	let mut removed = Vec::new();
+	let mut index = 0;
+	while index < self.image_list.len() {
+	    if self.image_list[index].path.starts_with(path) {
+		removed.push(self.image_list.remove(index));
+		if index < self.index {
+		    self.index -= 1;
+		}
+	    } else {
+		index += 1;
+	    }
+	}
+	if self.index >= self.image_list.len() {
+	    self.index = 0;
+	}
+	let dir_count = self.dirs.len();
+	self.dirs.retain(|dir| !dir.starts_with(path));
+	self.dirs_changed |= self.dirs.len() != dir_count;
+	removed
+    }
+
+    // New images are appended, so that they show up before the end of the current rotation. They
+    // are shared out again with the others.
+    fn add_image(&mut self, image_file: ImageFile, scan_options: &ScanOptions, metadata_cache: &MetadataCache) {
+	self.remove_path(&image_file.path);
+	add_unique(&mut self.image_list, vec![image_file], metadata_cache, scan_options);
+	self.assigned_for.clear();
+    }
+
+    // Replaces what the set has below dir with the result of its scan
+    fn add_dir(&mut self, dir: &Path, scan_result: ScanResult, scan_options: &ScanOptions, metadata_cache: &MetadataCache) {
+	self.remove_path(dir);
+	add_unique(&mut self.image_list, scan_result.image_list, metadata_cache, scan_options);
+	self.assigned_for.clear();
+	self.dirs.extend(scan_result.dirs);
+	self.dirs_changed = true;
+    }
+}
+
+// Every image set in use, by directory. Outputs that share a source share its rotation.
+pub struct Gallery {
+    pub image_sets: HashMap<PathBuf, ImageSet>,
+    pub metadata_cache: MetadataCache,
+    pub scanner: BackgroundScanner,
+    next_scan_id: u64,
+    pub rotation_state: RotationState,
+    order: Order,
+    seed: Option<u64>,
+    // sets were dropped since the directories to watch were last collected
+    dirs_changed: bool,
+}
+
+impl Gallery {
+    // The sources are scanned the first time an output uses them, so that those that no output
+    // uses are never scanned
+    pub fn new(order: Order, seed: Option<u64>) -> Self {
+	Gallery {
+	    image_sets: HashMap::new(),
+	    metadata_cache: MetadataCache::load(),
+	    scanner: BackgroundScanner::new(),
+	    next_scan_id: 0,
+	    rotation_state: RotationState::load(),
+	    order,
+	    seed,
+	    dirs_changed: false,
+	}
+    }
+
+    // Scans the set at root, or only dir when it is a directory inside it
+    fn start_scan(&mut self, root: &Path, dir: &Path, scan_options: &ScanOptions) -> u64 {
+	let scan_id = self.next_scan_id;
+	self.next_scan_id += 1;
+	println!("scanning {} in the background", dir.to_string_lossy());
+	self.scanner.start(scan_id, root.to_path_buf(), dir.to_path_buf(), scan_options, self.metadata_cache.snapshot());
+	scan_id
+    }
+
+    // Scans a directory of a set again, or the whole set when dir is its path. The set is used as
+    // it is until the scan is over.
+    fn start_update(&mut self, path: &Path, dir: &Path, scan_options: &ScanOptions) {
+	let scan_id = self.start_scan(path, dir, scan_options);
+	if let Some(image_set) = self.image_sets.get_mut(path) {
+	    image_set.updates.insert(scan_id, Update { dir: dir.to_path_buf(), stale: false });
+	}
+    }
+
+    // Starts scanning the directory the first time it is asked for, its set is empty until the
+    // scan finds the first images
+    pub fn image_set(&mut self, path: &Path, scan_options: &ScanOptions) -> &mut ImageSet {
+	if !self.image_sets.contains_key(path) {
+	    let scan_id = self.start_scan(path, path, scan_options);
+	    let resume_at = self.rotation_state.position(path).cloned();
+	    let shuffle_state = self.shuffle_state(path);
+	    let image_set = ImageSet::scanning(path.to_path_buf(), scan_id, resume_at, self.order, shuffle_state);
+	    self.image_sets.insert(path.to_path_buf(), image_set);
+	}
+	self.image_sets.get_mut(path).unwrap()
+    }
+
+    // The shuffle goes on from where it was when the program last stopped, unless the seed was
+    // changed since
+    fn shuffle_state(&self, path: &Path) -> ShuffleState {
+	match self.rotation_state.shuffle(path) {
+	    Some(shuffle_state) if self.seed.is_none_or(|seed| seed == shuffle_state.seed) => shuffle_state,
+	    _ => ShuffleState::new(self.seed),
+	}
+    }
+
+    // Applies a new order to the existing sets, without scanning them again
+    pub fn set_order(&mut self, order: Order, seed: Option<u64>) {
+	if order == self.order && seed == self.seed {
+	    return;
+	}
+	println!("the rotation order is now {:?}", order);
+	self.order = order;
+	self.seed = seed;
+	for image_set in self.image_sets.values_mut() {
+	    let shuffle_state = match seed {
+		Some(_) => ShuffleState::new(seed),
+		None => image_set.shuffle_state,
+	    };
+	    image_set.set_order(order, shuffle_state);
+	}
+    }
+
+    // The sets without images are scanned again, less and less often, until pictures show up:
+    // their source may be on a drive that is not mounted yet. Returns the time until the next
+    // retry, if any.
+    pub fn retry_scans(&mut self, scan_options: &ScanOptions) -> Option<Duration> {
+	let now = Instant::now();
+	let mut until_next_retry: Option<Duration> = None;
+	let mut to_scan = Vec::new();
+	for image_set in self.image_sets.values_mut() {
+	    if image_set.scan_id.is_some() || !image_set.updates.is_empty() {
+		continue;
+	    }
+	    if !image_set.image_list.is_empty() {
+		image_set.retry_at = None;
+		image_set.retry_delay = RETRY_DELAY_MIN;
+		continue;
+	    }
+	    let retry_at = match image_set.retry_at {
+		Some(retry_at) => retry_at,
+		None => {
+		    println!(
+			"no picture in {}, looking again in {}",
+			image_set.path.to_string_lossy(),
+			format_duration(image_set.retry_delay.as_secs())
+		    );
+		    *image_set.retry_at.insert(now + image_set.retry_delay)
+		}
+	    };
+	    if retry_at <= now {
+		image_set.retry_at = None;
+		image_set.retry_delay = (image_set.retry_delay * 2).min(RETRY_DELAY_MAX);
+		to_scan.push(image_set.path.clone());
+	    } else if until_next_retry.is_none_or(|until_next_retry| retry_at - now < until_next_retry) {
+		until_next_retry = Some(retry_at - now);
+	    }
+	}
+	for path in to_scan {
+	    let scan_id = self.start_scan(&path, &path, scan_options);
+	    if let Some(image_set) = self.image_sets.get_mut(&path) {
+		image_set.scan_id = Some(scan_id);
+	    }
+	}
+	until_next_retry
+    }
+
+    // Adds what the background scans found to their sets, the scans that the changes of their
+    // source made stale are started again. True if images were added.
+    pub fn apply_scan_events(&mut self, events: Vec<ScanEvent>, scan_options: &ScanOptions) -> bool {
+	let mut added = false;
+	let mut to_scan: Vec<(PathBuf, PathBuf)> = Vec::new();
+	let mut scanned = false;
+	for event in events {
+	    match event {
+		ScanEvent::Found { scan_id, image_files } => {
+		    // the set is gone if its source is no longer used, updates only count once over
+		    if let Some(image_set) = self.image_sets.values_mut().find(|image_set| image_set.scan_id == Some(scan_id)) {
+			added |= !image_files.is_empty();
+			image_set.image_list.extend(image_files);
+		    }
+		}
+		ScanEvent::Done { scan_id, scan_result, metadata_cache } => {
+		    scanned = true;
+		    // what the scan probed is kept either way
+		    self.metadata_cache.merge(*metadata_cache);
+		    let image_set = self
+			.image_sets
+			.values_mut()
+			.find(|image_set| image_set.scan_id == Some(scan_id) || image_set.updates.contains_key(&scan_id));
+		    if let Some(image_set) = image_set {
+			let image_count = image_set.image_list.len();
+			if image_set.scan_id == Some(scan_id) {
+			    if image_set.finish_scan(scan_result, scan_options, &self.metadata_cache) {
+				to_scan.push((image_set.path.clone(), image_set.path.clone()));
+			    }
+			} else if let Some(dir) = image_set.finish_update(scan_id, scan_result, scan_options, &self.metadata_cache) {
+			    to_scan.push((image_set.path.clone(), dir));
+			}
+			added |= image_set.image_list.len() > image_count;
+		    }
+		}
+	    }
+	}
+	for (path, dir) in to_scan {
+	    println!("{} changed during its scan", dir.to_string_lossy());
+	    self.start_update(&path, &dir, scan_options);
+	}
+	// an archive that was read again may have changed
+	if scanned {
+	    retain_decompressed_copies(self.image_sets.keys());
+	}
+	self.metadata_cache.save();
+	added
+    }
+
+    // A single image, outside of any scan, such as the one an output showed when the program last
+    // stopped. None if it cannot be used anymore.
+    pub fn probe(&mut self, path: &Path, scan_options: &ScanOptions) -> Option<ImageFile> {
+	let mut image_file = self.metadata_cache.probe(path, scan_options)?;
+	image_file.overrides = ImageOverrides::load(path);
+	Some(image_file)
+    }
+
+    // Records where the rotation of each set is, the sets that are being scanned are not there
+    // yet. Returns the time until the changes that could not be written yet can be.
+    pub fn save_rotation_state(&mut self) -> Option<Duration> {
+	self.record_rotation_state();
+	self.rotation_state.save()
+    }
+
+    // Writes where the rotation is right away, before the program stops
+    pub fn flush_rotation_state(&mut self) {
+	self.record_rotation_state();
+	self.rotation_state.flush();
+    }
+
+    fn record_rotation_state(&mut self) {
+	for image_set in self.image_sets.values() {
+	    if image_set.scan_id.is_none() && !image_set.image_list.is_empty() {
+		self.rotation_state.set_position(&image_set.path, &image_set.current().path);
+		if image_set.order == Order::Shuffle {
+		    self.rotation_state.set_shuffle(&image_set.path, image_set.shuffle_state);
+		}
+	    }
+	}
+    }
+
+    // Assigns the images of each set to the outputs that use it, those whose mode is not known
+    // yet are left out
+    pub fn assign(&mut self, assignment: FitType, outputs: &[&Output]) {
+	for image_set in self.image_sets.values_mut() {
+	    if image_set.scan_id.is_some() {
+		continue;
+	    }
+	    let mut users: Vec<&Output> = outputs
+		.iter()
+		.filter(|output| output.mode_width > 0 && output.mode_height > 0)
+		.filter(|output| {
+		    output.settings.as_ref().is_some_and(|settings| {
+			settings.sources.iter().any(|source| source.path == image_set.path)
+		    })
+		})
+		.copied()
+		.collect();
+	    users.sort_by(|output, other| output.name.cmp(&other.name));
+	    image_set.assign(assignment, &users);
+	}
+    }
+
+    // The directories to watch for changes
+    pub fn watched_dirs(&self) -> HashSet<PathBuf> {
+	self.image_sets
+	    .values()
+	    .flat_map(|image_set| image_set.dirs.iter().cloned())
+	    .collect()
+    }
+
+    // The directories to watch for changes, if they changed since the last time they were
+    // collected
+    pub fn changed_watched_dirs(&mut self) -> Option<HashSet<PathBuf>> {
+	let mut changed = std::mem::take(&mut self.dirs_changed);
+	for image_set in self.image_sets.values_mut() {
+	    changed |= std::mem::take(&mut image_set.dirs_changed);
+	}
+	if changed {
+	    Some(self.watched_dirs())
+	} else {
+	    None
+	}
+    }
+
+    // Applies the changes seen in the watched directories to the image sets they belong to.
+    // Only the files that changed are probed, and renamed files are not probed again. The
+    // directories, and the sets, that have to be scanned again are scanned in the background.
+    pub fn apply_changes(&mut self, changes: Vec<SourceChange>, scan_options: &ScanOptions) {
+	// the images moved out of a directory, by inotify cookie, until they are moved back in
+	let mut moved_images: HashMap<u32, Vec<ImageFile>> = HashMap::new();
+	let mut to_rescan: HashSet<PathBuf> = HashSet::new();
+	// the new directories, along with their set
+	let mut to_scan: Vec<(PathBuf, PathBuf)> = Vec::new();
+
+	for change in changes {
+	    if let SourceChange::Overflow = change {
+		println!("too many changes at once, rescanning every source");
+		for image_set in self.image_sets.values_mut() {
+		    let path = image_set.path.clone();
+		    if !image_set.mark_stale(&path) {
+			to_rescan.insert(path);
+		    }
+		}
+		continue;
+	    }
+	    if let SourceChange::Unmounted { ref path } = change {
+		// the cache is kept, the files are likely to be the same when it is mounted again
+		for image_set in self.image_sets.values_mut().filter(|image_set| image_set.dirs.contains(path)) {
+		    println!("{} was unmounted", path.to_string_lossy());
+		    image_set.remove_path(path);
+		}
+		continue;
+	    }
+	    let path = match change.path() {
+		Some(path) => path,
+		None => continue,
+	    };
+	    let dir = match path.parent() {
+		Some(dir) => dir,
+		None => continue,
+	    };
+	    for image_set in self.image_sets.values_mut() {
+		if !image_set.dirs.iter().any(|watched_dir| watched_dir == dir) {
+		    continue;
+		}
+		if is_playlist(&image_set.path) || is_archive(&image_set.path) {
+		    // the other files of its directory do not matter, and the current list is kept
+		    // while the file is being replaced
+		    let written = matches!(change, SourceChange::Added { .. } | SourceChange::MovedTo { .. });
+		    if written
+			&& *path == image_set.path
+			&& !image_set.mark_stale(path)
+			&& to_rescan.insert(image_set.path.clone())
+		    {
+			println!("{} changed, reading it again", image_set.path.to_string_lossy());
+		    }
+		    continue;
+		}
+		if path.file_name().is_some_and(|file_name| file_name == IGNORE_FILE_NAME) {
+		    // the rules changed for the whole directory
+		    let set_path = image_set.path.clone();
+		    if !image_set.mark_stale(&set_path) && to_rescan.insert(set_path) {
+			println!("ignore rules changed, rescanning {}", image_set.path.to_string_lossy());
+		    }
+		    continue;
+		}
+		if let Some(image_path) = image_path(path) {
+		    // the sidecar of an image was written, moved or deleted
+		    if let Some(image_file) = image_set.image_list.iter_mut().find(|image_file| image_file.path == image_path) {
+			println!("the settings of {} changed", image_path.to_string_lossy());
+			image_file.overrides = ImageOverrides::load(&image_path);
+		    }
+		    continue;
+		}
+
+		// the change is applied right away, and the scans that may have missed it are done again
+		let covered = image_set.mark_stale(path);
+		match change {
+		    SourceChange::Removed { ref path } => {
+			self.metadata_cache.remove(path);
+			for image_file in image_set.remove_path(path) {
+			    println!("{} was removed", image_file.path.to_string_lossy());
+			}
+		    }
+		    SourceChange::MovedFrom { ref path, cookie } => {
+			let removed = image_set.remove_path(path);
+			moved_images.entry(cookie).or_default().extend(removed);
+		    }
+		    SourceChange::Added { ref path, is_dir } | SourceChange::MovedTo { ref path, is_dir, .. } => {
+			if !is_scanned(&image_set.path, path, is_dir, scan_options) {
+			    image_set.remove_path(path);
+			    continue;
+			}
+			if is_dir {
+			    if !covered {
+				println!("new directory {}", path.to_string_lossy());
+				to_scan.push((image_set.path.clone(), path.clone()));
+			    }
+			    continue;
+			}
+			// a renamed file keeps its dimensions
+			let renamed = match change {
+			    SourceChange::MovedTo { cookie, .. } => match moved_images.get(&cookie) {
+				Some(images) if images.len() == 1 => {
+				    self.metadata_cache.rename(&images[0].path, path);
+				    Some(ImageFile::new(
+					images[0].width,
+					images[0].height,
+					images[0].format,
+					images[0].orientation,
+					path.clone(),
+				    ))
+				}
+				_ => None,
+			    },
+			    _ => None,
+			};
+			let image_file = match renamed {
+			    Some(image_file) => {
+				println!("{} was renamed", image_file.path.to_string_lossy());
+				Some(image_file)
+			    }
+			    None => self.metadata_cache.probe(path, scan_options),
+			};
+			match image_file {
+			    Some(mut image_file) => {
+				println!("{} was added", image_file.path.to_string_lossy());
+				image_file.overrides = ImageOverrides::load(&image_file.path);
+				image_set.add_image(image_file, scan_options, &self.metadata_cache);
+			    }
+			    None => drop(image_set.remove_path(path)),
+			}
+		    }
+		    SourceChange::Unmounted { .. } | SourceChange::Overflow => (),
+		}
+	    }
+	}
+
+	for path in to_rescan {
+	    self.start_update(&path, &path, scan_options);
+	}
+	for (path, dir) in to_scan {
+	    self.start_update(&path, &dir, scan_options);
+	}
+	self.metadata_cache.save();
+    }
+
+    // Drops the sets of the directories that are no longer used, they will be scanned again if
+    // they ever come back
+    pub fn retain_sources(&mut self, sources: &[&Source]) {
+	let set_count = self.image_sets.len();
+	self.image_sets
+	    .retain(|path, _| sources.iter().any(|source| &source.path == path));
+	self.dirs_changed |= self.image_sets.len() != set_count;
+	retain_decompressed_copies(self.image_sets.keys());
+    }
+}
+
+// Smooth weighted round-robin between the sources of an output: over any window, each source is
+// picked in proportion to its weight, however many images it contains, and picks are spread out
+// instead of coming in bursts.
+#[derive(Debug, Default)]
+pub struct SourcePicker {
+    credits: Vec<i64>,
+}
+
+impl SourcePicker {
+    pub fn new() -> Self {
+	SourcePicker::default()
+    }
+
+    pub fn pick(&mut self, sources: &[Source]) -> usize {
+	if self.credits.len() != sources.len() {
+	    self.credits = vec![0; sources.len()];
+	}
+	let total_weight: i64 = sources.iter().map(|source| source.weight as i64).sum();
+	if total_weight == 0 {
+	    // every source is disabled, fall back to the first one
+	    return 0;
+	}
+
+	let mut picked = 0;
+	for (index, source) in sources.iter().enumerate() {
+	    self.credits[index] += source.weight as i64;
+	    if self.credits[index] > self.credits[picked] {
+		picked = index;
+	    }
+	}
+	self.credits[picked] -= total_weight;
+	picked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{metadata::Orientation, ImageFormat};
+
+    use crate::config::Matcher;
+
+    // Images a0, a1... only show on output A, b0, b1... only on B
+    fn image_set(order: Order, seed: u64, a_count: usize, b_count: usize) -> ImageSet {
+	let image_list = (0..a_count)
+	    .map(|index| ("A", format!("a{}", index)))
+	    .chain((0..b_count).map(|index| ("B", format!("b{}", index))))
+	    .map(|(output_name, name)| {
+		let path = PathBuf::from(format!("/pictures/{}.png", name));
+		let mut image_file = ImageFile::new(1920, 1080, ImageFormat::Png, Orientation::NoTransforms, path);
+		image_file.overrides = Some(ImageOverrides {
+		    fit: None,
+		    background_color: None,
+		    focal_point: None,
+		    bg_duration_seconds: None,
+		    weight: None,
+		    outputs: vec![Matcher::Glob(String::from(output_name))],
+		});
+		image_file
+	    })
+	    .collect();
+	let mut image_set = ImageSet::scanning(PathBuf::from("/pictures"), 0, None, order, ShuffleState { seed, cycle: 0 });
+	image_set.scan_id = None;
+	image_set.replace_images(image_list);
+	image_set
+    }
+
+    // What the output shows next, the way the main loop does it
+    fn show(image_set: &mut ImageSet, output_name: &str) -> String {
+	assert!(image_set.seek(output_name));
+	let name = image_set.current().path.file_stem().unwrap().to_string_lossy().into_owned();
+	image_set.advance();
+	name
+    }
+
+    #[test]
+    fn outputs_only_get_their_images() {
+	let mut image_set = image_set(Order::Sequential, 0, 2, 3);
+	let shown: Vec<String> = (0..4).map(|_| show(&mut image_set, "A")).collect();
+	assert_eq!(shown, ["a0", "a1", "a0", "a1"]);
+	// the images A skipped were left for B, in order
+	let shown: Vec<String> = (0..4).map(|_| show(&mut image_set, "B")).collect();
+	assert_eq!(shown, ["b0", "b1", "b2", "b0"]);
+    }
+
+    #[test]
+    fn no_repeat_when_a_cycle_ends_for_one_output() {
+	for seed in 0..50 {
+	    let mut image_set = image_set(Order::Shuffle, seed, 2, 4);
+	    let mut previous_a = String::new();
+	    let mut previous_b = String::new();
+	    let mut shown_b = Vec::new();
+	    for _ in 0..40 {
+		// A has fewer images, its cycles end while B still has images left in them
+		for _ in 0..2 {
+		    let shown = show(&mut image_set, "A");
+		    assert!(shown.starts_with('a'));
+		    assert_ne!(shown, previous_a, "seed {}", seed);
+		    previous_a = shown;
+		}
+		let shown = show(&mut image_set, "B");
+		assert!(shown.starts_with('b'));
+		assert_ne!(shown, previous_b, "seed {}", seed);
+		previous_b = shown.clone();
+		shown_b.push(shown);
+	    }
+	    // the images of B are all shown before any of them comes again
+	    for window in shown_b.chunks(4).take(2) {
+		let mut window = window.to_vec();
+		window.sort();
+		window.dedup();
+		assert_eq!(window.len(), 4, "seed {}: {:?}", seed, shown_b);
+	    }
+	}
+    }
+}
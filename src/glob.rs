@@ -0,0 +1,215 @@
+// Minimal shell-style pattern matching: `*` matches any sequence of characters, `?` matches a
+// single character, `[abc]` / `[a-z]` / `[!a-z]` match character classes and `\` escapes the next
+// character.
+
+fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    // returns whether c matches the class starting after the '[', and the length of the class
+    // (closing bracket included)
+    let mut i = 0;
+    let mut negate = false;
+    if i < class.len() && (class[i] == '!' || class[i] == '^') {
+	negate = true;
+	i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() {
+	if class[i] == ']' && !first {
+	    return Some((matched != negate, i + 1));
+	}
+	first = false;
+	let low = class[i];
+	if i + 2 < class.len() && class[i + 1] == '-' && class[i + 2] != ']' {
+	    if low <= c && c <= class[i + 2] {
+		matched = true;
+	    }
+	    i += 3;
+	} else {
+	    if low == c {
+		matched = true;
+	    }
+	    i += 1;
+	}
+    }
+    // unterminated class, '[' is treated literally by the caller
+    None
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+    // position to backtrack to when the last '*' has to swallow one more character
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+	if p < pattern.len() {
+	    match pattern[p] {
+		'*' => {
+		    backtrack = Some((p, t));
+		    p += 1;
+		    continue;
+		}
+		'?' => {
+		    p += 1;
+		    t += 1;
+		    continue;
+		}
+		'[' => {
+		    if let Some((matched, len)) = match_class(&pattern[p + 1..], text[t]) {
+			if matched {
+			    p += len + 1;
+			    t += 1;
+			    continue;
+			}
+		    } else if text[t] == '[' {
+			p += 1;
+			t += 1;
+			continue;
+		    }
+		}
+		'\\' if p + 1 < pattern.len() => {
+		    if pattern[p + 1] == text[t] {
+			p += 2;
+			t += 1;
+			continue;
+		    }
+		}
+		c => {
+		    if c == text[t] {
+			p += 1;
+			t += 1;
+			continue;
+		    }
+		}
+	    }
+	}
+	match backtrack {
+	    Some((star_p, star_t)) => {
+		p = star_p + 1;
+		t = star_t + 1;
+		backtrack = Some((star_p, star_t + 1));
+	    }
+	    None => return false,
+	}
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+// Matches pattern[p..] against text[t..]. The result for each (p, t) is kept in memo, so that
+// patterns with several `*` or `**` take polynomial time instead of trying every way to split the
+// text again and again.
+fn match_path_from(pattern: &[char], text: &[char], p: usize, t: usize, memo: &mut Vec<Option<bool>>) -> bool {
+    let key = p * (text.len() + 1) + t;
+    if let Some(matched) = memo[key] {
+	return matched;
+    }
+    let matched = match_path_step(pattern, text, p, t, memo);
+    memo[key] = Some(matched);
+    matched
+}
+
+fn match_path_step(pattern: &[char], text: &[char], p: usize, t: usize, memo: &mut Vec<Option<bool>>) -> bool {
+    if p == pattern.len() {
+	return t == text.len();
+    }
+    let rest = &text[t..];
+
+    match pattern[p] {
+	'*' if p + 1 < pattern.len() && pattern[p + 1] == '*' => {
+	    // "**/" matches any number of directories, "**" anything at all
+	    if p + 2 < pattern.len() && pattern[p + 2] == '/' {
+		(t..=text.len())
+		    .filter(|i| *i == t || text[i - 1] == '/')
+		    .any(|i| match_path_from(pattern, text, p + 3, i, memo))
+	    } else {
+		(t..=text.len()).any(|i| match_path_from(pattern, text, p + 2, i, memo))
+	    }
+	}
+	'*' => {
+	    for i in t..=text.len() {
+		if match_path_from(pattern, text, p + 1, i, memo) {
+		    return true;
+		}
+		if i < text.len() && text[i] == '/' {
+		    break;
+		}
+	    }
+	    false
+	}
+	'?' => !rest.is_empty() && rest[0] != '/' && match_path_from(pattern, text, p + 1, t + 1, memo),
+	'[' if !rest.is_empty() && rest[0] != '/' => match match_class(&pattern[p + 1..], rest[0]) {
+	    Some((matched, len)) => matched && match_path_from(pattern, text, p + len + 1, t + 1, memo),
+	    None => rest[0] == '[' && match_path_from(pattern, text, p + 1, t + 1, memo),
+	},
+	'\\' if p + 1 < pattern.len() => {
+	    !rest.is_empty() && rest[0] == pattern[p + 1] && match_path_from(pattern, text, p + 2, t + 1, memo)
+	}
+	c => !rest.is_empty() && rest[0] == c && match_path_from(pattern, text, p + 1, t + 1, memo),
+    }
+}
+
+// Same as glob_match, except that `*`, `?` and classes do not match `/`, while `**` matches
+// across directories
+pub fn path_glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    let mut memo = vec![None; (pattern.len() + 1) * (path.len() + 1)];
+    match_path_from(&pattern, &path, 0, 0, &mut memo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_wildcards() {
+	assert!(glob_match("*.toml", "config.toml"));
+	assert!(glob_match("*", ""));
+	assert!(glob_match("a*b*c", "aXXbYYc"));
+	assert!(!glob_match("a*b*c", "aXXbYY"));
+	assert!(glob_match("?.jpg", "a.jpg"));
+	assert!(!glob_match("?.jpg", "ab.jpg"));
+	// unlike path_glob_match, `*` goes through directories
+	assert!(glob_match("*.jpg", "dir/a.jpg"));
+    }
+
+    #[test]
+    fn glob_classes_and_escapes() {
+	assert!(glob_match("DP-[0-9]", "DP-3"));
+	assert!(!glob_match("DP-[0-9]", "DP-a"));
+	assert!(glob_match("DP-[!0-9]", "DP-a"));
+	assert!(glob_match("[]]", "]"));
+	assert!(glob_match("a[b", "a[b"));
+	assert!(glob_match("\\*", "*"));
+	assert!(!glob_match("\\*", "a"));
+    }
+
+    #[test]
+    fn path_glob_stars() {
+	assert!(path_glob_match("*.jpg", "a.jpg"));
+	assert!(!path_glob_match("*.jpg", "dir/a.jpg"));
+	assert!(!path_glob_match("a?b", "a/b"));
+	assert!(path_glob_match("**/*.jpg", "a.jpg"));
+	assert!(path_glob_match("**/*.jpg", "a/b/c.jpg"));
+	assert!(path_glob_match("a/**/b", "a/b"));
+	assert!(path_glob_match("a/**/b", "a/x/y/b"));
+	assert!(!path_glob_match("a/**/b", "a/xb"));
+	assert!(path_glob_match("a/**", "a/x/y"));
+	assert!(path_glob_match("photos/[0-9]*/*.png", "photos/2024/x.png"));
+    }
+
+    #[test]
+    fn path_glob_is_not_exponential() {
+	let path = format!("{}b", "a/".repeat(30) + &"a".repeat(40));
+	assert!(!path_glob_match("**/*a*a*a*a*a*a*a*a*c", &path));
+	assert!(!path_glob_match("**/**/**/**/**/**/*a*a*a*c", &path));
+	assert!(path_glob_match("**/*a*a*a*b", &path));
+    }
+}
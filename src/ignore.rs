@@ -0,0 +1,191 @@
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::glob::path_glob_match;
+
+pub const IGNORE_FILE_NAME: &str = ".wallpaperignore";
+
+// One line of a .wallpaperignore file, with the gitignore semantics: a leading `!` re-includes
+// what a previous rule excluded, a trailing `/` only matches directories, and a pattern that
+// contains a `/` is relative to the directory of the file instead of matching names at any depth.
+#[derive(Debug, Clone, PartialEq)]
+struct IgnoreRule {
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl IgnoreRule {
+    fn parse(line: &str) -> Option<IgnoreRule> {
+	let mut line = line.trim_end();
+	if line.is_empty() || line.starts_with('#') {
+	    return None;
+	}
+
+	let mut negated = false;
+	if let Some(stripped) = line.strip_prefix('!') {
+	    negated = true;
+	    line = stripped;
+	} else if line.starts_with("\\!") || line.starts_with("\\#") {
+	    line = &line[1..];
+	}
+
+	let mut dir_only = false;
+	if let Some(stripped) = line.strip_suffix('/') {
+	    dir_only = true;
+	    line = stripped;
+	}
+
+	let anchored = line.contains('/');
+	let pattern = line.strip_prefix('/').unwrap_or(line);
+	if pattern.is_empty() {
+	    return None;
+	}
+
+	Some(IgnoreRule {
+	    pattern: String::from(pattern),
+	    negated,
+	    dir_only,
+	    anchored,
+	})
+    }
+
+    fn matches(&self, relative_path: &str, is_dir: bool) -> bool {
+	if self.dir_only && !is_dir {
+	    return false;
+	}
+	if self.anchored {
+	    return path_glob_match(&self.pattern, relative_path);
+	}
+	let file_name = match relative_path.rfind('/') {
+	    Some(index) => &relative_path[index + 1..],
+	    None => relative_path,
+	};
+	path_glob_match(&self.pattern, file_name)
+    }
+}
+
+// The rules of a .wallpaperignore file, or of the `exclude` config key, which apply to the
+// directory `base` and everything below it
+#[derive(Debug, Clone, PartialEq)]
+pub struct IgnoreFile {
+    base: PathBuf,
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreFile {
+    pub fn from_patterns<S: AsRef<str>>(base: &Path, patterns: &[S]) -> IgnoreFile {
+	IgnoreFile {
+	    base: base.to_path_buf(),
+	    rules: patterns
+		.iter()
+		.filter_map(|pattern| IgnoreRule::parse(pattern.as_ref()))
+		.collect(),
+	}
+    }
+
+    // The .wallpaperignore file of dir, if there is one
+    pub fn read(dir: &Path) -> Option<IgnoreFile> {
+	let ignore_file_path = dir.join(IGNORE_FILE_NAME);
+	let mut file = match File::open(&ignore_file_path) {
+	    Ok(file) => file,
+	    Err(_) => return None,
+	};
+	let mut content = String::new();
+	if let Err(error) = file.read_to_string(&mut content) {
+	    println!(
+		"failed to read {}: {}",
+		ignore_file_path.to_string_lossy(),
+		error
+	    );
+	    return None;
+	}
+	let lines: Vec<&str> = content.lines().collect();
+	Some(IgnoreFile::from_patterns(dir, &lines))
+    }
+
+    // Some(true) if the last rule that matches path excludes it, Some(false) if it re-includes
+    // it, None if no rule matches
+    fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+	let relative_path = match path.strip_prefix(&self.base) {
+	    Ok(relative_path) => relative_path.to_string_lossy(),
+	    Err(_) => return None,
+	};
+	self.rules
+	    .iter()
+	    .rev()
+	    .find(|rule| rule.matches(&relative_path, is_dir))
+	    .map(|rule| !rule.negated)
+    }
+}
+
+// ignore_files goes from the outermost directory to the innermost one, and the rules of inner
+// files take precedence
+pub fn is_ignored(ignore_files: &[IgnoreFile], path: &Path, is_dir: bool) -> bool {
+    for ignore_file in ignore_files.iter().rev() {
+	if let Some(ignored) = ignore_file.matched(path, is_dir) {
+	    return ignored;
+	}
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(patterns: &[&str], relative_path: &str, is_dir: bool) -> bool {
+	let base = Path::new("/pictures");
+	let ignore_files = [IgnoreFile::from_patterns(base, patterns)];
+	is_ignored(&ignore_files, &base.join(relative_path), is_dir)
+    }
+
+    #[test]
+    fn unanchored_patterns_match_names_at_any_depth() {
+	assert!(ignored(&["*.png"], "a.png", false));
+	assert!(ignored(&["*.png"], "dir/sub/a.png", false));
+	assert!(!ignored(&["*.png"], "a.jpg", false));
+    }
+
+    #[test]
+    fn anchored_patterns_are_relative_to_the_base() {
+	assert!(ignored(&["/drafts"], "drafts", true));
+	assert!(!ignored(&["/drafts"], "old/drafts", true));
+	assert!(ignored(&["old/*.jpg"], "old/a.jpg", false));
+	assert!(!ignored(&["old/*.jpg"], "new/old/a.jpg", false));
+    }
+
+    #[test]
+    fn dir_only_patterns() {
+	assert!(ignored(&["raw/"], "raw", true));
+	assert!(!ignored(&["raw/"], "raw", false));
+    }
+
+    #[test]
+    fn negation_reincludes_and_last_rule_wins() {
+	let patterns = ["*.jpg", "!keep.jpg"];
+	assert!(ignored(&patterns, "a.jpg", false));
+	assert!(!ignored(&patterns, "keep.jpg", false));
+	assert!(ignored(&["!keep.jpg", "*.jpg"], "keep.jpg", false));
+	assert!(ignored(&["\\!bang.jpg"], "!bang.jpg", false));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+	assert!(!ignored(&["# *.jpg", "", "   "], "a.jpg", false));
+	assert!(ignored(&["\\#hash.jpg"], "#hash.jpg", false));
+    }
+
+    #[test]
+    fn inner_files_take_precedence() {
+	let outer = IgnoreFile::from_patterns(Path::new("/pictures"), &["*.jpg"]);
+	let inner = IgnoreFile::from_patterns(Path::new("/pictures/keep"), &["!*.jpg"]);
+	let ignore_files = [outer, inner];
+	assert!(is_ignored(&ignore_files, Path::new("/pictures/a.jpg"), false));
+	assert!(!is_ignored(&ignore_files, Path::new("/pictures/keep/a.jpg"), false));
+    }
+}
@@ -1,26 +1,371 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use std::fs::read_dir;
+use std::io::{BufRead, Cursor, Seek};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
-use image::{ImageFormat, ImageReader};
+use image::{metadata::Orientation, ImageDecoder, ImageFormat, ImageReader};
 
+use crate::archive::{is_archive, scan_archive, ArchiveEntry};
+use crate::dedup::Deduplicate;
+use crate::filter::ImageFilter;
+use crate::glob::path_glob_match;
+use crate::ignore::{is_ignored, IgnoreFile, IGNORE_FILE_NAME};
 use crate::image_order_prio::Priority;
+use crate::metadata_cache::MetadataCache;
+use crate::order::DEFAULT_IMAGE_WEIGHT;
 use crate::output::Output;
+use crate::playlist::{is_playlist, scan_playlist};
+use crate::sidecar::{is_sidecar, sidecar_path, ImageOverrides};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ImageFile {
     pub width: u32,
     pub height: u32,
+    pub format: ImageFormat,
+    // the EXIF orientation, width and height are those of the image once it is applied
+    pub orientation: Orientation,
+    // for the images found in an archive, path is that of the archive followed by the name of the
+    // entry, which does not exist on the file system
     pub path: PathBuf,
+    pub archive_entry: Option<ArchiveEntry>,
+    // what its sidecar file changes to the settings of the config
+    pub overrides: Option<ImageOverrides>,
 }
 
 
-pub fn get_image_list(dir_path: &String, authorized_formats: &Vec<ImageFormat>) -> Vec<ImageFile> {
-    let dir = match read_dir(&dir_path) {
-        Ok(dir) => dir,
-        Err(error) => panic!("Error when opening {} directory: {}", dir_path, error),
+// The cargo feature that enables the decoder for a format, for the formats that are not always built
+fn cargo_feature(format: ImageFormat) -> Option<&'static str> {
+    match format {
+	ImageFormat::Gif => Some("gif"),
+	ImageFormat::Bmp => Some("bmp"),
+	ImageFormat::Avif => Some("avif"),
+	ImageFormat::Qoi => Some("qoi"),
+	ImageFormat::Tga => Some("tga"),
+	ImageFormat::Pnm => Some("pnm"),
+	ImageFormat::Hdr => Some("hdr"),
+	ImageFormat::OpenExr => Some("exr"),
+	ImageFormat::Ico => Some("ico"),
+	_ => None,
+    }
+}
+
+pub fn decoding_enabled(format: ImageFormat) -> bool {
+    match format {
+	ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Tiff | ImageFormat::WebP => true,
+	ImageFormat::Gif => cfg!(feature = "gif"),
+	ImageFormat::Bmp => cfg!(feature = "bmp"),
+	ImageFormat::Avif => cfg!(feature = "avif"),
+	ImageFormat::Qoi => cfg!(feature = "qoi"),
+	ImageFormat::Tga => cfg!(feature = "tga"),
+	ImageFormat::Pnm => cfg!(feature = "pnm"),
+	ImageFormat::Hdr => cfg!(feature = "hdr"),
+	ImageFormat::OpenExr => cfg!(feature = "exr"),
+	ImageFormat::Ico => cfg!(feature = "ico"),
+	_ => false,
+    }
+}
+
+pub fn format_name(format: ImageFormat) -> String {
+    format!("{:?}", format).to_lowercase()
+}
+
+// Accepts the names printed by format_name, as well as the file extensions of the formats
+pub fn parse_format(name: &str) -> Option<ImageFormat> {
+    let name = name.to_lowercase();
+    match ImageFormat::all().find(|format| format_name(*format) == name) {
+	Some(format) => Some(format),
+	None => ImageFormat::from_extension(name),
+    }
+}
+
+// Logs the configured formats that cannot be decoded by this build
+pub fn report_disabled_formats(authorized_formats: &[ImageFormat]) {
+    for format in authorized_formats.iter().filter(|format| !decoding_enabled(**format)) {
+	match cargo_feature(*format) {
+	    Some(feature) => println!(
+		"format {} is authorized in the config, but support for it was not compiled in (cargo feature `{}`), its images will be ignored",
+		format_name(*format), feature
+	    ),
+	    None => println!(
+		"format {} is authorized in the config, but it is not supported, its images will be ignored",
+		format_name(*format)
+	    ),
+	}
+    }
+}
+
+// What get_image_list looks for
+#[derive(Debug, PartialEq)]
+pub struct ScanOptions<'a> {
+    pub authorized_formats: &'a Vec<ImageFormat>,
+    // whether subdirectories are scanned, and how deep
+    pub recursive: bool,
+    pub max_depth: u32,
+    // if not empty, only the files that match one of these patterns are used
+    pub include: &'a Vec<String>,
+    // gitignore-style patterns, applied as if they were in a .wallpaperignore at the root
+    pub exclude: &'a Vec<String>,
+    pub extension_prefilter: bool,
+    pub scan_threads: u32,
+    pub deduplicate: Deduplicate,
+    pub similarity_threshold: u32,
+    pub filter: &'a ImageFilter,
+}
+
+// The same options, for the scans that run on another thread
+#[derive(Debug, Clone)]
+pub struct OwnedScanOptions {
+    authorized_formats: Vec<ImageFormat>,
+    recursive: bool,
+    max_depth: u32,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    extension_prefilter: bool,
+    scan_threads: u32,
+    deduplicate: Deduplicate,
+    similarity_threshold: u32,
+    filter: ImageFilter,
+}
+
+impl ScanOptions<'_> {
+    pub fn to_owned_options(&self) -> OwnedScanOptions {
+	OwnedScanOptions {
+	    authorized_formats: self.authorized_formats.clone(),
+	    recursive: self.recursive,
+	    max_depth: self.max_depth,
+	    include: self.include.clone(),
+	    exclude: self.exclude.clone(),
+	    extension_prefilter: self.extension_prefilter,
+	    scan_threads: self.scan_threads,
+	    deduplicate: self.deduplicate,
+	    similarity_threshold: self.similarity_threshold,
+	    filter: self.filter.clone(),
+	}
+    }
+}
+
+impl OwnedScanOptions {
+    pub fn scan_options(&self) -> ScanOptions<'_> {
+	ScanOptions {
+	    authorized_formats: &self.authorized_formats,
+	    recursive: self.recursive,
+	    max_depth: self.max_depth,
+	    include: &self.include,
+	    exclude: &self.exclude,
+	    extension_prefilter: self.extension_prefilter,
+	    scan_threads: self.scan_threads,
+	    deduplicate: self.deduplicate,
+	    similarity_threshold: self.similarity_threshold,
+	    filter: &self.filter,
+	}
+    }
+}
+
+// Called with each image as soon as it is found, before the scan is over. Images are found in no
+// particular order.
+pub type FoundImage<'a> = &'a mut dyn FnMut(&mut ImageFile);
+
+// What scan_dir found, before the files are probed
+#[derive(Debug, Default)]
+struct Walk {
+    dirs: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+    // every file of dirs, whether it is probed or not
+    listed: HashSet<PathBuf>,
+    // the files that may be the sidecar of an image
+    sidecars: HashSet<PathBuf>,
+    skipped_by_extension: usize,
+}
+
+// What a scan found: the images, and the directories that were read to find them
+#[derive(Debug, Default)]
+pub struct ScanResult {
+    pub image_list: Vec<ImageFile>,
+    pub dirs: Vec<PathBuf>,
+}
+
+pub fn is_usable_format(path: &Path, format: ImageFormat, authorized_formats: &[ImageFormat]) -> bool {
+    if ! authorized_formats.contains(&format) {
+	println!("Format {:#?} for file {} is not authorized", format, path.to_string_lossy());
+	return false;
+    }
+    if ! decoding_enabled(format) {
+	println!("Format {:#?} for file {} is not compiled in", format, path.to_string_lossy());
+	return false;
+    }
+    true
+}
+
+// What probing a file found out, whatever the formats that are authorized
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProbeResult {
+    Image { format: ImageFormat, width: u32, height: u32, orientation: Orientation },
+    // an image whose decoder was not compiled in
+    Unsupported { format: ImageFormat },
+    // not an image, or a broken one
+    Unreadable,
+}
+
+// Reads the format and dimensions of an image without decoding it. None if the file cannot be
+// opened, which may only last until its permissions change.
+pub fn probe_file(path: &Path) -> Option<ProbeResult> {
+    match ImageReader::open(path) {
+	Ok(image_reader) => Some(probe_reader(path, image_reader)),
+	Err(error) => {
+	    println!("could not open image {} : {}", path.to_string_lossy(), error);
+	    None
+	},
+    }
+}
+
+// Same as probe_file, for an image that was read in memory
+pub fn probe_data(path: &Path, data: &[u8]) -> ProbeResult {
+    probe_reader(path, ImageReader::new(Cursor::new(data)))
+}
+
+// path is only used in messages
+fn probe_reader<R: BufRead + Seek>(path: &Path, mut image_reader: ImageReader<R>) -> ProbeResult {
+    image_reader = match image_reader.with_guessed_format() {
+	Ok(image_reader) => image_reader,
+	Err(error) => {
+	    println!("Failed to decode format for file {} : {}", path.to_string_lossy(), error);
+	    return ProbeResult::Unreadable;
+	},
+    };
+
+    let format = match image_reader.format() {
+	Some(format) => {
+	    if ! decoding_enabled(format) {
+		return ProbeResult::Unsupported { format };
+	    }
+	    format
+	},
+	None => {
+	    println!("Failed to get format for file {}", path.to_string_lossy());
+	    return ProbeResult::Unreadable;
+	}
+    };
+
+    let mut decoder = match image_reader.into_decoder() {
+	Ok(decoder) => decoder,
+	Err(error)  => {
+	    println!("Failed to get dimensions format for file {} : {}", path.to_string_lossy(), error);
+	    return ProbeResult::Unreadable;
+	},
+    };
+    let (width, height) = decoder.dimensions();
+    let orientation = match decoder.orientation() {
+	Ok(orientation) => orientation,
+	Err(error) => {
+	    println!("Failed to read the orientation of {} : {}", path.to_string_lossy(), error);
+	    Orientation::NoTransforms
+	},
     };
+    if swaps_dimensions(orientation) {
+	ProbeResult::Image { format, width: height, height: width, orientation }
+    } else {
+	ProbeResult::Image { format, width, height, orientation }
+    }
+}
+
+// Whether the image is displayed sideways, with its width and height exchanged
+pub fn swaps_dimensions(orientation: Orientation) -> bool {
+    matches!(
+	orientation,
+	Orientation::Rotate90 | Orientation::Rotate270 | Orientation::Rotate90FlipH | Orientation::Rotate270FlipH
+    )
+}
 
-    let mut image_list: Vec<ImageFile> = Vec::new();
+pub fn image_from_probe(path: &Path, probe_result: ProbeResult, authorized_formats: &[ImageFormat]) -> Option<ImageFile> {
+    match probe_result {
+	ProbeResult::Image { format, width, height, orientation } => {
+	    if ! is_usable_format(path, format, authorized_formats) {
+		return None;
+	    }
+	    Some(ImageFile::new(width, height, format, orientation, path.to_path_buf()))
+	},
+	ProbeResult::Unsupported { format } => {
+	    // only for the message
+	    is_usable_format(path, format, authorized_formats);
+	    None
+	},
+	ProbeResult::Unreadable => None,
+    }
+}
+
+pub fn probe_image(path: &Path, authorized_formats: &[ImageFormat]) -> Option<ImageFile> {
+    image_from_probe(path, probe_file(path)?, authorized_formats)
+}
+
+fn is_included(root: &Path, path: &Path, include: &[String]) -> bool {
+    if include.is_empty() {
+	return true;
+    }
+    let relative_path = match path.strip_prefix(root) {
+	Ok(relative_path) => relative_path.to_string_lossy(),
+	Err(_) => return false,
+    };
+    let file_name = match path.file_name() {
+	Some(file_name) => file_name.to_string_lossy(),
+	None => return false,
+    };
+    // like in ignore files, patterns without a slash match the file name at any depth
+    include.iter().any(|pattern| {
+	if pattern.contains('/') {
+	    path_glob_match(pattern.trim_start_matches('/'), &relative_path)
+	} else {
+	    path_glob_match(pattern, &file_name)
+	}
+    })
+}
+
+// The fast path of extension_prefilter: files whose extension is unknown or belongs to a format
+// that is not authorized are not opened. Files without an extension are always probed.
+pub fn has_authorized_extension(path: &Path, scan_options: &ScanOptions) -> bool {
+    if !scan_options.extension_prefilter {
+	return true;
+    }
+    let extension = match path.extension() {
+	Some(extension) => extension,
+	None => return true,
+    };
+    match ImageFormat::from_extension(extension) {
+	Some(format) => scan_options.authorized_formats.contains(&format),
+	None => false,
+    }
+}
+
+// (device, inode) of a directory, to detect the symlinks that lead to a directory we already
+// scanned
+fn dir_id(path: &Path) -> Option<(u64, u64)> {
+    match std::fs::metadata(path) {
+	Ok(metadata) => Some((metadata.dev(), metadata.ino())),
+	Err(_) => None,
+    }
+}
+
+fn scan_dir(
+    root: &Path,
+    dir_path: &Path,
+    depth: u32,
+    scan_options: &ScanOptions,
+    ignore_files: &mut Vec<IgnoreFile>,
+    visited_dirs: &mut HashSet<(u64, u64)>,
+    walk: &mut Walk,
+) -> Result<(), std::io::Error> {
+    let dir = read_dir(dir_path)?;
+    walk.dirs.push(dir_path.to_path_buf());
+
+    let has_ignore_file = match IgnoreFile::read(dir_path) {
+	Some(ignore_file) => {
+	    ignore_files.push(ignore_file);
+	    true
+	},
+	None => false,
+    };
 
     for dir_entry_res in dir {
 	let dir_entry = match dir_entry_res {
@@ -30,178 +375,390 @@ pub fn get_image_list(dir_path: &String, authorized_formats: &Vec<ImageFormat>)
 		continue;
 	    },
 	};
-	let _ = match dir_entry.file_type() {
-	    Ok(file_type) => {
-		if ! file_type.is_file() {
-		    continue;
-		}
-	    },
-	    Err(error) => {
-		println!("Could not determine file type for {}: {}", Path::new(&dir_path).join(dir_entry.path()).to_string_lossy(), error);
-		continue;
-	    }
-	};
-	let dir_entry_path = Path::new(&dir_path).join(dir_entry.path());
+	let dir_entry_path = dir_entry.path();
+	if dir_entry.file_name() == IGNORE_FILE_NAME {
+	    continue;
+	}
 
-	let mut image_reader = match ImageReader::open(&dir_entry_path) {
-	    Ok(image_reader) => image_reader,
+	let file_type = match dir_entry.file_type() {
+	    Ok(file_type) => file_type,
 	    Err(error) => {
-		println!("could not open image {} : {}", Path::new(&dir_path).join(dir_entry.path()).to_string_lossy(), error);
+		println!("Could not determine file type for {}: {}", dir_entry_path.to_string_lossy(), error);
 		continue;
-	    },
-	};
-	image_reader = match image_reader.with_guessed_format() {
-	    Ok(image_reader) => image_reader,
-	    Err(error) => {
-		println!("Failed to decode format for file {} : {}", dir_entry_path.to_string_lossy(), error);
-		continue;
-	    },
+	    }
 	};
-
-	let _ = match image_reader.format() {
-	    Some(format) => {
-		if ! authorized_formats.contains(&format) {
-		    println!("Format {:#?} for file {} is not authorized", format, dir_entry_path.to_string_lossy());
+	// symlinks are followed
+	let (is_dir, is_file) = if file_type.is_symlink() {
+	    match std::fs::metadata(&dir_entry_path) {
+		Ok(metadata) => (metadata.is_dir(), metadata.is_file()),
+		Err(error) => {
+		    println!("Could not follow symlink {}: {}", dir_entry_path.to_string_lossy(), error);
 		    continue;
 		}
-	    },
-	    None => {
-		println!("Failed to get format for file {}", dir_entry_path.to_string_lossy());
-		continue;
 	    }
+	} else {
+	    (file_type.is_dir(), file_type.is_file())
 	};
 
-	let (width, height) = match image_reader.into_dimensions() {
-	    Ok((width, height)) => (width, height),
-	    Err(error)  => {
-		println!("Failed to get dimensions format for file {} : {}", dir_entry_path.to_string_lossy(), error);
+	if !is_dir && !is_file {
+	    continue;
+	}
+	if is_file {
+	    walk.listed.insert(dir_entry_path.clone());
+	}
+
+	if is_file && is_sidecar(&dir_entry_path) {
+	    walk.sidecars.insert(dir_entry_path);
+	    continue;
+	}
+
+	if is_ignored(ignore_files, &dir_entry_path, is_dir) {
+	    continue;
+	}
+
+	if is_dir {
+	    if !scan_options.recursive || depth >= scan_options.max_depth {
 		continue;
-	    },
-	};
-	image_list.push(ImageFile {
-	    width,
-	    height,
-	    path: dir_entry_path,
-	});
+	    }
+	    match dir_id(&dir_entry_path) {
+		Some(id) => {
+		    if !visited_dirs.insert(id) {
+			println!("{} was already scanned, skipping it", dir_entry_path.to_string_lossy());
+			continue;
+		    }
+		},
+		None => continue,
+	    }
+	    if let Err(error) = scan_dir(root, &dir_entry_path, depth + 1, scan_options, ignore_files, visited_dirs, walk) {
+		println!("Error when opening {} directory: {}", dir_entry_path.to_string_lossy(), error);
+	    }
+	    continue;
+	}
+
+	if !is_included(root, &dir_entry_path, scan_options.include) {
+	    continue;
+	}
+
+	if !has_authorized_extension(&dir_entry_path, scan_options) {
+	    walk.skipped_by_extension += 1;
+	    continue;
+	}
+
+	walk.files.push(dir_entry_path);
+    };
+
+    if has_ignore_file {
+	ignore_files.pop();
+    }
+    Ok(())
+}
+
+// How many levels below root dir is
+fn depth_below(root: &Path, dir: &Path) -> Option<u32> {
+    match dir.strip_prefix(root) {
+	Ok(relative_path) => Some(relative_path.components().count() as u32),
+	Err(_) => None,
+    }
+}
+
+// The ignore files that apply to the entries of dir, which is root or one of its subdirectories:
+// the exclude patterns, then the .wallpaperignore files from root down to dir
+fn ignore_files_for(root: &Path, dir: &Path, scan_options: &ScanOptions) -> Vec<IgnoreFile> {
+    let mut ignore_files = vec!(IgnoreFile::from_patterns(root, scan_options.exclude));
+    let relative_path = dir.strip_prefix(root).unwrap_or(Path::new(""));
+    let mut current_dir = root.to_path_buf();
+    if let Some(ignore_file) = IgnoreFile::read(&current_dir) {
+	ignore_files.push(ignore_file);
+    }
+    for component in relative_path.components() {
+	current_dir.push(component);
+	if let Some(ignore_file) = IgnoreFile::read(&current_dir) {
+	    ignore_files.push(ignore_file);
+	}
+    }
+    ignore_files
+}
+
+// Whether a scan of root would pick up path, a file or a directory that appeared in one of the
+// directories it read
+pub fn is_scanned(root: &Path, path: &Path, is_dir: bool, scan_options: &ScanOptions) -> bool {
+    let dir = match path.parent() {
+	Some(dir) => dir,
+	None => return false,
     };
-    return image_list;
+    if is_ignored(&ignore_files_for(root, dir, scan_options), path, is_dir) {
+	return false;
+    }
+    if is_dir {
+	return scan_options.recursive
+	    && depth_below(root, path).is_some_and(|depth| depth <= scan_options.max_depth);
+    }
+    path.file_name().is_some_and(|file_name| file_name != IGNORE_FILE_NAME)
+	&& !is_sidecar(path)
+	&& is_included(root, path, scan_options.include)
+	&& has_authorized_extension(path, scan_options)
+}
+
+// Scans dir, which is root or one of its subdirectories, the way it is scanned as part of root
+pub fn scan_images(
+    root: &Path,
+    dir: &Path,
+    scan_options: &ScanOptions,
+    metadata_cache: &mut MetadataCache,
+    found: FoundImage,
+) -> Result<ScanResult, std::io::Error> {
+    let start = Instant::now();
+    let mut walk = Walk::default();
+    let mut ignore_files = match dir.parent() {
+	Some(parent) if dir != root => ignore_files_for(root, parent, scan_options),
+	_ => vec!(IgnoreFile::from_patterns(root, scan_options.exclude)),
+    };
+    let mut visited_dirs = HashSet::new();
+    if let Some(id) = dir_id(dir) {
+	visited_dirs.insert(id);
+    }
+    let depth = depth_below(root, dir).unwrap_or(0);
+
+    scan_dir(root, dir, depth, scan_options, &mut ignore_files, &mut visited_dirs, &mut walk)?;
+    let walk_duration = start.elapsed();
+
+    let (image_list, probe_stats) = metadata_cache.probe_all(&walk.files, scan_options, &mut |image_file| {
+	if walk.sidecars.contains(&sidecar_path(&image_file.path)) {
+	    image_file.overrides = ImageOverrides::load(&image_file.path);
+	}
+	found(image_file);
+    });
+    metadata_cache.prune(dir, &walk.dirs, &walk.listed);
+    println!(
+	"scanned {} in {:.2?} ({:.2?} listing): {} directories, {} files, {} skipped by extension, {} from the cache, {} probed, {} filtered out, {} images",
+	dir.to_string_lossy(),
+	start.elapsed(),
+	walk_duration,
+	walk.dirs.len(),
+	walk.files.len() + walk.skipped_by_extension,
+	walk.skipped_by_extension,
+	probe_stats.cached,
+	probe_stats.probed,
+	probe_stats.rejected,
+	image_list.len()
+    );
+    Ok(ScanResult {
+	image_list,
+	dirs: walk.dirs,
+    })
+}
+
+// An empty result if root cannot be read, e.g. a drive that is not mounted yet. The error is
+// logged, and the source is tried again later.
+pub fn get_image_list(
+    root: &Path,
+    scan_options: &ScanOptions,
+    metadata_cache: &mut MetadataCache,
+    found: FoundImage,
+) -> ScanResult {
+    if is_archive(root) {
+	return match scan_archive(root, scan_options, metadata_cache, found) {
+	    Ok(scan_result) => scan_result,
+	    Err(error) => {
+		println!("Error when reading {} archive: {}", root.to_string_lossy(), error);
+		ScanResult::default()
+	    }
+	};
+    }
+    if is_playlist(root) {
+	return match scan_playlist(root, scan_options, metadata_cache, found) {
+	    Ok(scan_result) => scan_result,
+	    Err(error) => {
+		println!("Error when reading {} playlist: {}", root.to_string_lossy(), error);
+		ScanResult::default()
+	    }
+	};
+    }
+    get_dir_image_list(root, root, scan_options, metadata_cache, found)
+}
+
+// The images below dir, a directory inside root, such as one that appeared after root was scanned
+pub fn get_dir_image_list(
+    root: &Path,
+    dir: &Path,
+    scan_options: &ScanOptions,
+    metadata_cache: &mut MetadataCache,
+    found: FoundImage,
+) -> ScanResult {
+    match scan_images(root, dir, scan_options, metadata_cache, found) {
+	Ok(scan_result) => scan_result,
+	Err(error) => {
+	    println!("Error when opening {} directory: {}", dir.to_string_lossy(), error);
+	    ScanResult::default()
+	}
+    }
 }
 
 
 impl ImageFile {
-    pub fn new(width: u32, height: u32, path: PathBuf) -> Self {
+    pub fn new(width: u32, height: u32, format: ImageFormat, orientation: Orientation, path: PathBuf) -> Self {
 	ImageFile {
 	    width,
 	    height,
-	    path
+	    format,
+	    orientation,
+	    path,
+	    archive_entry: None,
+	    overrides: None,
 	}
     }
-}
 
-struct CurImage {
-    image: usize,
-    prio: Priority
+    pub fn shows_on(&self, output_name: &str) -> bool {
+	self.overrides.as_ref().is_none_or(|overrides| overrides.shows_on(output_name))
+    }
+
+    // How long the image stays on screen, when its sidecar says so
+    pub fn duration(&self) -> Option<Duration> {
+	self.overrides.as_ref().and_then(|overrides| overrides.duration())
+    }
+
+    // How often the image is drawn when the order is Random, 1 unless its sidecar says otherwise
+    pub fn weight(&self) -> u32 {
+	self.overrides.as_ref().and_then(|overrides| overrides.weight).unwrap_or(DEFAULT_IMAGE_WEIGHT)
+    }
 }
 
+// How well the image suits the resolution of the output. Only an image of exactly the resolution of
+// the output is a best fit. Images larger than the output come before the smaller ones, they only
+// lose details when they are downsized while the others have to be upsized or leave borders. An
+// image that matches one dimension and is smaller in the other is a smaller one. Within each group,
+// the closer to the resolution of the output the better.
 pub fn get_image_fit(image_file: &ImageFile, output: &Output) -> Priority {
+    let image_area = image_file.width as f32 * image_file.height as f32;
+    let output_area = output.mode_width as f32 * output.mode_height as f32;
+    if image_area == 0.0 || output_area == 0.0 {
+	return Priority::Any;
+    }
     if image_file.width > output.mode_width || image_file.height > output.mode_height {
-	return Priority::Upsize { fact: (image_file.width * image_file.height) as f32 / (output.mode_width * output.mode_height) as f32 };
-    } else if image_file.width == output.mode_width || image_file.height == output.mode_height {
+	return Priority::Larger { fact: output_area / image_area };
+    } else if image_file.width == output.mode_width && image_file.height == output.mode_height {
 	return Priority::BestFit;
-    } else if image_file.width < output.mode_width && image_file.height < output.mode_height {
-	return Priority::Downsize { fact: (image_file.width * image_file.height) as f32 / (output.mode_width * output.mode_height) as f32 };
-    }
-
-    // Should never be returned
-    return Priority::Any;
-}
-
-// pub fn assign_everything_everyone(image_list: &Vec<ImageFile>, screens: &mut HashMap<u32, Output>) {
-//     let index: Vec<usize> = image_list.iter().enumerate().map(|(index, _)| index).collect();
-//     for (_, screen) in screens.iter_mut() {
-// 	screen.image_list = index.clone();
-//     }
-// }
-
-// pub fn assign_next_file(image_list: &Vec<ImageFile>, screens: &mut HashMap<u32, Output>) {
-//     let ratio = image_list.len() / screens.len();
-//     if ratio == 0 {
-// 	println!("less images than there are screens, assigning everything to everyone");
-// 	assign_everything_everyone(image_list, screens);
-// 	return;
-//     }
-//     let mut screens_iter_mut = screens.iter_mut();
-//     for (index, _file) in image_list.iter().enumerate() {
-// 	let (mut _screen_index, curscreen) = match screens_iter_mut.next() {
-// 	    Some(curscreen) => curscreen,
-// 	    None => {
-// 		screens_iter_mut = screens.iter_mut();
-// 		screens_iter_mut.next().unwrap()
-// 	    }
-// 	};
-// 	curscreen.image_list.push(index);
-//     }
-// }
-
-// pub fn assign_best_fit(image_list: &Vec<ImageFile>, screens: &mut HashMap<u32, Output>) {
-//     let ratio = image_list.len() / screens.len();
-//     if ratio == 0 {
-// 	println!("less images than there are screens, assigning everything to everyone");
-// 	assign_everything_everyone(image_list, screens);
-// 	return;
-//     }
-
-// 	cur_best_screen.unwrap().image_list.push(index);
-//     }
-    // let mut marked_indices: HashMap<usize, bool> = HashMap::new();
-    // let mut screenstate_map: HashMap<u32, Priority> = HashMap::new();
-    // while marked_indices.len() < image_list.len() {
-
-    // 	for (key, output) in screens.iter_mut() {
-    // 	    if marked_indices.len() == image_list.len() {
-    // 		break;
-    // 	    }
-    // 	    let current_prio = match screenstate_map.get(key) {
-    // 		Some(screenstate) => screenstate,
-    // 		None => {
-    // 		    screenstate_map.insert(*key, Priority::BestFit);
-    // 		    screenstate_map.get(key).unwrap()
-    // 		}
-    // 	    };
-
-
-    // 	    let mut curimage: Option<CurImage> = None;
-
-    // 	    for (index, image) in image_list.iter().enumerate() {
-    // 		if let Some(_)  = marked_indices.get(&index) {
-    // 		    continue;
-    // 		}
-
-    // 		let prio = get_image_fit(image, output, best_fit_fact);
-
-    // 		if prio == *current_prio {
-    // 		    println!("giving image {}x{} to screen {}x{} policy {:#?}", image.width, image.height, output.mode_width, output.mode_height, current_prio);
-    // 		    curimage = Some(CurImage {
-    // 			image: index,
-    // 			prio,
-    // 		    });
-    // 		    break;
-    // 		}
-    // 	    }
-    // 	    let _ = match curimage {
-    // 		Some(image) => {
-    // 		    marked_indices.insert(image.image, true);
-    // 		    output.image_list.push(image.image);
-    // 		}
-    // 		None => {
-    // 		    let _ = match current_prio {
-    // 			Priority::BestFit => screenstate_map.insert(*key, Priority::UpsizeSmall),
-    // 			Priority::UpsizeSmall => screenstate_map.insert(*key, Priority::UpsizeAny),
-    // 			Priority::UpsizeAny => screenstate_map.insert(*key, Priority::Any),
-    // 			Priority::Any => panic!("not supposed to go anywhere after this"),
-    // 		    };
-    // 		}
-    // 	    };
-    // 	}
-    // }
-// }
+    }
+    Priority::Smaller { fact: output_area / image_area }
+}
+
+// Shares the images out between the outputs, each of them getting those that suit its resolution
+// best. The outputs take turns picking their best image among those left, so that every output
+// gets its share even when most images suit another one better. Returns the name of the output
+// each image goes to, by path.
+pub fn assign_best_fit(image_list: &[ImageFile], outputs: &[&Output]) -> HashMap<PathBuf, String> {
+    // for each output, the images it can show, best first
+    let preferences: Vec<Vec<usize>> = outputs
+	.iter()
+	.map(|output| {
+	    let mut indices: Vec<usize> = (0..image_list.len())
+		.filter(|index| image_list[*index].shows_on(&output.name))
+		.collect();
+	    indices.sort_by_cached_key(|index| Reverse(get_image_fit(&image_list[*index], output)));
+	    indices
+	})
+	.collect();
+
+    let mut assignment = HashMap::new();
+    let mut assigned = vec![false; image_list.len()];
+    let mut next_preference = vec![0; outputs.len()];
+    loop {
+	let mut picked_any = false;
+	for (output_index, output) in outputs.iter().enumerate() {
+	    let preferences = &preferences[output_index];
+	    let next = &mut next_preference[output_index];
+	    while *next < preferences.len() && assigned[preferences[*next]] {
+		*next += 1;
+	    }
+	    if let Some(&index) = preferences.get(*next) {
+		assigned[index] = true;
+		assignment.insert(image_list[index].path.clone(), output.name.clone());
+		picked_any = true;
+	    }
+	}
+	if !picked_any {
+	    return assignment;
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Matcher;
+
+    fn output(name: &str, mode_width: u32, mode_height: u32) -> Output {
+	let mut output = Output::new();
+	output.name = String::from(name);
+	output.mode_width = mode_width;
+	output.mode_height = mode_height;
+	output
+    }
+
+    fn image(path: &str, width: u32, height: u32) -> ImageFile {
+	ImageFile::new(width, height, ImageFormat::Png, Orientation::NoTransforms, PathBuf::from(path))
+    }
+
+    #[test]
+    fn image_fit() {
+	let output = output("DP-1", 1920, 1080);
+	let fit = |width, height| get_image_fit(&image("image.png", width, height), &output);
+	assert!(fit(1920, 1080) > fit(2560, 1440));
+	assert!(fit(2560, 1440) > fit(3840, 2160));
+	assert!(fit(3840, 2160) > fit(1280, 720));
+	assert!(fit(1280, 720) > fit(640, 360));
+	assert!(fit(640, 360) > fit(0, 0));
+	assert!(matches!(fit(2560, 1440), Priority::Larger { .. }));
+	assert!(matches!(fit(1280, 720), Priority::Smaller { .. }));
+	assert!(matches!(fit(1920, 1080), Priority::BestFit));
+	// matching one dimension is not enough, the image would still leave borders
+	assert!(matches!(fit(1920, 800), Priority::Smaller { .. }));
+	assert!(matches!(fit(1440, 1080), Priority::Smaller { .. }));
+	assert!(matches!(fit(1920, 1200), Priority::Larger { .. }));
+	assert!(fit(1920, 1080) > fit(1920, 1200));
+	assert!(fit(1920, 1000) > fit(1920, 800));
+	assert!(fit(1440, 1080) > fit(1280, 720));
+    }
+
+    #[test]
+    fn best_fit_gives_every_output_a_share() {
+	let laptop = output("eDP-1", 1920, 1080);
+	let monitor = output("DP-1", 3840, 2160);
+	// every image but one suits the monitor best, the laptop still gets half of them
+	let image_list = vec![
+	    image("a.png", 3840, 2160),
+	    image("b.png", 4000, 3000),
+	    image("c.png", 5000, 2800),
+	    image("d.png", 1920, 1080),
+	    image("e.png", 3840, 2160),
+	    image("f.png", 6000, 4000),
+	];
+	let assignment = assign_best_fit(&image_list, &[&laptop, &monitor]);
+	assert_eq!(assignment.len(), image_list.len());
+	let share = |name: &str| assignment.values().filter(|assigned_to| *assigned_to == name).count();
+	assert_eq!(share("eDP-1"), 3);
+	assert_eq!(share("DP-1"), 3);
+	// each output gets the image that suits it best
+	assert_eq!(assignment[Path::new("d.png")], "eDP-1");
+	assert_eq!(assignment[Path::new("a.png")], "DP-1");
+    }
+
+    #[test]
+    fn best_fit_respects_sidecar_outputs() {
+	let laptop = output("eDP-1", 1920, 1080);
+	let monitor = output("DP-1", 3840, 2160);
+	let mut only_on_monitor = image("a.png", 1920, 1080);
+	only_on_monitor.overrides = Some(ImageOverrides {
+	    fit: None,
+	    background_color: None,
+	    focal_point: None,
+	    bg_duration_seconds: None,
+	    weight: None,
+	    outputs: vec![Matcher::Glob(String::from("DP-*"))],
+	});
+	let image_list = vec![only_on_monitor, image("b.png", 3840, 2160)];
+	let assignment = assign_best_fit(&image_list, &[&laptop, &monitor]);
+	assert_eq!(assignment[Path::new("a.png")], "DP-1");
+	assert_eq!(assignment[Path::new("b.png")], "eDP-1");
+    }
+}
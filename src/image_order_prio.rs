@@ -2,8 +2,10 @@
 
 pub enum Priority {
     BestFit,
-    Downsize { fact: f32 },
-    Upsize { fact: f32 },
+    // the image is larger than the output, fact is the area of the output over that of the image
+    Larger { fact: f32 },
+    // the image is smaller than the output, fact is the area of the output over that of the image
+    Smaller { fact: f32 },
     Any
 }
 
@@ -11,8 +13,8 @@ impl Priority {
     fn get_rank(&self) -> u32{
 	match self {
 	    Priority::BestFit => 1,
-	    Priority::Downsize { .. } => 2,
-	    Priority::Upsize { .. } => 3,
+	    Priority::Larger { .. } => 2,
+	    Priority::Smaller { .. } => 3,
 	    Priority::Any => 4,
 	}
     }
@@ -38,16 +40,16 @@ impl Ord for Priority {
 		    _ => std::cmp::Ordering::Greater,
 		}
 	    },
-	    Priority::Downsize { fact: fact_self } => {
+	    Priority::Larger { fact: fact_self } => {
 		match other {
 		    Priority::BestFit => std::cmp::Ordering::Less,
-		    Priority::Downsize { fact: fact_other } => fact_self.total_cmp(fact_other),
+		    Priority::Larger { fact: fact_other } => fact_self.total_cmp(fact_other),
 		    _ => std::cmp::Ordering::Greater,
 		}
 	    },
-	    Priority::Upsize { fact: fact_self } => {
+	    Priority::Smaller { fact: fact_self } => {
 		match other {
-		    Priority::Upsize { fact: fact_other } => fact_other.total_cmp(fact_self),
+		    Priority::Smaller { fact: fact_other } => fact_other.total_cmp(fact_self),
 		    Priority::Any => std::cmp::Ordering::Greater,
 		    _ => std::cmp::Ordering::Less,
 		}
@@ -55,8 +57,8 @@ impl Ord for Priority {
 	    Priority::Any => {
 		match other {
 		    Priority::BestFit => std::cmp::Ordering::Less,
-		    Priority::Downsize { fact: _ } => std::cmp::Ordering::Less,
-		    Priority::Upsize { fact: _ } => std::cmp::Ordering::Less,
+		    Priority::Larger { fact: _ } => std::cmp::Ordering::Less,
+		    Priority::Smaller { fact: _ } => std::cmp::Ordering::Less,
 		    Priority::Any => std::cmp::Ordering::Equal,
 		}
 	    },
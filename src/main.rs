@@ -5,17 +5,69 @@ pub mod background_image;
 pub mod image_file;
 pub mod config;
 pub mod image_order_prio;
+pub mod glob;
+pub mod reload;
+pub mod cli;
+pub mod xdg;
+pub mod gallery;
+pub mod ignore;
+pub mod schedule;
+pub mod duration;
+pub mod cron;
+mod dedup;
+pub mod expand;
+mod filter;
+pub mod source_watcher;
+pub mod metadata_cache;
+mod playlist;
+pub mod archive;
+pub mod sidecar;
+pub mod background_scan;
+pub mod rotation_state;
+pub mod order;
 
+use std::process::exit;
+
+use cli::Command;
 use config::Config;
+use gallery::Gallery;
+use image_file::report_disabled_formats;
 use wl_app::WlApp;
 
 fn main() {
-    let config = Config::get_config();
-    let image_list = image_file::get_image_list(&config.path, &config.authorized_formats);
-    if image_list.len() == 0 {
-	panic!("No images to set as background !");
+    reload::block_handled_signals();
+    let args = cli::parse_args();
+    match args.command {
+	Command::CheckConfig => {
+	    match Config::load(&args.config_path) {
+		Ok(config) => {
+		    report_disabled_formats(&config.authorized_formats);
+		    println!("config is valid");
+		},
+		Err(error) => {
+		    eprintln!("{}", error);
+		    exit(1);
+		}
+	    }
+	    return;
+	}
+	Command::ExplainConfig => {
+	    match Config::load(&args.config_path) {
+		Ok(config) => print!("{}", config.explain()),
+		Err(error) => {
+		    eprintln!("{}", error);
+		    exit(1);
+		}
+	    }
+	    return;
+	}
+	Command::Run => (),
     }
 
-    let mut wl_app = WlApp::new(config, image_list);
+    let config = Config::get_config(&args.config_path);
+    report_disabled_formats(&config.authorized_formats);
+    let gallery = Gallery::new(config.order, config.seed);
+
+    let mut wl_app = WlApp::new(config, args.config_path, gallery);
     wl_app.run();
 }
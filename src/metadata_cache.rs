@@ -0,0 +1,681 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    sync::{
+	atomic::{AtomicUsize, Ordering},
+	mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use image::metadata::Orientation;
+use nix::fcntl::{Flock, FlockArg};
+
+use crate::dedup::{Deduplicate, ImageHashes};
+use crate::image_file::{
+    decoding_enabled, format_name, image_from_probe, parse_format, probe_data, probe_file, FoundImage, ImageFile,
+    ProbeResult, ScanOptions,
+};
+use crate::xdg;
+
+const CACHE_HEADER: &str = "wl-bg-gallery metadata cache 3";
+// how often a long scan reports its progress
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+// What we know about a file, valid as long as its size and modification time do not change
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CacheEntry {
+    size: u64,
+    mtime: (i64, i64),
+    probe_result: ProbeResult,
+    hashes: ImageHashes,
+}
+
+// The results of probe_file, kept in $XDG_CACHE_HOME/wl-bg-gallery/metadata so that only new or
+// modified files have to be opened. The file has one line per path, with tab separated fields.
+// Several instances of the program can share it: writers take a lock, merge their changes with
+// what is on disk and atomically replace the file, so readers never see a partial file.
+pub struct MetadataCache {
+    // None if there is no cache directory we can use
+    cache_path: Option<PathBuf>,
+    entries: HashMap<PathBuf, CacheEntry>,
+    // the changes that have not been saved yet
+    updated: HashSet<PathBuf>,
+    removed: HashSet<PathBuf>,
+    // the files that the filters rejected, and why, so that each rejection is only logged once
+    rejected: HashMap<PathBuf, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ProbeStats {
+    pub cached: usize,
+    pub probed: usize,
+    pub rejected: usize,
+}
+
+// The result of probing one file: its cache entry, and whether it had to be opened to get it.
+// None if the file cannot be stat'ed or opened, then nothing is cached.
+fn lookup_or_probe(entries: &HashMap<PathBuf, CacheEntry>, path: &Path, deduplicate: Deduplicate) -> Option<(CacheEntry, bool)> {
+    let metadata = match fs::metadata(path) {
+	Ok(metadata) => metadata,
+	Err(error) => {
+	    println!("could not stat {} : {}", path.to_string_lossy(), error);
+	    return None;
+	}
+    };
+    let size = metadata.size();
+    let mtime = (metadata.mtime(), metadata.mtime_nsec());
+
+    let cached = match entries.get(path) {
+	Some(entry) if entry.size == size && entry.mtime == mtime => match entry.probe_result {
+	    // the decoder may have been compiled in since then
+	    ProbeResult::Unsupported { format } if decoding_enabled(format) => None,
+	    _ => Some(*entry),
+	},
+	_ => None,
+    };
+    let (mut entry, mut probed) = match cached {
+	Some(entry) => (entry, false),
+	None => (CacheEntry { size, mtime, probe_result: probe_file(path)?, hashes: ImageHashes::default() }, true),
+    };
+    if let ProbeResult::Image { .. } = entry.probe_result {
+	if !entry.hashes.is_complete(deduplicate) {
+	    entry.hashes.complete(path, deduplicate);
+	    probed = true;
+	}
+    }
+    Some((entry, probed))
+}
+
+// Applies the filters of the config to an image, rejected is where the rejections were logged
+fn filter(
+    rejected: &mut HashMap<PathBuf, String>,
+    image_file: ImageFile,
+    entry: &CacheEntry,
+    scan_options: &ScanOptions,
+) -> Option<ImageFile> {
+    let reason = match scan_options.filter.rejection(&image_file, entry.size, entry.mtime.0) {
+	Some(reason) => reason,
+	None => {
+	    rejected.remove(&image_file.path);
+	    return Some(image_file);
+	}
+    };
+    if rejected.get(&image_file.path) != Some(&reason) {
+	println!("skipping {}: {}", image_file.path.to_string_lossy(), reason);
+	rejected.insert(image_file.path, reason);
+    }
+    None
+}
+
+fn format_hash(hash: Option<u64>) -> String {
+    match hash {
+	Some(hash) => format!("{:016x}", hash),
+	None => String::from("-"),
+    }
+}
+
+fn parse_hash(hash: &str) -> Option<Option<u64>> {
+    match hash {
+	"-" => Some(None),
+	hash => u64::from_str_radix(hash, 16).ok().map(Some),
+    }
+}
+
+// Paths are stored as raw bytes, only the characters that would break the format are escaped
+pub fn escape_path(path: &Path) -> Vec<u8> {
+    let mut escaped = Vec::new();
+    for byte in path.as_os_str().as_bytes() {
+	match byte {
+	    b'%' | b'\t' | b'\n' | b'\r' => escaped.extend_from_slice(format!("%{:02x}", byte).as_bytes()),
+	    byte => escaped.push(*byte),
+	}
+    }
+    escaped
+}
+
+pub fn unescape_path(escaped: &[u8]) -> Option<PathBuf> {
+    let mut path = Vec::new();
+    let mut index = 0;
+    while index < escaped.len() {
+	if escaped[index] == b'%' {
+	    let hex = std::str::from_utf8(escaped.get(index + 1..index + 3)?).ok()?;
+	    path.push(u8::from_str_radix(hex, 16).ok()?);
+	    index += 3;
+	} else {
+	    path.push(escaped[index]);
+	    index += 1;
+	}
+    }
+    Some(PathBuf::from(OsStr::from_bytes(&path)))
+}
+
+fn format_entry(path: &Path, entry: &CacheEntry) -> Vec<u8> {
+    let mut line = escape_path(path);
+    let probe_result = match entry.probe_result {
+	ProbeResult::Image { format, width, height, orientation } => {
+	    format!("image\t{}\t{}\t{}\t{}", format_name(format), width, height, orientation.to_exif())
+	}
+	ProbeResult::Unsupported { format } => format!("unsupported\t{}", format_name(format)),
+	ProbeResult::Unreadable => String::from("unreadable"),
+    };
+    line.extend_from_slice(
+	format!(
+	    "\t{}\t{}\t{}\t{}\t{}\t{}\n",
+	    entry.size,
+	    entry.mtime.0,
+	    entry.mtime.1,
+	    format_hash(entry.hashes.content),
+	    format_hash(entry.hashes.perceptual),
+	    probe_result
+	)
+	.as_bytes(),
+    );
+    line
+}
+
+fn parse_entry(line: &[u8]) -> Option<(PathBuf, CacheEntry)> {
+    let fields: Vec<&[u8]> = line.split(|byte| *byte == b'\t').collect();
+    let path = unescape_path(fields.first()?)?;
+    let fields: Vec<&str> = fields[1..]
+	.iter()
+	.map(|field| std::str::from_utf8(field).ok())
+	.collect::<Option<Vec<&str>>>()?;
+    let probe_result = match fields.get(5..)? {
+	["image", name, width, height, orientation] => ProbeResult::Image {
+	    format: parse_format(name)?,
+	    width: width.parse().ok()?,
+	    height: height.parse().ok()?,
+	    orientation: Orientation::from_exif(orientation.parse().ok()?)?,
+	},
+	["unsupported", name] => ProbeResult::Unsupported { format: parse_format(name)? },
+	["unreadable"] => ProbeResult::Unreadable,
+	_ => return None,
+    };
+    let entry = CacheEntry {
+	size: fields[0].parse().ok()?,
+	mtime: (fields[1].parse().ok()?, fields[2].parse().ok()?),
+	probe_result,
+	hashes: ImageHashes { content: parse_hash(fields[3])?, perceptual: parse_hash(fields[4])? },
+    };
+    Some((path, entry))
+}
+
+fn read_entries(cache_path: &Path) -> HashMap<PathBuf, CacheEntry> {
+    let contents = match fs::read(cache_path) {
+	Ok(contents) => contents,
+	Err(_) => return HashMap::new(),
+    };
+    let mut lines = contents.split(|byte| *byte == b'\n');
+    if lines.next() != Some(CACHE_HEADER.as_bytes()) {
+	println!("ignoring {}, it was written by another version", cache_path.to_string_lossy());
+	return HashMap::new();
+    }
+    lines
+	.filter(|line| !line.is_empty())
+	.filter_map(parse_entry)
+	.collect()
+}
+
+impl MetadataCache {
+    pub fn load() -> MetadataCache {
+	let cache_path = xdg::cache_home().join("wl-bg-gallery").join("metadata");
+	let entries = read_entries(&cache_path);
+	println!("{} entries in the metadata cache", entries.len());
+	MetadataCache {
+	    cache_path: Some(cache_path),
+	    entries,
+	    updated: HashSet::new(),
+	    removed: HashSet::new(),
+	    rejected: HashMap::new(),
+	}
+    }
+
+    // Same as probe_image, but files whose size and modification time did not change since they
+    // were last probed are not opened
+    pub fn probe(&mut self, path: &Path, scan_options: &ScanOptions) -> Option<ImageFile> {
+	let (entry, probed) = lookup_or_probe(&self.entries, path, scan_options.deduplicate)?;
+	if probed {
+	    self.insert(path, entry);
+	}
+	let image_file = image_from_probe(path, entry.probe_result, scan_options.authorized_formats)?;
+	filter(&mut self.rejected, image_file, &entry, scan_options)
+    }
+
+    // Same as probe, for the files that are not on the file system, such as the entries of an
+    // archive. The entry is valid as long as size and mtime do not change, and read is only called
+    // when the file has to be probed.
+    pub fn probe_data(
+	&mut self,
+	path: &Path,
+	size: u64,
+	mtime: (i64, i64),
+	read: impl FnOnce() -> std::io::Result<Vec<u8>>,
+	scan_options: &ScanOptions,
+	stats: &mut ProbeStats,
+    ) -> Option<ImageFile> {
+	let cached = match self.entries.get(path) {
+	    Some(entry) if entry.size == size && entry.mtime == mtime => match entry.probe_result {
+		ProbeResult::Unsupported { format } if decoding_enabled(format) => None,
+		ProbeResult::Image { .. } if !entry.hashes.is_complete(scan_options.deduplicate) => None,
+		_ => Some(*entry),
+	    },
+	    _ => None,
+	};
+	let entry = match cached {
+	    Some(entry) => {
+		stats.cached += 1;
+		entry
+	    }
+	    None => {
+		let data = match read() {
+		    Ok(data) => data,
+		    Err(error) => {
+			println!("could not read {} : {}", path.to_string_lossy(), error);
+			return None;
+		    }
+		};
+		let probe_result = probe_data(path, &data);
+		let mut hashes = ImageHashes::default();
+		if let ProbeResult::Image { .. } = probe_result {
+		    hashes.complete_from_data(path, &data, scan_options.deduplicate);
+		}
+		let entry = CacheEntry { size, mtime, probe_result, hashes };
+		self.insert(path, entry);
+		stats.probed += 1;
+		entry
+	    }
+	};
+	let image_file = image_from_probe(path, entry.probe_result, scan_options.authorized_formats)?;
+	let image_file = filter(&mut self.rejected, image_file, &entry, scan_options);
+	if image_file.is_none() {
+	    stats.rejected += 1;
+	}
+	image_file
+    }
+
+    // The hashes computed when the file was last probed
+    pub fn hashes(&self, path: &Path) -> ImageHashes {
+	self.entries.get(path).map_or(ImageHashes::default(), |entry| entry.hashes)
+    }
+
+    fn insert(&mut self, path: &Path, entry: CacheEntry) {
+	self.entries.insert(path.to_path_buf(), entry);
+	self.updated.insert(path.to_path_buf());
+	self.removed.remove(path);
+    }
+
+    // Probes paths on a pool of scan_threads workers, the images are returned in the same order
+    pub fn probe_all(
+	&mut self,
+	paths: &[PathBuf],
+	scan_options: &ScanOptions,
+	found: FoundImage,
+    ) -> (Vec<ImageFile>, ProbeStats) {
+	let threads = match scan_options.scan_threads {
+	    0 => thread::available_parallelism().map_or(1, |threads| threads.get()),
+	    threads => threads as usize,
+	};
+	let threads = threads.min(paths.len()).max(1);
+
+	let mut images: Vec<Option<ImageFile>> = vec![None; paths.len()];
+	let mut probed_entries = Vec::new();
+	let mut stats = ProbeStats::default();
+	let next_index = AtomicUsize::new(0);
+	// the workers read the entries while the images they return are filtered
+	let entries = &self.entries;
+	let rejected = &mut self.rejected;
+	thread::scope(|scope| {
+	    let (sender, receiver) = mpsc::channel();
+	    for _ in 0..threads {
+		let sender = sender.clone();
+		let next_index = &next_index;
+		scope.spawn(move || loop {
+		    let index = next_index.fetch_add(1, Ordering::Relaxed);
+		    if index >= paths.len() {
+			break;
+		    }
+		    if sender.send((index, lookup_or_probe(entries, &paths[index], scan_options.deduplicate))).is_err() {
+			break;
+		    }
+		});
+	    }
+	    // the workers hold the remaining senders, the loop ends when they are all done
+	    drop(sender);
+
+	    let start = Instant::now();
+	    let mut last_report = start;
+	    let mut done = 0;
+	    loop {
+		match receiver.recv_timeout(PROGRESS_INTERVAL) {
+		    Ok((index, Some((entry, probed)))) => {
+			done += 1;
+			if probed {
+			    stats.probed += 1;
+			    probed_entries.push((index, entry));
+			} else {
+			    stats.cached += 1;
+			}
+			let image_file = match image_from_probe(&paths[index], entry.probe_result, scan_options.authorized_formats) {
+			    Some(image_file) => image_file,
+			    None => continue,
+			};
+			match filter(rejected, image_file, &entry, scan_options) {
+			    Some(mut image_file) => {
+				found(&mut image_file);
+				images[index] = Some(image_file);
+			    }
+			    None => stats.rejected += 1,
+			}
+		    }
+		    Ok((_, None)) => done += 1,
+		    Err(mpsc::RecvTimeoutError::Timeout) => (),
+		    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+		}
+		if last_report.elapsed() >= PROGRESS_INTERVAL {
+		    println!("probed {} files out of {} in {:.0?}", done, paths.len(), start.elapsed());
+		    last_report = Instant::now();
+		}
+	    }
+	});
+
+	for (index, entry) in probed_entries {
+	    self.insert(&paths[index], entry);
+	}
+	(images.into_iter().flatten().collect(), stats)
+    }
+
+    // A cache that starts empty and is never saved
+    #[cfg(test)]
+    pub fn in_memory() -> MetadataCache {
+	MetadataCache {
+	    cache_path: None,
+	    entries: HashMap::new(),
+	    updated: HashSet::new(),
+	    removed: HashSet::new(),
+	    rejected: HashMap::new(),
+	}
+    }
+
+    // A copy of the entries, for a scan that runs on another thread. What it probes is brought
+    // back with merge.
+    pub fn snapshot(&self) -> MetadataCache {
+	MetadataCache {
+	    cache_path: None,
+	    entries: self.entries.clone(),
+	    updated: HashSet::new(),
+	    removed: HashSet::new(),
+	    rejected: self.rejected.clone(),
+	}
+    }
+
+    pub fn merge(&mut self, snapshot: MetadataCache) {
+	for path in snapshot.removed.iter() {
+	    self.forget(path);
+	}
+	for path in snapshot.updated.iter() {
+	    if let Some(entry) = snapshot.entries.get(path) {
+		self.insert(path, *entry);
+	    }
+	}
+	self.rejected.extend(snapshot.rejected);
+    }
+
+    // A renamed file keeps its entry
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+	if let Some(entry) = self.entries.remove(from) {
+	    self.entries.insert(to.to_path_buf(), entry);
+	    self.updated.remove(from);
+	    self.removed.insert(from.to_path_buf());
+	    self.updated.insert(to.to_path_buf());
+	    self.removed.remove(to);
+	}
+    }
+
+    // Forgets path and, if it is a directory, everything below it
+    pub fn remove(&mut self, path: &Path) {
+	let removed: Vec<PathBuf> = self
+	    .entries
+	    .keys()
+	    .filter(|entry_path| entry_path.starts_with(path))
+	    .cloned()
+	    .collect();
+	for removed_path in removed {
+	    self.forget(&removed_path);
+	}
+    }
+
+    // Forgets the files below dir that a complete scan of it did not list: those that were deleted,
+    // and those of the directories that were. dirs are the directories the scan read, listed every
+    // file it found in them.
+    pub fn prune(&mut self, dir: &Path, dirs: &[PathBuf], listed: &HashSet<PathBuf>) {
+	let dirs: HashSet<&Path> = dirs.iter().map(|dir| dir.as_path()).collect();
+	// the directories the scan did not read may have been skipped, they only go if they are gone
+	let mut gone_dirs: HashMap<PathBuf, bool> = HashMap::new();
+	let stale: Vec<PathBuf> = self
+	    .entries
+	    .keys()
+	    .filter(|path| path.starts_with(dir) && !listed.contains(*path))
+	    .filter(|path| match path.parent() {
+		Some(parent) if dirs.contains(parent) => true,
+		Some(parent) => *gone_dirs.entry(parent.to_path_buf()).or_insert_with(|| !parent.is_dir()),
+		None => false,
+	    })
+	    .cloned()
+	    .collect();
+	for stale_path in stale {
+	    self.forget(&stale_path);
+	}
+    }
+
+    fn forget(&mut self, path: &Path) {
+	self.entries.remove(path);
+	self.updated.remove(path);
+	self.removed.insert(path.to_path_buf());
+    }
+
+    // Merges our changes into the cache file. Errors are not fatal, the cache is disabled instead.
+    pub fn save(&mut self) {
+	if self.updated.is_empty() && self.removed.is_empty() {
+	    return;
+	}
+	let cache_path = match self.cache_path {
+	    Some(ref cache_path) => cache_path.clone(),
+	    None => return,
+	};
+	if let Err(error) = self.write(&cache_path) {
+	    println!("disabling the metadata cache, failed to write {}: {}", cache_path.to_string_lossy(), error);
+	    self.cache_path = None;
+	}
+	self.updated.clear();
+	self.removed.clear();
+    }
+
+    fn write(&mut self, cache_path: &Path) -> std::io::Result<()> {
+	if let Some(cache_dir) = cache_path.parent() {
+	    fs::create_dir_all(cache_dir)?;
+	}
+
+	// held until the new file is in place, so that writers do not lose each other's changes
+	let lock_file = OpenOptions::new()
+	    .create(true)
+	    .truncate(false)
+	    .write(true)
+	    .open(cache_path.with_extension("lock"))?;
+	let _lock = match Flock::lock(lock_file, FlockArg::LockExclusive) {
+	    Ok(lock) => lock,
+	    Err((_, errno)) => return Err(std::io::Error::from(errno)),
+	};
+
+	let mut entries = read_entries(cache_path);
+	for removed_path in self.removed.iter() {
+	    entries.remove(removed_path);
+	}
+	for updated_path in self.updated.iter() {
+	    if let Some(entry) = self.entries.get(updated_path) {
+		entries.insert(updated_path.clone(), *entry);
+	    }
+	}
+
+	let tmp_path = cache_path.with_extension(format!("{}.tmp", std::process::id()));
+	let mut tmp_file = File::create(&tmp_path)?;
+	let mut contents = Vec::new();
+	contents.extend_from_slice(CACHE_HEADER.as_bytes());
+	contents.push(b'\n');
+	for (path, entry) in entries.iter() {
+	    contents.extend_from_slice(&format_entry(path, entry));
+	}
+	let written = tmp_file.write_all(&contents).and_then(|_| tmp_file.sync_all());
+	if let Err(error) = written.and_then(|_| fs::rename(&tmp_path, cache_path)) {
+	    let _ = fs::remove_file(&tmp_path);
+	    return Err(error);
+	}
+
+	// what the other instances found is useful to us too
+	self.entries = entries;
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ImageFormat;
+
+    fn test_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("wl-bg-gallery-{}-{}", name, std::process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	dir
+    }
+
+    fn empty_cache(cache_path: Option<PathBuf>) -> MetadataCache {
+	MetadataCache {
+	    cache_path,
+	    entries: HashMap::new(),
+	    updated: HashSet::new(),
+	    removed: HashSet::new(),
+	    rejected: HashMap::new(),
+	}
+    }
+
+    fn image_entry(size: u64) -> CacheEntry {
+	CacheEntry {
+	    size,
+	    mtime: (1700000000, 123456789),
+	    probe_result: ProbeResult::Image {
+		format: ImageFormat::Png,
+		width: 1920,
+		height: 1080,
+		orientation: Orientation::Rotate90,
+	    },
+	    hashes: ImageHashes { content: Some(0xdeadbeef), perceptual: None },
+	}
+    }
+
+    #[test]
+    fn escaped_paths() {
+	let paths = [
+	    PathBuf::from("/pictures/plain.png"),
+	    PathBuf::from("/pictures/tab\tand\nnewline\r.png"),
+	    PathBuf::from("/pictures/100% sure.png"),
+	    PathBuf::from(OsStr::from_bytes(b"/pictures/\xff\xfe latin-1 \xe9.png")),
+	];
+	for path in paths.iter() {
+	    let escaped = escape_path(path);
+	    assert!(!escaped.iter().any(|byte| [b'\t', b'\n', b'\r'].contains(byte)), "{:?}", path);
+	    assert_eq!(unescape_path(&escaped).as_ref(), Some(path));
+	}
+	assert_eq!(escape_path(&paths[2]), b"/pictures/100%25 sure.png");
+	// truncated or invalid escapes
+	assert_eq!(unescape_path(b"/pictures/%2"), None);
+	assert_eq!(unescape_path(b"/pictures/%zz.png"), None);
+    }
+
+    #[test]
+    fn entries_parse_back() {
+	let unsupported = CacheEntry {
+	    probe_result: ProbeResult::Unsupported { format: ImageFormat::Avif },
+	    hashes: ImageHashes::default(),
+	    ..image_entry(10)
+	};
+	let unreadable = CacheEntry { probe_result: ProbeResult::Unreadable, ..unsupported };
+	let path = PathBuf::from("/pictures/new\nline.png");
+	for entry in [image_entry(2048), unsupported, unreadable] {
+	    let line = format_entry(&path, &entry);
+	    assert_eq!(line.last(), Some(&b'\n'));
+	    assert_eq!(parse_entry(&line[..line.len() - 1]), Some((path.clone(), entry)));
+	}
+    }
+
+    #[test]
+    fn invalid_entries() {
+	let lines: [&[u8]; 9] = [
+	    b"",
+	    b"/pictures/a.png",
+	    b"/pictures/a.png\t2048\t1\t2\t-\t-",
+	    b"/pictures/a.png\tsize\t1\t2\t-\t-\tunreadable",
+	    b"/pictures/a.png\t2048\t1\t2\tnot-hex\t-\tunreadable",
+	    b"/pictures/a.png\t2048\t1\t2\t-\t-\timage\tpng\t1920\t1080",
+	    b"/pictures/a.png\t2048\t1\t2\t-\t-\timage\tnot-a-format\t1920\t1080\t1",
+	    b"/pictures/a.png\t2048\t1\t2\t-\t-\timage\tpng\t1920\t1080\t9",
+	    b"/pictures/a.png\t20\xff48\t1\t2\t-\t-\tunreadable",
+	];
+	for line in lines {
+	    assert_eq!(parse_entry(line), None, "{}", String::from_utf8_lossy(line));
+	}
+	assert!(parse_entry(b"/pictures/a.png\t2048\t1\t2\t-\t-\tunreadable").is_some());
+    }
+
+    #[test]
+    fn prune_forgets_deleted_files() {
+	let dir = test_dir("prune");
+	let skipped = dir.join("skipped");
+	fs::create_dir_all(&skipped).unwrap();
+	let mut cache = empty_cache(None);
+	let kept = [dir.join("a.png"), skipped.join("d.png"), PathBuf::from("/elsewhere/e.png")];
+	let deleted = [dir.join("b.png"), dir.join("sub").join("c.png"), dir.join("gone").join("f.png")];
+	for path in kept.iter().chain(deleted.iter()) {
+	    cache.insert(path, image_entry(1));
+	}
+	let listed: HashSet<PathBuf> = [dir.join("a.png")].into_iter().collect();
+	// skipped was not read, gone no longer exists
+	cache.prune(&dir, &[dir.clone(), dir.join("sub")], &listed);
+	for path in kept.iter() {
+	    assert!(cache.entries.contains_key(path), "{:?}", path);
+	}
+	for path in deleted.iter() {
+	    assert!(!cache.entries.contains_key(path), "{:?}", path);
+	    assert!(cache.removed.contains(path));
+	}
+	let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn writers_merge_their_changes() {
+	let dir = test_dir("metadata-cache");
+	let cache_path = dir.join("metadata");
+	let mut first = empty_cache(Some(cache_path.clone()));
+	let mut second = empty_cache(Some(cache_path.clone()));
+	first.insert(Path::new("/pictures/a.png"), image_entry(1));
+	first.insert(Path::new("/pictures/b.png"), image_entry(2));
+	first.save();
+	second.insert(Path::new("/pictures/c\tab.png"), image_entry(3));
+	second.save();
+	// the second writer picked up what the first one wrote
+	assert_eq!(second.entries.len(), 3);
+
+	first.remove(Path::new("/pictures/b.png"));
+	first.save();
+	let entries = read_entries(&cache_path);
+	assert_eq!(entries.len(), 2);
+	assert_eq!(entries.get(Path::new("/pictures/a.png")), Some(&image_entry(1)));
+	assert_eq!(entries.get(Path::new("/pictures/c\tab.png")), Some(&image_entry(3)));
+	assert!(first.updated.is_empty() && first.removed.is_empty());
+	let _ = fs::remove_dir_all(&dir);
+    }
+}
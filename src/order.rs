@@ -0,0 +1,183 @@
+use std::path::Path;
+
+use rand::{
+    distr::{weighted::WeightedIndex, Distribution},
+    rngs::StdRng,
+    seq::SliceRandom,
+    Rng, SeedableRng,
+};
+use serde::Deserialize;
+
+use crate::image_file::ImageFile;
+
+// the weight of the images whose sidecar does not set one
+pub const DEFAULT_IMAGE_WEIGHT: u32 = 1;
+
+// The order in which the rotation goes through the images of a source
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    // by path, playlists in the order in which they list their images
+    Sequential,
+    // every image once per cycle, in a new order every cycle
+    Shuffle,
+    // an image drawn at random every time, in proportion to its weight
+    Random,
+}
+
+// Where a shuffled rotation is. The order of a cycle only depends on the seed, the cycle and the
+// images, so that it can be found again after a restart without saving the whole permutation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShuffleState {
+    pub seed: u64,
+    pub cycle: u64,
+}
+
+impl ShuffleState {
+    // A random seed if the config does not set one
+    pub fn new(seed: Option<u64>) -> Self {
+	ShuffleState {
+	    seed: seed.unwrap_or_else(|| rand::rng().random()),
+	    cycle: 0,
+	}
+    }
+}
+
+pub fn sort(image_list: &mut [ImageFile]) {
+    image_list.sort_by(|image_file, other| image_file.path.cmp(&other.path));
+}
+
+// The generator of a cycle. The seed and the cycle both go into its key, so that the orders of
+// consecutive cycles, or of consecutive seeds, are unrelated.
+fn cycle_rng(shuffle_state: ShuffleState) -> StdRng {
+    let mut key = [0; 32];
+    key[..8].copy_from_slice(&shuffle_state.seed.to_le_bytes());
+    key[8..16].copy_from_slice(&shuffle_state.cycle.to_le_bytes());
+    StdRng::from_seed(key)
+}
+
+// Puts the images in the order of the cycle. previous is the image the previous cycle ended with,
+// it does not come first so that no image is shown twice in a row.
+pub fn shuffle(image_list: &mut [ImageFile], shuffle_state: ShuffleState, previous: Option<&Path>) {
+    // the order the images were found in does not matter
+    sort(image_list);
+    image_list.shuffle(&mut cycle_rng(shuffle_state));
+    if image_list.len() > 1 && previous == Some(image_list[0].path.as_path()) {
+	let last = image_list.len() - 1;
+	image_list.swap(0, last);
+    }
+}
+
+// Draws one of the eligible images, in proportion to their weights. None if no eligible image has
+// a weight.
+pub fn pick_weighted<F>(image_list: &[ImageFile], rng: &mut StdRng, eligible: F) -> Option<usize>
+where
+    F: Fn(usize, &ImageFile) -> bool,
+{
+    let weights = image_list
+	.iter()
+	.enumerate()
+	.map(|(index, image_file)| if eligible(index, image_file) { image_file.weight() } else { 0 });
+    match WeightedIndex::new(weights) {
+	Ok(distribution) => Some(distribution.sample(rng)),
+	Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use image::{metadata::Orientation, ImageFormat};
+
+    use crate::sidecar::ImageOverrides;
+
+    fn images(count: usize) -> Vec<ImageFile> {
+	(0..count)
+	    .map(|index| {
+		let path = PathBuf::from(format!("/pictures/{:02}.png", index));
+		ImageFile::new(1920, 1080, ImageFormat::Png, Orientation::NoTransforms, path)
+	    })
+	    .collect()
+    }
+
+    fn paths(image_list: &[ImageFile]) -> Vec<PathBuf> {
+	image_list.iter().map(|image_file| image_file.path.clone()).collect()
+    }
+
+    fn shuffled(seed: u64, cycle: u64) -> Vec<PathBuf> {
+	let mut image_list = images(20);
+	shuffle(&mut image_list, ShuffleState { seed, cycle }, None);
+	paths(&image_list)
+    }
+
+    #[test]
+    fn fixed_seed_gives_the_same_order() {
+	assert_eq!(shuffled(7, 3), shuffled(7, 3));
+	// whatever the order the images were found in
+	let mut image_list = images(20);
+	image_list.reverse();
+	shuffle(&mut image_list, ShuffleState { seed: 7, cycle: 3 }, None);
+	assert_eq!(paths(&image_list), shuffled(7, 3));
+	assert_ne!(shuffled(7, 3), paths(&images(20)));
+    }
+
+    #[test]
+    fn cycles_and_seeds_give_different_orders() {
+	assert_ne!(shuffled(7, 3), shuffled(7, 4));
+	assert_ne!(shuffled(7, 3), shuffled(8, 3));
+	// the next cycle of a seed is not the current cycle of the next seed
+	assert_ne!(shuffled(7, 4), shuffled(8, 3));
+    }
+
+    #[test]
+    fn no_repeat_across_cycles() {
+	for seed in 0..20 {
+	    let mut image_list = images(3);
+	    shuffle(&mut image_list, ShuffleState { seed, cycle: 0 }, None);
+	    for cycle in 1..50 {
+		let previous = image_list.last().unwrap().path.clone();
+		shuffle(&mut image_list, ShuffleState { seed, cycle }, Some(&previous));
+		assert_ne!(image_list[0].path, previous, "seed {} cycle {}", seed, cycle);
+	    }
+	}
+	// a single image has to come again
+	let mut image_list = images(1);
+	let previous = image_list[0].path.clone();
+	shuffle(&mut image_list, ShuffleState { seed: 0, cycle: 1 }, Some(&previous));
+	assert_eq!(image_list[0].path, previous);
+    }
+
+    #[test]
+    fn weighted_picks() {
+	let mut image_list = images(3);
+	for (image_file, weight) in image_list.iter_mut().zip([1, 3, 0]) {
+	    image_file.overrides = Some(ImageOverrides {
+		fit: None,
+		background_color: None,
+		focal_point: None,
+		bg_duration_seconds: None,
+		weight: Some(weight),
+		outputs: Vec::new(),
+	    });
+	}
+	let mut rng = StdRng::seed_from_u64(0);
+	let mut counts = [0; 3];
+	for _ in 0..4000 {
+	    counts[pick_weighted(&image_list, &mut rng, |_, _| true).unwrap()] += 1;
+	}
+	assert_eq!(counts[2], 0);
+	assert!((800..1200).contains(&counts[0]), "{:?}", counts);
+	assert!((2800..3200).contains(&counts[1]), "{:?}", counts);
+
+	// the images that are not eligible are never drawn
+	for _ in 0..100 {
+	    assert_eq!(pick_weighted(&image_list, &mut rng, |index, _| index != 1), Some(0));
+	}
+	assert_eq!(pick_weighted(&image_list, &mut rng, |index, _| index == 2), None);
+	// without sidecars, every image has the default weight
+	let image_list = images(2);
+	let picked: Vec<usize> = (0..100).filter_map(|_| pick_weighted(&image_list, &mut rng, |_, _| true)).collect();
+	assert!(picked.contains(&0) && picked.contains(&1));
+    }
+}
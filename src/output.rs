@@ -1,15 +1,27 @@
 use crate::{
-    background_image::{fill_buffer_random, fill_buffer_with_image, BackgroundImageError},
+    background_image::{fill_buffer_with_color, fill_buffer_with_image, BackgroundImageError},
+    config::{FitMode, OutputSettings},
+    gallery::SourcePicker,
+    image_file::ImageFile,
     memory::MemoryMapping,
     wl_app::WlApp,
 };
-use std::{num::NonZeroUsize, os::fd::AsFd, path::PathBuf, time::Instant};
+use std::{
+    num::NonZeroUsize,
+    os::fd::AsFd,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
 use wayland_client::{
     protocol::{wl_buffer, wl_output, wl_shm, wl_shm_pool, wl_surface},
     QueueHandle,
 };
 use wayland_protocols_wlr::layer_shell::v1::client::zwlr_layer_surface_v1;
 
+// Instants stop during suspend: the main loop wakes up at least this often while an output changes
+// at wall-clock times, to catch up with them soon after a resume
+pub const WALL_CLOCK_RECHECK: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub struct Output {
     pub make: String,
@@ -27,8 +39,16 @@ pub struct Output {
     pub serial_to_ack: u32,
     pub should_update_config: bool,
     pub next_redraw: Option<Instant>,
+    // with change_at, the wall-clock time of the next change, next_redraw follows it
+    pub next_change_at: Option<SystemTime>,
     pub image_list: Vec<usize>,
     pub index_in_image_list: usize,
+    pub settings: Option<OutputSettings>,
+    pub source_picker: SourcePicker,
+    // nothing could be shown at the last redraw, it is done again as soon as new images are found
+    pub waiting_for_images: bool,
+    // the image on screen, if any
+    pub shown_image: Option<PathBuf>,
 }
 
 impl Output {
@@ -49,8 +69,39 @@ impl Output {
             serial_to_ack: 0,
             should_update_config: true,
             next_redraw: None,
+            next_change_at: None,
             image_list: Vec::new(),
             index_in_image_list: 0,
+            settings: None,
+            source_picker: SourcePicker::new(),
+            waiting_for_images: false,
+            shown_image: None,
+        }
+    }
+
+    // Schedules the next change of image. The duration of the image, set by its sidecar, wins over
+    // the settings of the output.
+    pub fn schedule_next_change(&mut self, image_duration: Option<Duration>) {
+        let settings = match self.settings {
+            Some(ref settings) => settings,
+            None => return,
+        };
+        let until_next_change = image_duration.unwrap_or_else(|| settings.until_next_change());
+        self.next_change_at = match (image_duration, &settings.change_at) {
+            (None, Some(_)) => Some(SystemTime::now() + until_next_change),
+            _ => None,
+        };
+        self.next_redraw = Some(Instant::now() + until_next_change);
+    }
+
+    // Moves the next redraw back to the wall-clock time of the next change, if any, in case the
+    // system was suspended since it was scheduled
+    pub fn catch_up_with_wall_clock(&mut self, now: Instant) {
+        if let (Some(_), Some(next_change_at)) = (self.next_redraw, self.next_change_at) {
+            let until_next_change = next_change_at
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO);
+            self.next_redraw = Some(now + until_next_change);
         }
     }
 
@@ -115,14 +166,27 @@ impl Output {
         ));
     }
 
-    pub fn fill_buffer_dispatch(&self, to_draw: &Option<&PathBuf>, ptr: &mut [u8]) -> Result<(), BackgroundImageError> {
+    pub fn fill_buffer_dispatch(&self, to_draw: &Option<&ImageFile>, ptr: &mut [u8]) -> Result<(), BackgroundImageError> {
+        let (mut fit, mut background_color) = match self.settings {
+            Some(ref settings) => (settings.fit, settings.background_color),
+            None => (FitMode::Fit, [0, 0, 0, 0]),
+        };
         match to_draw {
-            Some(path) => {
-                return fill_buffer_with_image(&path, self.mode_width, self.mode_height, ptr);
-            }
-            None => {
-                return fill_buffer_random(ptr);
+            Some(image_file) => {
+                if let Some(ref overrides) = image_file.overrides {
+                    fit = overrides.fit.unwrap_or(fit);
+                    background_color = overrides.background_color.unwrap_or(background_color);
+                }
+                fill_buffer_with_image(
+                    image_file,
+                    self.mode_width,
+                    self.mode_height,
+                    fit,
+                    background_color,
+                    ptr,
+                )
             }
+            None => fill_buffer_with_color(background_color, ptr),
         }
     }
 
@@ -130,7 +194,7 @@ impl Output {
         &mut self,
         key: &u32,
         qhandle: &QueueHandle<WlApp>,
-        to_draw: Option<&PathBuf>,
+        to_draw: Option<&ImageFile>,
     ) -> Result<(), BackgroundImageError> {
         self.create_buffer(key, qhandle);
         let ptr: &mut [u8];
@@ -154,6 +218,28 @@ impl Output {
 	return Ok(());
     }
 
+    // Shown while there is no picture to show: the fallback image if it can be shown, the
+    // background color otherwise
+    pub fn render_fallback(
+        &mut self,
+        key: &u32,
+        qhandle: &QueueHandle<WlApp>,
+        fallback_image: Option<&ImageFile>,
+    ) {
+        if let Some(image_file) = fallback_image {
+            match self.render(key, qhandle, Some(image_file)) {
+                Ok(()) => return,
+                Err(error) => println!(
+                    "cannot render fallback image {:#?} on screen {}: {}!",
+                    image_file.path, self.name, error
+                ),
+            }
+        }
+        if let Err(error) = self.render(key, qhandle, None) {
+            println!("cannot fill screen {}: {}!", self.name, error);
+        }
+    }
+
     pub fn clear(&mut self) {
         if let Some(ref layer_shell) = self.wlr_layer_surface_proxy {
             layer_shell.destroy();
@@ -179,5 +265,7 @@ impl Output {
         }
         self.should_update_config = true;
         self.next_redraw = None;
+        // the new surface has to show the fallback too
+        self.waiting_for_images = false;
     }
 }
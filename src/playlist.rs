@@ -0,0 +1,189 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::image_file::{has_authorized_extension, FoundImage, ScanOptions, ScanResult};
+use crate::metadata_cache::MetadataCache;
+use crate::sidecar::ImageOverrides;
+
+const PLAYLIST_EXTENSIONS: [&str; 3] = ["m3u", "m3u8", "txt"];
+
+// A source that is a list of pictures rather than a directory
+pub fn is_playlist(path: &Path) -> bool {
+    path.extension()
+	.and_then(|extension| extension.to_str())
+	.is_some_and(|extension| PLAYLIST_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+	&& !path.is_dir()
+}
+
+// One path per line, absolute or relative to the directory of the playlist. Empty lines and lines
+// starting with # (comments, and the #EXTM3U / #EXTINF lines of m3u files) are skipped.
+fn read_playlist(playlist: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let contents = fs::read_to_string(playlist)?;
+    let base_dir = playlist.parent().unwrap_or(Path::new(""));
+    Ok(contents
+	.lines()
+	.map(|line| line.trim_start_matches('\u{feff}').trim())
+	.filter(|line| !line.is_empty() && !line.starts_with('#'))
+	.map(|line| base_dir.join(line))
+	.collect())
+}
+
+// The images of the playlist, in the order in which they are listed
+pub fn scan_playlist(
+    playlist: &Path,
+    scan_options: &ScanOptions,
+    metadata_cache: &mut MetadataCache,
+    found: FoundImage,
+) -> Result<ScanResult, std::io::Error> {
+    let start = Instant::now();
+    let entries = read_playlist(playlist)?;
+    let entry_count = entries.len();
+    let paths: Vec<PathBuf> = entries
+	.into_iter()
+	.filter(|path| has_authorized_extension(path, scan_options))
+	.collect();
+
+    let (image_list, probe_stats) = metadata_cache.probe_all(&paths, scan_options, &mut |image_file| {
+	image_file.overrides = ImageOverrides::load(&image_file.path);
+	found(image_file);
+    });
+    println!(
+	"read playlist {} in {:.2?}: {} entries, {} skipped by extension, {} from the cache, {} probed, {} filtered out, {} images",
+	playlist.to_string_lossy(),
+	start.elapsed(),
+	entry_count,
+	entry_count - paths.len(),
+	probe_stats.cached,
+	probe_stats.probed,
+	probe_stats.rejected,
+	image_list.len()
+    );
+    Ok(ScanResult {
+	image_list,
+	// the directory of the playlist is watched, to read it again when it changes
+	dirs: playlist.parent().map(Path::to_path_buf).into_iter().collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dedup::Deduplicate;
+    use crate::filter::ImageFilter;
+    use image::{ImageFormat, RgbImage};
+
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+	fn new(name: &str) -> TestDir {
+	    let dir = std::env::temp_dir().join(format!("wl-bg-gallery-{}-{}", name, std::process::id()));
+	    let _ = fs::remove_dir_all(&dir);
+	    fs::create_dir_all(&dir).unwrap();
+	    TestDir(dir)
+	}
+
+	fn write(&self, name: &str, contents: &str) -> PathBuf {
+	    let path = self.0.join(name);
+	    fs::create_dir_all(path.parent().unwrap()).unwrap();
+	    fs::write(&path, contents).unwrap();
+	    path
+	}
+
+	fn image(&self, name: &str) -> PathBuf {
+	    let path = self.0.join(name);
+	    fs::create_dir_all(path.parent().unwrap()).unwrap();
+	    RgbImage::new(4, 3).save(&path).unwrap();
+	    path
+	}
+    }
+
+    impl Drop for TestDir {
+	fn drop(&mut self) {
+	    let _ = fs::remove_dir_all(&self.0);
+	}
+    }
+
+    #[test]
+    fn playlist_extensions() {
+	let dir = TestDir::new("playlist-extensions");
+	assert!(is_playlist(Path::new("/pictures/favorites.m3u")));
+	assert!(is_playlist(Path::new("/pictures/favorites.M3U8")));
+	assert!(is_playlist(Path::new("/pictures/favorites.txt")));
+	assert!(!is_playlist(Path::new("/pictures/favorites.png")));
+	assert!(!is_playlist(Path::new("/pictures/favorites")));
+	// a directory is scanned even if its name looks like a playlist
+	fs::create_dir(dir.0.join("holidays.txt")).unwrap();
+	assert!(!is_playlist(&dir.0.join("holidays.txt")));
+    }
+
+    #[test]
+    fn entries_are_relative_to_the_playlist() {
+	let dir = TestDir::new("playlist-entries");
+	let playlist = dir.write(
+	    "lists/favorites.m3u",
+	    "\u{feff}#EXTM3U\n\
+	     #EXTINF:-1,beach\n\
+	     beach.png\n\
+	     \n\
+	     \t../mountains/lake.png  \r\n\
+	     # a comment\n\
+	     /srv/pictures/forest.jpg\n\
+	     \x20\x20\n\
+	     sub dir/city.png",
+	);
+	let lists = dir.0.join("lists");
+	assert_eq!(
+	    read_playlist(&playlist).unwrap(),
+	    [
+		lists.join("beach.png"),
+		lists.join("../mountains/lake.png"),
+		PathBuf::from("/srv/pictures/forest.jpg"),
+		lists.join("sub dir/city.png"),
+	    ]
+	);
+	assert!(read_playlist(&dir.0.join("missing.m3u")).is_err());
+    }
+
+    #[test]
+    fn missing_entries_are_skipped() {
+	let dir = TestDir::new("playlist-scan");
+	let second = dir.image("second.png");
+	let first = dir.image("pictures/first.png");
+	dir.write("notes.png", "not a picture");
+	let playlist = dir.write(
+	    "favorites.m3u",
+	    "second.png\nmissing.png\nnotes.png\nfavorites.m3u\npictures/first.png\npictures\n",
+	);
+
+	let authorized_formats = vec![ImageFormat::Png];
+	let no_patterns = Vec::new();
+	let filter = ImageFilter::default();
+	let scan_options = ScanOptions {
+	    authorized_formats: &authorized_formats,
+	    recursive: false,
+	    max_depth: 0,
+	    include: &no_patterns,
+	    exclude: &no_patterns,
+	    extension_prefilter: true,
+	    scan_threads: 2,
+	    deduplicate: Deduplicate::Off,
+	    similarity_threshold: 0,
+	    filter: &filter,
+	};
+	let mut found = Vec::new();
+	let result = scan_playlist(
+	    &playlist,
+	    &scan_options,
+	    &mut MetadataCache::in_memory(),
+	    &mut |image_file| found.push(image_file.path.clone()),
+	)
+	.unwrap();
+	// in the order of the playlist, whatever the order in which they were probed
+	let paths: Vec<&Path> = result.image_list.iter().map(|image_file| image_file.path.as_path()).collect();
+	assert_eq!(paths, [second.as_path(), first.as_path()]);
+	found.sort();
+	assert_eq!(found, [first, second]);
+	assert_eq!(result.dirs, std::slice::from_ref(&dir.0));
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    os::fd::{AsFd, BorrowedFd},
+    path::{Path, PathBuf},
+};
+
+use crate::glob::glob_match;
+
+use nix::sys::{
+    inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor},
+    signal::{SigSet, Signal},
+    signalfd::{SfdFlags, SignalFd},
+};
+
+// SIGUSR2 asks for a reload, SIGTERM and SIGINT for a clean stop
+fn handled_signals() -> SigSet {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGUSR2);
+    mask.add(Signal::SIGTERM);
+    mask.add(Signal::SIGINT);
+    mask
+}
+
+// The signals have to be blocked for them to be delivered through the signalfd. This is done
+// before any thread is started, since threads inherit the mask of the thread that starts them: a
+// thread that does not block them would be killed by the signals.
+pub fn block_handled_signals() {
+    if let Err(error) = handled_signals().thread_block() {
+	panic!("failed to block SIGUSR2, SIGTERM and SIGINT: {}", error);
+    }
+}
+
+// What the signals read from the signalfd ask for
+#[derive(Debug, Default)]
+pub struct SignalRequests {
+    pub reload: bool,
+    pub stop: bool,
+}
+
+// The nearest directory on the way to dir that exists, along with the name of its entry that
+// leads to dir, or file_name if dir exists
+fn nearest_existing_dir(dir: &Path, file_name: String) -> (&Path, String) {
+    let mut dir = dir;
+    let mut file_name = file_name;
+    while !dir.is_dir() {
+	match (dir.parent(), dir.file_name()) {
+	    (Some(parent), Some(name)) => {
+		file_name = name.to_string_lossy().into_owned();
+		dir = parent;
+	    }
+	    _ => break,
+	}
+    }
+    (dir, file_name)
+}
+
+// Sources of config reload requests: SIGUSR2 (sent by `systemctl --user reload`) and changes to
+// the config files. Both are exposed as file descriptors so they can be added to the epoll set of
+// the main loop. The signalfd also receives the stop requests, SIGTERM and SIGINT.
+pub struct ReloadWatcher {
+    signal_fd: SignalFd,
+    inotify: Inotify,
+    // patterns of the file names we care about, by watched parent directory
+    watched_files: HashMap<WatchDescriptor, Vec<String>>,
+}
+
+impl ReloadWatcher {
+    // block_handled_signals has to be called first
+    pub fn new(watched_paths: &[PathBuf]) -> ReloadWatcher {
+	let signal_fd = match SignalFd::with_flags(&handled_signals(), SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC) {
+	    Ok(signal_fd) => signal_fd,
+	    Err(error) => panic!("failed to create signalfd: {}", error),
+	};
+
+	let inotify = match Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC) {
+	    Ok(inotify) => inotify,
+	    Err(error) => panic!("failed to create inotify instance: {}", error),
+	};
+
+	let mut watcher = ReloadWatcher {
+	    signal_fd,
+	    inotify,
+	    watched_files: HashMap::new(),
+	};
+	watcher.watch(watched_paths);
+	watcher
+    }
+
+    // Editors usually replace files instead of writing to them, so we watch the directories that
+    // contain the config files rather than the files themselves. A directory that does not exist
+    // yet is watched for from its nearest existing parent: creating it triggers a reload, after
+    // which it is watched itself.
+    pub fn watch(&mut self, watched_paths: &[PathBuf]) {
+	for (watch_descriptor, _) in self.watched_files.drain() {
+	    let _ = self.inotify.rm_watch(watch_descriptor);
+	}
+
+	for watched_path in watched_paths {
+	    let (dir, file_name) = match (watched_path.parent(), watched_path.file_name()) {
+		(Some(dir), Some(file_name)) => nearest_existing_dir(dir, file_name.to_string_lossy().into_owned()),
+		_ => continue,
+	    };
+	    let flags = AddWatchFlags::IN_CLOSE_WRITE
+		| AddWatchFlags::IN_MOVED_TO
+		| AddWatchFlags::IN_MOVED_FROM
+		| AddWatchFlags::IN_CREATE
+		| AddWatchFlags::IN_DELETE;
+	    match self.inotify.add_watch(dir, flags) {
+		Ok(watch_descriptor) => self.watched_files.entry(watch_descriptor).or_default().push(file_name),
+		Err(error) => println!("cannot watch {} for config changes: {}", dir.to_string_lossy(), error),
+	    }
+	}
+    }
+
+    pub fn signal_fd(&self) -> BorrowedFd<'_> {
+	self.signal_fd.as_fd()
+    }
+
+    pub fn inotify_fd(&self) -> BorrowedFd<'_> {
+	self.inotify.as_fd()
+    }
+
+    // Drains pending signals
+    pub fn read_signals(&mut self) -> SignalRequests {
+	let mut requests = SignalRequests::default();
+	loop {
+	    match self.signal_fd.read_signal() {
+		Ok(Some(siginfo)) => {
+		    println!("received signal {}", siginfo.ssi_signo);
+		    match Signal::try_from(siginfo.ssi_signo as i32) {
+			Ok(Signal::SIGTERM) | Ok(Signal::SIGINT) => requests.stop = true,
+			_ => requests.reload = true,
+		    }
+		}
+		Ok(None) => break,
+		Err(error) => {
+		    println!("error when reading signalfd: {}", error);
+		    break;
+		}
+	    }
+	}
+	requests
+    }
+
+    // Drains pending inotify events, returns true if one of them concerns a config file
+    pub fn read_inotify(&mut self) -> bool {
+	let mut reload = false;
+	loop {
+	    let events = match self.inotify.read_events() {
+		Ok(events) => events,
+		Err(nix::errno::Errno::EAGAIN) => break,
+		Err(error) => {
+		    println!("error when reading inotify events: {}", error);
+		    break;
+		}
+	    };
+	    for event in events {
+		let file_names = match self.watched_files.get(&event.wd) {
+		    Some(file_names) => file_names,
+		    None => continue,
+		};
+		if let Some(name) = event.name {
+		    let name = name.to_string_lossy();
+		    if file_names.iter().any(|file_name| glob_match(file_name, &name)) {
+			println!("config file {} changed", name);
+			reload = true;
+		    }
+		}
+	    }
+	}
+	reload
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{self, File},
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::metadata_cache::{escape_path, unescape_path};
+use crate::order::ShuffleState;
+use crate::xdg;
+
+const STATE_HEADER: &str = "wl-bg-gallery rotation state 2";
+// the state is written at most this often while the program runs, and once more when it stops
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Where the rotation was when the program stopped, kept in $XDG_STATE_HOME/wl-bg-gallery/rotation
+// so that a restart does not show the same images again. The file has one tab separated line per
+// source and per output, and one per shuffled source, paths are escaped as in the metadata cache.
+// A shuffled rotation is found again from its seed and cycle.
+pub struct RotationState {
+    // None if there is no state directory we can use
+    state_path: Option<PathBuf>,
+    // by source, the image its rotation goes on with
+    positions: HashMap<PathBuf, PathBuf>,
+    // by output name, the source it shows an image of, and the image
+    shown: HashMap<String, (PathBuf, PathBuf)>,
+    // by source, where its shuffle is
+    shuffles: HashMap<PathBuf, ShuffleState>,
+    // whether there are changes to write
+    changed: bool,
+    saved_at: Option<Instant>,
+}
+
+enum StateLine {
+    Source(PathBuf, PathBuf),
+    Output(PathBuf, PathBuf, PathBuf),
+    Shuffle(PathBuf, ShuffleState),
+}
+
+fn parse_number(field: &[u8]) -> Option<u64> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+fn parse_line(line: &[u8]) -> Option<StateLine> {
+    let fields: Vec<&[u8]> = line.split(|byte| *byte == b'\t').collect();
+    match fields[..] {
+	[b"source", source, image] => Some(StateLine::Source(unescape_path(source)?, unescape_path(image)?)),
+	[b"output", output_name, source, image] => Some(StateLine::Output(
+	    unescape_path(output_name)?,
+	    unescape_path(source)?,
+	    unescape_path(image)?,
+	)),
+	[b"shuffle", source, seed, cycle] => Some(StateLine::Shuffle(
+	    unescape_path(source)?,
+	    ShuffleState {
+		seed: parse_number(seed)?,
+		cycle: parse_number(cycle)?,
+	    },
+	)),
+	_ => None,
+    }
+}
+
+impl RotationState {
+    pub fn load() -> RotationState {
+	RotationState::load_from(xdg::state_home().join("wl-bg-gallery").join("rotation"))
+    }
+
+    fn load_from(state_path: PathBuf) -> RotationState {
+	let mut state = RotationState {
+	    state_path: Some(state_path.clone()),
+	    positions: HashMap::new(),
+	    shown: HashMap::new(),
+	    shuffles: HashMap::new(),
+	    changed: false,
+	    saved_at: None,
+	};
+	let contents = match fs::read(&state_path) {
+	    Ok(contents) => contents,
+	    Err(_) => return state,
+	};
+	let mut lines = contents.split(|byte| *byte == b'\n');
+	if lines.next() != Some(STATE_HEADER.as_bytes()) {
+	    println!("ignoring {}, it was written by another version", state_path.to_string_lossy());
+	    return state;
+	}
+	for line in lines.filter(|line| !line.is_empty()) {
+	    match parse_line(line) {
+		Some(StateLine::Source(source, image)) => {
+		    state.positions.insert(source, image);
+		}
+		Some(StateLine::Output(output_name, source, image)) => {
+		    state.shown.insert(output_name.to_string_lossy().into_owned(), (source, image));
+		}
+		Some(StateLine::Shuffle(source, shuffle_state)) => {
+		    state.shuffles.insert(source, shuffle_state);
+		}
+		_ => println!("ignoring invalid line in {}", state_path.to_string_lossy()),
+	    }
+	}
+	state
+    }
+
+    pub fn position(&self, source: &Path) -> Option<&PathBuf> {
+	self.positions.get(source)
+    }
+
+    pub fn set_position(&mut self, source: &Path, image: &Path) {
+	if self.positions.get(source).map(PathBuf::as_path) != Some(image) {
+	    self.positions.insert(source.to_path_buf(), image.to_path_buf());
+	    self.changed = true;
+	}
+    }
+
+    pub fn shown(&self, output_name: &str) -> Option<&(PathBuf, PathBuf)> {
+	self.shown.get(output_name)
+    }
+
+    pub fn set_shown(&mut self, output_name: &str, source: &Path, image: &Path) {
+	let shown = (source.to_path_buf(), image.to_path_buf());
+	if self.shown.get(output_name) != Some(&shown) {
+	    self.shown.insert(String::from(output_name), shown);
+	    self.changed = true;
+	}
+    }
+
+    pub fn shuffle(&self, source: &Path) -> Option<ShuffleState> {
+	self.shuffles.get(source).copied()
+    }
+
+    pub fn set_shuffle(&mut self, source: &Path, shuffle_state: ShuffleState) {
+	if self.shuffles.get(source) != Some(&shuffle_state) {
+	    self.shuffles.insert(source.to_path_buf(), shuffle_state);
+	    self.changed = true;
+	}
+    }
+
+    // Writes the state if it changed, at most every SAVE_INTERVAL. Returns the time until the
+    // changes that are left can be written, if any.
+    pub fn save(&mut self) -> Option<Duration> {
+	if !self.changed || self.state_path.is_none() {
+	    return None;
+	}
+	if let Some(saved_at) = self.saved_at {
+	    let since_saved = saved_at.elapsed();
+	    if since_saved < SAVE_INTERVAL {
+		return Some(SAVE_INTERVAL - since_saved);
+	    }
+	}
+	self.flush();
+	None
+    }
+
+    // Writes the state if it changed, however recently it was written. Errors are not fatal, the
+    // state is no longer saved instead.
+    pub fn flush(&mut self) {
+	if !self.changed {
+	    return;
+	}
+	let state_path = match self.state_path {
+	    Some(ref state_path) => state_path.clone(),
+	    None => return,
+	};
+	if let Err(error) = self.write(&state_path) {
+	    println!("no longer saving the rotation, failed to write {}: {}", state_path.to_string_lossy(), error);
+	    self.state_path = None;
+	}
+	self.saved_at = Some(Instant::now());
+	self.changed = false;
+    }
+
+    fn write(&self, state_path: &Path) -> std::io::Result<()> {
+	if let Some(state_dir) = state_path.parent() {
+	    fs::create_dir_all(state_dir)?;
+	}
+	let mut contents = Vec::new();
+	contents.extend_from_slice(STATE_HEADER.as_bytes());
+	contents.push(b'\n');
+	for (source, image) in self.positions.iter() {
+	    contents.extend_from_slice(b"source\t");
+	    contents.extend_from_slice(&escape_path(source));
+	    contents.push(b'\t');
+	    contents.extend_from_slice(&escape_path(image));
+	    contents.push(b'\n');
+	}
+	for (output_name, (source, image)) in self.shown.iter() {
+	    contents.extend_from_slice(b"output\t");
+	    contents.extend_from_slice(&escape_path(Path::new(OsStr::from_bytes(output_name.as_bytes()))));
+	    contents.push(b'\t');
+	    contents.extend_from_slice(&escape_path(source));
+	    contents.push(b'\t');
+	    contents.extend_from_slice(&escape_path(image));
+	    contents.push(b'\n');
+	}
+	for (source, shuffle_state) in self.shuffles.iter() {
+	    contents.extend_from_slice(b"shuffle\t");
+	    contents.extend_from_slice(&escape_path(source));
+	    contents.extend_from_slice(format!("\t{}\t{}\n", shuffle_state.seed, shuffle_state.cycle).as_bytes());
+	}
+
+	// replaced atomically, a crash never leaves a partial file behind
+	let tmp_path = state_path.with_extension(format!("{}.tmp", std::process::id()));
+	let mut tmp_file = File::create(&tmp_path)?;
+	let written = tmp_file.write_all(&contents).and_then(|_| tmp_file.sync_all());
+	if let Err(error) = written.and_then(|_| fs::rename(&tmp_path, state_path)) {
+	    let _ = fs::remove_file(&tmp_path);
+	    return Err(error);
+	}
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+
+    fn test_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("wl-bg-gallery-{}-{}", name, std::process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	dir
+    }
+
+    #[test]
+    fn state_parses_back() {
+	let dir = test_dir("rotation-state");
+	let state_path = dir.join("rotation");
+	let odd_path = PathBuf::from(std::ffi::OsString::from_vec(b"/pictures/tab\there/new\nline/\xff.png".to_vec()));
+	let shuffle_state = ShuffleState {
+	    seed: u64::MAX,
+	    cycle: 42,
+	};
+
+	let mut state = RotationState::load_from(state_path.clone());
+	state.set_position(Path::new("/pictures"), &odd_path);
+	state.set_position(Path::new("/playlist.m3u"), Path::new("/other/a.jpg"));
+	state.set_shown("DP-1", Path::new("/pictures"), &odd_path);
+	state.set_shown("eDP-1", Path::new("/playlist.m3u"), Path::new("/other/b.jpg"));
+	state.set_shuffle(Path::new("/pictures"), shuffle_state);
+	state.flush();
+
+	let loaded = RotationState::load_from(state_path.clone());
+	assert_eq!(loaded.positions, state.positions);
+	assert_eq!(loaded.shown, state.shown);
+	assert_eq!(loaded.shuffles, state.shuffles);
+	assert_eq!(loaded.position(Path::new("/pictures")), Some(&odd_path));
+	assert_eq!(loaded.shuffle(Path::new("/pictures")), Some(shuffle_state));
+	assert!(!loaded.changed);
+	let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn save_waits_for_the_interval_and_flush_does_not() {
+	let dir = test_dir("rotation-throttle");
+	let state_path = dir.join("rotation");
+	let mut state = RotationState::load_from(state_path.clone());
+	state.set_position(Path::new("/pictures"), Path::new("/pictures/a.png"));
+	assert_eq!(state.save(), None);
+
+	state.set_position(Path::new("/pictures"), Path::new("/pictures/b.png"));
+	assert!(state.save().is_some());
+	let loaded = RotationState::load_from(state_path.clone());
+	assert_eq!(loaded.position(Path::new("/pictures")), Some(&PathBuf::from("/pictures/a.png")));
+
+	state.flush();
+	let loaded = RotationState::load_from(state_path.clone());
+	assert_eq!(loaded.position(Path::new("/pictures")), Some(&PathBuf::from("/pictures/b.png")));
+	let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn state_of_another_version_is_ignored() {
+	let dir = test_dir("rotation-version");
+	let state_path = dir.join("rotation");
+	fs::create_dir_all(&dir).unwrap();
+	fs::write(&state_path, "wl-bg-gallery rotation state 1\nsource\t/pictures\t/pictures/a.png\n").unwrap();
+	let loaded = RotationState::load_from(state_path);
+	assert!(loaded.positions.is_empty());
+	let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn invalid_lines() {
+	assert!(parse_line(b"source\t/pictures").is_none());
+	assert!(parse_line(b"shuffle\t/pictures\tseed\t0").is_none());
+	assert!(parse_line(b"output\tDP-1\t/pictures").is_none());
+	assert!(parse_line(b"unknown\t/pictures\t/pictures/a.png").is_none());
+	assert!(matches!(parse_line(b"shuffle\t/pictures\t1\t2"), Some(StateLine::Shuffle(_, ShuffleState { seed: 1, cycle: 2 }))));
+    }
+}
@@ -0,0 +1,307 @@
+use std::{f64::consts::PI, time::Duration};
+
+use serde::Deserialize;
+
+use crate::config::Source;
+use crate::duration::parse_duration;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// The current date and time in the local timezone
+#[derive(Debug, Clone, Copy)]
+pub struct LocalTime {
+    // day of the year, starting at 0 for January 1st
+    pub day_of_year: u32,
+    pub seconds_since_midnight: i64,
+    pub utc_offset_seconds: i64,
+}
+
+impl LocalTime {
+    pub fn now() -> LocalTime {
+	unsafe {
+	    let now = libc::time(std::ptr::null_mut());
+	    let mut tm: libc::tm = std::mem::zeroed();
+	    if libc::localtime_r(&now, &mut tm).is_null() {
+		panic!("localtime_r failed");
+	    }
+	    LocalTime {
+		day_of_year: tm.tm_yday as u32,
+		seconds_since_midnight: (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec) as i64,
+		utc_offset_seconds: tm.tm_gmtoff,
+	    }
+	}
+    }
+}
+
+// When a schedule window starts: a fixed time, or sunrise / sunset with an optional offset, written
+// "07:30", "sunrise", "sunset-01:00", "sunrise+45m"...
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub enum TimeOfDay {
+    Clock { seconds: i64 },
+    Sunrise { offset_seconds: i64 },
+    Sunset { offset_seconds: i64 },
+}
+
+// "HH:MM" or "HH:MM:SS" to a number of seconds, up to 23:59:59
+fn parse_clock(clock: &str) -> Option<i64> {
+    let components: Vec<&str> = clock.split(':').collect();
+    if components.len() < 2 || components.len() > 3 {
+	return None;
+    }
+    let mut seconds = 0;
+    for (index, component) in components.iter().enumerate() {
+	if component.is_empty() || !component.chars().all(|c| c.is_ascii_digit()) {
+	    return None;
+	}
+	let value: i64 = match component.parse() {
+	    Ok(value) => value,
+	    Err(_) => return None,
+	};
+	let max = if index == 0 { 23 } else { 59 };
+	if value > max {
+	    return None;
+	}
+	seconds = seconds * 60 + value;
+    }
+    if components.len() == 2 {
+	seconds *= 60;
+    }
+    Some(seconds)
+}
+
+fn parse_offset(offset: &str) -> Option<i64> {
+    if offset.is_empty() {
+	return Some(0);
+    }
+    let (sign, clock) = match offset.split_at(1) {
+	("+", clock) => (1, clock),
+	("-", clock) => (-1, clock),
+	_ => return None,
+    };
+    // either "+HH:MM" or "+1h30m"
+    match parse_clock(clock) {
+	Some(seconds) => Some(sign * seconds),
+	None => parse_duration(clock).ok().map(|seconds| sign * seconds as i64),
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<TimeOfDay, String> {
+	let time_of_day = if let Some(offset) = value.strip_prefix("sunrise") {
+	    parse_offset(offset).map(|offset_seconds| TimeOfDay::Sunrise { offset_seconds })
+	} else if let Some(offset) = value.strip_prefix("sunset") {
+	    parse_offset(offset).map(|offset_seconds| TimeOfDay::Sunset { offset_seconds })
+	} else {
+	    parse_clock(&value).map(|seconds| TimeOfDay::Clock { seconds })
+	};
+	match time_of_day {
+	    Some(time_of_day) => Ok(time_of_day),
+	    None => Err(format!(
+		"invalid time {}, expected HH:MM, sunrise or sunset, optionally followed by an offset like +00:30 or -1h",
+		value
+	    )),
+	}
+    }
+}
+
+impl std::fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	fn format_offset(offset_seconds: i64) -> String {
+	    if offset_seconds == 0 {
+		return String::new();
+	    }
+	    let sign = if offset_seconds < 0 { '-' } else { '+' };
+	    let offset_seconds = offset_seconds.abs();
+	    format!("{}{:02}:{:02}", sign, offset_seconds / 3600, offset_seconds % 3600 / 60)
+	}
+	match self {
+	    TimeOfDay::Clock { seconds } => write!(f, "{:02}:{:02}", seconds / 3600, seconds % 3600 / 60),
+	    TimeOfDay::Sunrise { offset_seconds } => write!(f, "sunrise{}", format_offset(*offset_seconds)),
+	    TimeOfDay::Sunset { offset_seconds } => write!(f, "sunset{}", format_offset(*offset_seconds)),
+	}
+    }
+}
+
+impl TimeOfDay {
+    pub fn needs_location(&self) -> bool {
+	!matches!(self, TimeOfDay::Clock { .. })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+// A [[schedule]] table: the sources to use from `start` until the start of the next window
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleWindow {
+    pub start: TimeOfDay,
+    pub sources: Vec<Source>,
+}
+
+// Local sunrise and sunset times, in seconds since midnight, computed with the NOAA approximation
+// of the position of the sun. None when the sun does not rise or does not set on that day.
+pub fn sun_times(day_of_year: u32, utc_offset_seconds: i64, location: &Location) -> Option<(i64, i64)> {
+    let gamma = 2.0 * PI / 365.0 * day_of_year as f64;
+    let equation_of_time_minutes = 229.18
+	* (0.000075 + 0.001868 * gamma.cos()
+	    - 0.032077 * gamma.sin()
+	    - 0.014615 * (2.0 * gamma).cos()
+	    - 0.040849 * (2.0 * gamma).sin());
+    let declination = 0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin()
+	- 0.006758 * (2.0 * gamma).cos()
+	+ 0.000907 * (2.0 * gamma).sin()
+	- 0.002697 * (3.0 * gamma).cos()
+	+ 0.00148 * (3.0 * gamma).sin();
+    let latitude = location.latitude.to_radians();
+    // 90.833° accounts for atmospheric refraction and the size of the solar disk
+    let cos_hour_angle = 90.833_f64.to_radians().cos() / (latitude.cos() * declination.cos())
+	- latitude.tan() * declination.tan();
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+	return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+
+    let to_local_seconds = |utc_minutes: f64| -> i64 {
+	((utc_minutes * 60.0) as i64 + utc_offset_seconds).rem_euclid(SECONDS_PER_DAY)
+    };
+    let sunrise = to_local_seconds(720.0 - 4.0 * (location.longitude + hour_angle) - equation_of_time_minutes);
+    let sunset = to_local_seconds(720.0 - 4.0 * (location.longitude - hour_angle) - equation_of_time_minutes);
+    Some((sunrise, sunset))
+}
+
+// When a window starts on the given day, in seconds since midnight
+fn start_seconds(start: &TimeOfDay, day_of_year: u32, utc_offset_seconds: i64, location: &Option<Location>) -> Option<i64> {
+    let sun_times = match location {
+	Some(location) => sun_times(day_of_year, utc_offset_seconds, location),
+	None => None,
+    };
+    match (start, sun_times) {
+	(TimeOfDay::Clock { seconds }, _) => Some(*seconds),
+	(TimeOfDay::Sunrise { offset_seconds }, Some((sunrise, _))) => {
+	    Some((sunrise + offset_seconds).rem_euclid(SECONDS_PER_DAY))
+	}
+	(TimeOfDay::Sunset { offset_seconds }, Some((_, sunset))) => {
+	    Some((sunset + offset_seconds).rem_euclid(SECONDS_PER_DAY))
+	}
+	_ => None,
+    }
+}
+
+// The index of the window we are in, and the time until the next window starts. Windows whose
+// start cannot be computed (no sunrise during polar night...) are skipped.
+pub fn current_window(
+    schedule: &[ScheduleWindow],
+    location: &Option<Location>,
+    now: &LocalTime,
+) -> (Option<usize>, Option<Duration>) {
+    let mut starts: Vec<(i64, usize)> = schedule
+	.iter()
+	.enumerate()
+	.filter_map(|(index, window)| {
+	    start_seconds(&window.start, now.day_of_year, now.utc_offset_seconds, location)
+		.map(|start| (start, index))
+	})
+	.collect();
+    if starts.is_empty() {
+	return (None, None);
+    }
+    starts.sort();
+
+    // before the first start of the day, we are still in the last window of the day before
+    let current = match starts.iter().rev().find(|(start, _)| *start <= now.seconds_since_midnight) {
+	Some((_, index)) => *index,
+	None => starts.last().unwrap().1,
+    };
+    let next_start = match starts.iter().find(|(start, _)| *start > now.seconds_since_midnight) {
+	Some((start, _)) => *start,
+	None => starts[0].0 + SECONDS_PER_DAY,
+    };
+    let until_next = Duration::from_secs((next_start - now.seconds_since_midnight) as u64);
+    (Some(current), Some(until_next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // within a few minutes of the published times, the approximation is not more precise
+    fn assert_close(actual: i64, expected: &str) {
+	let expected = parse_clock(expected).unwrap();
+	assert!((actual - expected).abs() <= 5 * 60, "{} is not close to {}", TimeOfDay::Clock { seconds: actual }, TimeOfDay::Clock { seconds: expected });
+    }
+
+    #[test]
+    fn clock_times() {
+	assert_eq!(parse_clock("00:00"), Some(0));
+	assert_eq!(parse_clock("07:30"), Some(7 * 3600 + 30 * 60));
+	assert_eq!(parse_clock("23:59:59"), Some(86399));
+	assert_eq!(parse_clock("1:05"), Some(3900));
+    }
+
+    #[test]
+    fn invalid_clock_times() {
+	assert_eq!(parse_clock("24:00"), None);
+	assert_eq!(parse_clock("24:59"), None);
+	assert_eq!(parse_clock("12:60"), None);
+	assert_eq!(parse_clock("12"), None);
+	assert_eq!(parse_clock("12:00:00:00"), None);
+	assert_eq!(parse_clock("ab:cd"), None);
+	assert_eq!(parse_clock("-1:00"), None);
+    }
+
+    #[test]
+    fn times_of_day() {
+	assert_eq!(TimeOfDay::try_from(String::from("sunset-01:00")), Ok(TimeOfDay::Sunset { offset_seconds: -3600 }));
+	assert_eq!(TimeOfDay::try_from(String::from("sunrise+45m")), Ok(TimeOfDay::Sunrise { offset_seconds: 2700 }));
+	assert_eq!(TimeOfDay::try_from(String::from("sunrise")), Ok(TimeOfDay::Sunrise { offset_seconds: 0 }));
+	assert!(TimeOfDay::try_from(String::from("sunrise*2")).is_err());
+	assert!(TimeOfDay::try_from(String::from("noon")).is_err());
+    }
+
+    #[test]
+    fn sun_times_in_paris_at_the_summer_solstice() {
+	// June 21st, in CEST
+	let paris = Location { latitude: 48.8566, longitude: 2.3522 };
+	let (sunrise, sunset) = sun_times(171, 2 * 3600, &paris).unwrap();
+	assert_close(sunrise, "05:47");
+	assert_close(sunset, "21:58");
+    }
+
+    #[test]
+    fn sun_times_at_the_equator_at_the_equinox() {
+	let null_island = Location { latitude: 0.0, longitude: 0.0 };
+	let (sunrise, sunset) = sun_times(78, 0, &null_island).unwrap();
+	assert_close(sunrise, "06:04");
+	assert_close(sunset, "18:11");
+    }
+
+    #[test]
+    fn no_sunset_during_the_polar_day() {
+	let longyearbyen = Location { latitude: 78.22, longitude: 15.65 };
+	assert_eq!(sun_times(171, 2 * 3600, &longyearbyen), None);
+	assert_eq!(sun_times(355, 3600, &longyearbyen), None);
+    }
+
+    #[test]
+    fn windows_wrap_around_midnight() {
+	let window = |start: &str| ScheduleWindow {
+	    start: TimeOfDay::try_from(String::from(start)).unwrap(),
+	    sources: Vec::new(),
+	};
+	let schedule = [window("08:00"), window("20:00")];
+	let at = |clock: &str| LocalTime {
+	    day_of_year: 0,
+	    seconds_since_midnight: parse_clock(clock).unwrap(),
+	    utc_offset_seconds: 0,
+	};
+	assert_eq!(current_window(&schedule, &None, &at("12:00")), (Some(0), Some(Duration::from_secs(8 * 3600))));
+	assert_eq!(current_window(&schedule, &None, &at("06:00")), (Some(1), Some(Duration::from_secs(2 * 3600))));
+	assert_eq!(current_window(&schedule, &None, &at("21:00")), (Some(1), Some(Duration::from_secs(11 * 3600))));
+    }
+}
@@ -0,0 +1,174 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use image::ImageFormat;
+use serde::Deserialize;
+
+use crate::config::{deserialize_color, deserialize_interval, format_parse_error, FitMode, Matcher};
+
+const SIDECAR_EXTENSION: &str = "toml";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParsedSidecar {
+    pub fit: Option<FitMode>,
+    #[serde(default, deserialize_with = "deserialize_color")]
+    pub background_color: Option<[u8; 4]>,
+    pub focal_point: Option<[f64; 2]>,
+    #[serde(default, deserialize_with = "deserialize_interval")]
+    pub interval: Option<u64>,
+    pub weight: Option<u32>,
+    pub outputs: Option<Vec<Matcher>>,
+}
+
+// The settings of an image that replace those of the config, read from the image.jpg.toml file
+// next to image.jpg
+#[derive(Debug, Clone)]
+pub struct ImageOverrides {
+    pub fit: Option<FitMode>,
+    pub background_color: Option<[u8; 4]>,
+    // the point of the image, as fractions of its width and height, that stays visible when it is
+    // cropped
+    pub focal_point: Option<(f64, f64)>,
+    pub bg_duration_seconds: Option<u64>,
+    // how often the image is drawn when the order is Random, compared to the others
+    pub weight: Option<u32>,
+    // the outputs the image can be shown on, by name, all of them if empty
+    pub outputs: Vec<Matcher>,
+}
+
+pub fn sidecar_path(image_path: &Path) -> PathBuf {
+    let mut sidecar_path = OsString::from(image_path.as_os_str());
+    sidecar_path.push(".");
+    sidecar_path.push(SIDECAR_EXTENSION);
+    PathBuf::from(sidecar_path)
+}
+
+// The files named after an image, e.g. image.jpg.toml, whether the image is there or not. They
+// are never images themselves.
+pub fn is_sidecar(path: &Path) -> bool {
+    if path.extension().is_none_or(|extension| extension != SIDECAR_EXTENSION) {
+	return false;
+    }
+    let image_name = match path.file_stem() {
+	Some(image_name) => Path::new(image_name),
+	None => return false,
+    };
+    image_name.extension().is_some_and(|extension| ImageFormat::from_extension(extension).is_some())
+}
+
+// The image a sidecar file applies to
+pub fn image_path(sidecar_path: &Path) -> Option<PathBuf> {
+    if !is_sidecar(sidecar_path) {
+	return None;
+    }
+    Some(sidecar_path.with_extension(""))
+}
+
+impl ImageOverrides {
+    // None if the image has no sidecar file, or if it cannot be read, in which case the image is
+    // used with the settings of the config
+    pub fn load(image_path: &Path) -> Option<ImageOverrides> {
+	let sidecar_path = sidecar_path(image_path);
+	let sidecar_str = match fs::read_to_string(&sidecar_path) {
+	    Ok(sidecar_str) => sidecar_str,
+	    Err(error) if error.kind() == std::io::ErrorKind::NotFound => return None,
+	    Err(error) => {
+		println!("failed to read {}: {}", sidecar_path.to_string_lossy(), error);
+		return None;
+	    }
+	};
+	match ImageOverrides::parse(&sidecar_path, &sidecar_str) {
+	    Ok(overrides) => Some(overrides),
+	    Err(error) => {
+		println!("{}", error);
+		None
+	    }
+	}
+    }
+
+    fn parse(sidecar_path: &Path, sidecar_str: &str) -> Result<ImageOverrides, String> {
+	let parsed_sidecar: ParsedSidecar = match toml::from_str(sidecar_str) {
+	    Ok(parsed_sidecar) => parsed_sidecar,
+	    Err(error) => return Err(format_parse_error(&sidecar_path.to_string_lossy(), sidecar_str, &error)),
+	};
+	// the image would change in a busy loop, as with the interval of the config
+	if parsed_sidecar.interval == Some(0) {
+	    return Err(format!("{}: interval cannot be 0", sidecar_path.to_string_lossy()));
+	}
+	if let Some([x, y]) = parsed_sidecar.focal_point {
+	    if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+		return Err(format!(
+		    "{}: focal_point [{}, {}] is not within the image, it should be between 0 and 1",
+		    sidecar_path.to_string_lossy(),
+		    x,
+		    y
+		));
+	    }
+	}
+	Ok(ImageOverrides {
+	    fit: parsed_sidecar.fit,
+	    background_color: parsed_sidecar.background_color,
+	    focal_point: parsed_sidecar.focal_point.map(|[x, y]| (x, y)),
+	    bg_duration_seconds: parsed_sidecar.interval,
+	    weight: parsed_sidecar.weight,
+	    outputs: parsed_sidecar.outputs.unwrap_or_default(),
+	})
+    }
+
+    pub fn shows_on(&self, output_name: &str) -> bool {
+	self.outputs.is_empty() || self.outputs.iter().any(|matcher| matcher.is_match(output_name))
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+	self.bg_duration_seconds.map(Duration::from_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(sidecar_str: &str) -> Result<ImageOverrides, String> {
+	ImageOverrides::parse(Path::new("/pictures/image.jpg.toml"), sidecar_str)
+    }
+
+    #[test]
+    fn sidecar_settings() {
+	let overrides = parse("interval = \"2m\"\nweight = 3\nfocal_point = [0.25, 1.0]\noutputs = [\"DP-*\"]\n").unwrap();
+	assert_eq!(overrides.duration(), Some(Duration::from_secs(120)));
+	assert_eq!(overrides.weight, Some(3));
+	assert_eq!(overrides.focal_point, Some((0.25, 1.0)));
+	assert!(overrides.shows_on("DP-1"));
+	assert!(!overrides.shows_on("eDP-1"));
+	assert!(parse("").unwrap().shows_on("eDP-1"));
+    }
+
+    #[test]
+    fn zero_interval() {
+	for sidecar_str in ["interval = 0", "interval = \"0s\"", "interval = \"0m\""] {
+	    assert_eq!(parse(sidecar_str).unwrap_err(), "/pictures/image.jpg.toml: interval cannot be 0");
+	}
+	assert!(parse("interval = 1").is_ok());
+    }
+
+    #[test]
+    fn invalid_sidecars() {
+	assert!(parse("focal_point = [0.5, 1.5]").is_err());
+	assert!(parse("focal_point = [-0.1, 0.5]").is_err());
+	assert!(parse("unknown = 1").is_err());
+	assert!(parse("interval = \"soon\"").is_err());
+    }
+
+    #[test]
+    fn sidecar_names() {
+	assert!(is_sidecar(Path::new("/pictures/image.jpg.toml")));
+	assert!(!is_sidecar(Path::new("/pictures/config.toml")));
+	assert!(!is_sidecar(Path::new("/pictures/notes.txt.toml")));
+	assert!(!is_sidecar(Path::new("/pictures/image.jpg")));
+	assert_eq!(image_path(Path::new("/pictures/image.png.toml")), Some(PathBuf::from("/pictures/image.png")));
+	assert_eq!(sidecar_path(Path::new("/pictures/image.png")), PathBuf::from("/pictures/image.png.toml"));
+    }
+}
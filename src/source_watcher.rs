@@ -0,0 +1,159 @@
+use std::{
+    collections::{HashMap, HashSet},
+    os::fd::{AsFd, BorrowedFd},
+    path::PathBuf,
+};
+
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+
+// A change seen in one of the directories images are taken from
+#[derive(Debug)]
+pub enum SourceChange {
+    // a file was written or a directory was created
+    Added { path: PathBuf, is_dir: bool },
+    Removed { path: PathBuf },
+    // both halves of a rename share the same cookie
+    MovedFrom { path: PathBuf, cookie: u32 },
+    MovedTo { path: PathBuf, is_dir: bool, cookie: u32 },
+    // the file system the directory is on was unmounted, its files may come back
+    Unmounted { path: PathBuf },
+    // the kernel dropped events because they came in faster than they were read, anything may
+    // have changed
+    Overflow,
+}
+
+impl SourceChange {
+    pub fn path(&self) -> Option<&PathBuf> {
+	match self {
+	    SourceChange::Added { path, .. } => Some(path),
+	    SourceChange::Removed { path } => Some(path),
+	    SourceChange::MovedFrom { path, .. } => Some(path),
+	    SourceChange::MovedTo { path, .. } => Some(path),
+	    SourceChange::Unmounted { path } => Some(path),
+	    SourceChange::Overflow => None,
+	}
+    }
+}
+
+// Watches the source directories with inotify, so that the image sets can be updated as files
+// come and go instead of waiting for the next full scan
+pub struct SourceWatcher {
+    inotify: Inotify,
+    watched_dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl Default for SourceWatcher {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl SourceWatcher {
+    pub fn new() -> SourceWatcher {
+	let inotify = match Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC) {
+	    Ok(inotify) => inotify,
+	    Err(error) => panic!("failed to create inotify instance: {}", error),
+	};
+	SourceWatcher {
+	    inotify,
+	    watched_dirs: HashMap::new(),
+	}
+    }
+
+    // Adds and removes watches so that exactly dirs are watched
+    pub fn watch(&mut self, dirs: &HashSet<PathBuf>) {
+	let stale_watches: Vec<WatchDescriptor> = self
+	    .watched_dirs
+	    .iter()
+	    .filter(|(_, dir)| !dirs.contains(*dir))
+	    .map(|(watch_descriptor, _)| *watch_descriptor)
+	    .collect();
+	for watch_descriptor in stale_watches {
+	    self.watched_dirs.remove(&watch_descriptor);
+	    let _ = self.inotify.rm_watch(watch_descriptor);
+	}
+
+	let flags = AddWatchFlags::IN_CLOSE_WRITE
+	    | AddWatchFlags::IN_CREATE
+	    | AddWatchFlags::IN_DELETE
+	    | AddWatchFlags::IN_MOVED_FROM
+	    | AddWatchFlags::IN_MOVED_TO
+	    | AddWatchFlags::IN_ONLYDIR;
+	for dir in dirs {
+	    if self.watched_dirs.values().any(|watched_dir| watched_dir == dir) {
+		continue;
+	    }
+	    match self.inotify.add_watch(dir, flags) {
+		Ok(watch_descriptor) => {
+		    self.watched_dirs.insert(watch_descriptor, dir.clone());
+		}
+		Err(error) => println!("cannot watch {} for new images: {}", dir.to_string_lossy(), error),
+	    }
+	}
+    }
+
+    pub fn inotify_fd(&self) -> BorrowedFd<'_> {
+	self.inotify.as_fd()
+    }
+
+    // Drains pending inotify events
+    pub fn read_changes(&mut self) -> Vec<SourceChange> {
+	let mut changes = Vec::new();
+	loop {
+	    let events = match self.inotify.read_events() {
+		Ok(events) => events,
+		Err(nix::errno::Errno::EAGAIN) => break,
+		Err(error) => {
+		    println!("error when reading inotify events: {}", error);
+		    break;
+		}
+	    };
+	    for event in events {
+		if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+		    changes.push(SourceChange::Overflow);
+		    continue;
+		}
+		if event.mask.contains(AddWatchFlags::IN_UNMOUNT) {
+		    // the kernel drops the watch right after
+		    if let Some(dir) = self.watched_dirs.get(&event.wd) {
+			changes.push(SourceChange::Unmounted { path: dir.clone() });
+		    }
+		    continue;
+		}
+		if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+		    // the directory was removed, the kernel dropped the watch
+		    self.watched_dirs.remove(&event.wd);
+		    continue;
+		}
+		let (dir, name) = match (self.watched_dirs.get(&event.wd), event.name) {
+		    (Some(dir), Some(name)) => (dir, name),
+		    _ => continue,
+		};
+		let path = dir.join(name);
+		let is_dir = event.mask.contains(AddWatchFlags::IN_ISDIR);
+
+		let change = if event.mask.contains(AddWatchFlags::IN_MOVED_FROM) {
+		    SourceChange::MovedFrom { path, cookie: event.cookie }
+		} else if event.mask.contains(AddWatchFlags::IN_MOVED_TO) {
+		    SourceChange::MovedTo { path, is_dir, cookie: event.cookie }
+		} else if event.mask.contains(AddWatchFlags::IN_DELETE) {
+		    SourceChange::Removed { path }
+		} else if event.mask.contains(AddWatchFlags::IN_CLOSE_WRITE) {
+		    SourceChange::Added { path, is_dir: false }
+		} else if event.mask.contains(AddWatchFlags::IN_CREATE) {
+		    // files are picked up once they are written, but directories and symlinks are
+		    // complete as soon as they are created
+		    let is_symlink = path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink());
+		    if !is_dir && !is_symlink {
+			continue;
+		    }
+		    SourceChange::Added { path: path.clone(), is_dir: is_dir || path.is_dir() }
+		} else {
+		    continue;
+		};
+		changes.push(change);
+	    }
+	}
+	changes
+    }
+}
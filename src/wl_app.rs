@@ -1,11 +1,17 @@
 use crate::{
-    config::Config,
-    image_file::{self, ImageFile},
-    output::Output,
+    archive::is_archive,
+    config::{Config, Source},
+    gallery::{Gallery, SourcePicker},
+    image_file::probe_image,
+    output::{Output, WALL_CLOCK_RECHECK},
+    reload::ReloadWatcher,
+    schedule::{self, LocalTime},
+    source_watcher::SourceWatcher,
 };
 use nix::sys::epoll;
 use std::{
     collections::HashMap,
+    path::PathBuf,
     time::{Duration, Instant},
 };
 use wayland_client::{
@@ -18,6 +24,12 @@ use wayland_client::{
 use wayland_protocols_wlr::layer_shell::v1::client::zwlr_layer_shell_v1;
 use wayland_protocols_wlr::layer_shell::v1::client::zwlr_layer_surface_v1;
 
+const WAYLAND_EPOLL_DATA: u64 = 0;
+const SIGNAL_EPOLL_DATA: u64 = 1;
+const CONFIG_INOTIFY_EPOLL_DATA: u64 = 2;
+const SOURCE_INOTIFY_EPOLL_DATA: u64 = 3;
+const SCAN_EPOLL_DATA: u64 = 4;
+
 pub struct WlApp {
     pub output_map: HashMap<u32, Output>,
     pub compositor_proxy: Option<wl_compositor::WlCompositor>,
@@ -25,7 +37,11 @@ pub struct WlApp {
     pub wl_shm: Option<wl_shm::WlShm>,
     pub supported_formats_vec: Vec<wl_shm::Format>,
     pub config: Config,
-    pub image_list: Vec<ImageFile>,
+    // the config file given on the command line or in the environment, if any
+    pub config_path: Option<PathBuf>,
+    pub gallery: Gallery,
+    // the index of the [[schedule]] window we are in, if any
+    pub active_window: Option<usize>,
 }
 
 impl Dispatch<wl_shm::WlShm, ()> for WlApp {
@@ -201,6 +217,7 @@ impl Dispatch<wl_output::WlOutput, u32> for WlApp {
                     output.name
                 );
                 let compositor_proxy = state.compositor_proxy.as_ref().unwrap();
+                output.settings = Some(state.config.settings_for(output, state.active_window));
                 if !output.should_update_config {
                     println!("received done event for surface that should not be updated");
                     return;
@@ -336,16 +353,20 @@ impl Dispatch<wl_registry::WlRegistry, ()> for WlApp {
 }
 
 impl WlApp {
-    pub fn new(config: Config, image_list: Vec<ImageFile>) -> WlApp {
-        WlApp {
+    pub fn new(config: Config, config_path: Option<PathBuf>, gallery: Gallery) -> WlApp {
+        let mut wl_app = WlApp {
             output_map: HashMap::new(),
             supported_formats_vec: Vec::new(),
             wl_shm: None,
             compositor_proxy: None,
             wlr_layer_shell_proxy: None,
             config,
-            image_list,
-        }
+            config_path,
+            gallery,
+            active_window: None,
+        };
+        wl_app.active_window = wl_app.current_window().0;
+        wl_app
     }
 
     pub fn run(&mut self) {
@@ -399,22 +420,130 @@ impl WlApp {
         return event_queue;
     }
 
+    // Re-reads the config files and applies the result to the existing outputs, without
+    // recreating their surfaces. The previous config is kept if the new one cannot be loaded.
+    pub fn reload_config(&mut self) {
+        println!("reloading config");
+        let config = match Config::load(&self.config_path) {
+            Ok(config) => config,
+            Err(error) => {
+                println!("keeping the previous config, failed to load the new one: {}", error);
+                return;
+            }
+        };
+        if config.scan_options() != self.config.scan_options() {
+            // every directory has to be scanned again
+            self.gallery.retain_sources(&[]);
+        }
+        self.gallery.set_order(config.order, config.seed);
+        self.config = config;
+        self.active_window = self.current_window().0;
+        self.update_settings();
+    }
+
+    fn current_window(&self) -> (Option<usize>, Option<Duration>) {
+        schedule::current_window(
+            &self.config.schedule,
+            &self.config.location(),
+            &LocalTime::now(),
+        )
+    }
+
+    // Switches to the schedule window we are in if it changed, returns the time until the next one
+    fn update_schedule(&mut self) -> Option<Duration> {
+        let (active_window, until_next_window) = self.current_window();
+        if active_window != self.active_window {
+            match active_window {
+                Some(index) => println!(
+                    "entering the schedule window starting at {}",
+                    self.config.schedule[index].start
+                ),
+                None => println!("leaving the schedule, using the global sources"),
+            }
+            self.active_window = active_window;
+            self.update_settings();
+        }
+        until_next_window
+    }
+
+    // Recomputes the settings of every output, and schedules the redraws that they require
+    fn update_settings(&mut self) {
+        for output in self.output_map.values_mut() {
+            let new_settings = self.config.settings_for(output, self.active_window);
+            let old_settings = match output.settings.replace(new_settings.clone()) {
+                Some(old_settings) => old_settings,
+                None => continue,
+            };
+            if old_settings.sources != new_settings.sources {
+                output.source_picker = SourcePicker::new();
+            }
+            if old_settings.sources != new_settings.sources
+                || old_settings.fit != new_settings.fit
+                || old_settings.background_color != new_settings.background_color
+            {
+                // redraw on the next iteration of the main loop
+                output.next_redraw = None;
+            } else if old_settings.change_at != new_settings.change_at {
+                if output.next_redraw.is_some() {
+                    output.schedule_next_change(None);
+                }
+            } else if new_settings.change_at.is_none() {
+                // with change_at, the next change stays at the same wall-clock time
+                if let Some(next_redraw) = output.next_redraw {
+                    let last_redraw =
+                        next_redraw - Duration::new(old_settings.bg_duration_seconds, 0);
+                    output.next_redraw =
+                        Some(last_redraw + Duration::new(new_settings.bg_duration_seconds, 0));
+                }
+            }
+        }
+
+        let used_sources: Vec<&Source> = self
+            .output_map
+            .values()
+            .filter_map(|output| output.settings.as_ref())
+            .flat_map(|settings| settings.sources.iter())
+            .collect();
+        self.gallery.retain_sources(&used_sources);
+    }
 
     pub fn main_loop(&mut self, event_queue: &mut EventQueue<WlApp>) {
         let mut next_timer: Option<Duration> = None;
-        let bg_duration_as_duration = Duration::new(self.config.bg_duration_seconds, 0);
-        let bg_duration_as_epoll_timer =
-            match epoll::EpollTimeout::try_from(bg_duration_as_duration.as_millis()) {
-                Ok(duration) => duration,
-                Err(error) => panic!("Time between updates is incorrect: {}", error),
-            };
         let acceptable_delta = Duration::new(1, 0);
         let epoll = match epoll::Epoll::new(epoll::EpollCreateFlags::empty()) {
             Ok(epoll) => epoll,
             Err(error) => panic!("error when creating an epoll instance: {}", error),
         };
 
-        let mut index_in_image_array = 0;
+        let mut reload_watcher = ReloadWatcher::new(&Config::watched_paths(&self.config_path));
+        if let Err(error) = epoll.add(
+            reload_watcher.signal_fd(),
+            epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, SIGNAL_EPOLL_DATA),
+        ) {
+            panic!("Error when adding signalfd to epoll: {}", error);
+        }
+        if let Err(error) = epoll.add(
+            reload_watcher.inotify_fd(),
+            epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, CONFIG_INOTIFY_EPOLL_DATA),
+        ) {
+            panic!("Error when adding config inotify fd to epoll: {}", error);
+        }
+
+        let mut source_watcher = SourceWatcher::new();
+        source_watcher.watch(&self.gallery.watched_dirs());
+        if let Err(error) = epoll.add(
+            source_watcher.inotify_fd(),
+            epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, SOURCE_INOTIFY_EPOLL_DATA),
+        ) {
+            panic!("Error when adding source inotify fd to epoll: {}", error);
+        }
+        if let Err(error) = epoll.add(
+            self.gallery.scanner.event_fd(),
+            epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, SCAN_EPOLL_DATA),
+        ) {
+            panic!("Error when adding scan eventfd to epoll: {}", error);
+        }
+
         loop {
             let read_guard = event_queue.prepare_read().unwrap();
             let fd = read_guard.connection_fd();
@@ -426,24 +555,27 @@ impl WlApp {
                 }
             }
 
-            if let Err(error) = epoll.add(fd, epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, 0))
-            {
+            if let Err(error) = epoll.add(
+                fd,
+                epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, WAYLAND_EPOLL_DATA),
+            ) {
                 panic!("Error when adding fd to epoll: {}", error);
             }
 
-            let mut events = [epoll::EpollEvent::empty()];
+            let mut events = [epoll::EpollEvent::empty(); 5];
 
             if let Err(error) = event_queue.flush() {
                 panic!("error when flushing event queue : {}", error);
             }
 
+            let bg_duration_as_duration = Duration::new(self.config.bg_duration_seconds, 0);
             if let None = next_timer {
                 next_timer = Some(bg_duration_as_duration);
             }
 
             let timeout = match epoll::EpollTimeout::try_from(next_timer.unwrap()) {
                 Ok(timeout) => timeout,
-                Err(_) => bg_duration_as_epoll_timer,
+                Err(_) => epoll::EpollTimeout::MAX,
             };
 
             println!("epoll wait for {:#?}", next_timer.unwrap());
@@ -453,7 +585,27 @@ impl WlApp {
                 Err(epollerror) => panic!("error when waiting on epoll: {}", epollerror),
             };
             println!("#########################################epoll wait finished#########################################");
-            if nfd > 0 {
+            let mut wayland_ready = false;
+            let mut should_reload = false;
+            let mut should_stop = false;
+            let mut source_changes = Vec::new();
+            let mut scan_events = Vec::new();
+            for event in events.iter().take(nfd) {
+                match event.data() {
+                    WAYLAND_EPOLL_DATA => wayland_ready = true,
+                    SIGNAL_EPOLL_DATA => {
+                        let requests = reload_watcher.read_signals();
+                        should_reload |= requests.reload;
+                        should_stop |= requests.stop;
+                    }
+                    CONFIG_INOTIFY_EPOLL_DATA => should_reload |= reload_watcher.read_inotify(),
+                    SOURCE_INOTIFY_EPOLL_DATA => source_changes.extend(source_watcher.read_changes()),
+                    SCAN_EPOLL_DATA => scan_events.extend(self.gallery.scanner.read_events()),
+                    data => println!("epoll event with unknown data {}", data),
+                }
+            }
+
+            if wayland_ready {
                 {
                     let n_events = match read_guard.read() {
                         Ok(n_events) => n_events,
@@ -471,14 +623,52 @@ impl WlApp {
                 }
             }
 
+            if should_stop {
+                println!("stopping");
+                self.gallery.flush_rotation_state();
+                return;
+            }
+
+            if should_reload {
+                self.reload_config();
+                reload_watcher.watch(&Config::watched_paths(&self.config_path));
+            }
+
+            if !source_changes.is_empty() {
+                self.gallery
+                    .apply_changes(source_changes, &self.config.scan_options());
+            }
+
+            if !scan_events.is_empty()
+                && self
+                    .gallery
+                    .apply_scan_events(scan_events, &self.config.scan_options())
+            {
+                for output in self.output_map.values_mut() {
+                    if output.waiting_for_images {
+                        output.next_redraw = None;
+                    }
+                }
+            }
+
+            let until_next_window = self.update_schedule();
+            let until_next_retry = self.gallery.retry_scans(&self.config.scan_options());
+            // outputs may have been plugged, unplugged or changed mode since the last time
+            let outputs: Vec<&Output> = self.output_map.values().collect();
+            self.gallery.assign(self.config.assignment, &outputs);
+
             let now = Instant::now();
             next_timer = None;
             for (key, output) in self.output_map.iter_mut() {
                 if output.should_update_config == false {
+                    output.catch_up_with_wall_clock(now);
                     match output.next_redraw {
                         Some(next_redraw) => {
                             if now + acceptable_delta < next_redraw {
-                                let next_redraw_delta = next_redraw - now;
+                                let mut next_redraw_delta = next_redraw - now;
+                                if output.next_change_at.is_some() {
+                                    next_redraw_delta = next_redraw_delta.min(WALL_CLOCK_RECHECK);
+                                }
                                 println!(
                                     "output {}, next_redraw_delta: {:#?}",
                                     output.name, next_redraw_delta
@@ -495,46 +685,114 @@ impl WlApp {
                         None => (),
                     }
 
-                    loop {
+                    let settings = match output.settings.clone() {
+                        Some(settings) => settings,
+                        None => continue,
+                    };
+                    // after a restart, the output shows the image it showed before
+                    if output.shown_image.is_none() {
+                        // the entries of an archive cannot be probed on their own, the rotation
+                        // of the archive goes on from where it was instead
+                        let resumed = match self.gallery.rotation_state.shown(&output.name).cloned() {
+                            Some((shown_source, path))
+                                if !is_archive(&shown_source)
+                                    && settings
+                                        .sources
+                                        .iter()
+                                        .any(|source| source.path == shown_source) =>
+                            {
+                                self.gallery.probe(&path, &self.config.scan_options())
+                            }
+                            _ => None,
+                        };
+                        if let Some(image_file) = resumed {
+                            if output.render(key, &event_queue.handle(), Some(&image_file)).is_ok() {
+                                println!(
+                                    "resuming with {} on {}",
+                                    image_file.path.to_string_lossy(),
+                                    output.name
+                                );
+                                output.schedule_next_change(image_file.duration());
+                                output.shown_image = Some(image_file.path);
+                                continue;
+                            }
+                        }
+                    }
+
+                    let source = &settings.sources[output.source_picker.pick(&settings.sources)];
+                    let image_set = self
+                        .gallery
+                        .image_set(&source.path, &self.config.scan_options());
+                    let mut rendered = false;
+                    while image_set.seek(&output.name) {
                         if let Err(error) = output.render(
                             key,
                             &event_queue.handle(),
-                            Some(&(self.image_list.get(index_in_image_array).unwrap().path)),
+                            Some(image_set.current()),
                         ) {
                             println!(
                                 "cannot render image {:#?} on screen {}: {}!",
-                                &(self.image_list.get(index_in_image_array).unwrap().path),
+                                &(image_set.current().path),
                                 output.name,
-				    error
+                                error
                             );
-                            self.image_list.swap_remove(index_in_image_array);
-
-                            if self.image_list.len() == 0 {
-                                panic!("cannot find any picture to display !");
-                            }
-
-                            if index_in_image_array > 0
-                                && index_in_image_array >= self.image_list.len()
-                            {
-                                index_in_image_array = self.image_list.len() - 1;
-                            }
-			    continue;
+                            image_set.remove_current();
+                            continue;
                         }
+                        rendered = true;
                         break;
                     }
-                    output.next_redraw = Some(Instant::now() + bg_duration_as_duration);
-                    index_in_image_array = index_in_image_array + 1;
-                    if index_in_image_array >= self.image_list.len() {
-			println!("checking to see if neww images were added");
-                        self.image_list = image_file::get_image_list(
-                            &self.config.path,
-                            &self.config.authorized_formats,
-                        );
-			if self.image_list.len() == 0 {
-			    panic!("get_image_list returned arrya of len 0");
-			}
-			index_in_image_array = 0;
+                    if !rendered {
+                        if !image_set.image_list.is_empty() {
+                            println!(
+                                "no picture of {} can be shown on {}, waiting for new ones",
+                                source.path.to_string_lossy(),
+                                output.name
+                            );
+                        } else if image_set.scan_id.is_some() {
+                            println!(
+                                "{} is being scanned, waiting for its first pictures",
+                                source.path.to_string_lossy()
+                            );
+                        } else {
+                            println!(
+                                "no picture left in {}, waiting for new ones",
+                                source.path.to_string_lossy()
+                            );
+                        }
+                        if !output.waiting_for_images {
+                            let fallback_image = self.config.fallback_image.as_ref().and_then(
+                                |fallback_image| {
+                                    probe_image(fallback_image, &self.config.authorized_formats)
+                                },
+                            );
+                            output.render_fallback(key, &event_queue.handle(), fallback_image.as_ref());
+                        }
+                        output.waiting_for_images = true;
+                        output.schedule_next_change(None);
+                        continue;
                     }
+                    output.waiting_for_images = false;
+                    // the sidecar of the image can make it stay longer, or shorter
+                    output.schedule_next_change(image_set.current().duration());
+                    let shown_image = image_set.current().path.clone();
+                    image_set.advance();
+                    self.gallery
+                        .rotation_state
+                        .set_shown(&output.name, &source.path, &shown_image);
+                    output.shown_image = Some(shown_image);
+                }
+            }
+            let until_next_save = self.gallery.save_rotation_state();
+            if let Some(watched_dirs) = self.gallery.changed_watched_dirs() {
+                source_watcher.watch(&watched_dirs);
+            }
+            for until_next_event in [until_next_window, until_next_retry, until_next_save]
+                .into_iter()
+                .flatten()
+            {
+                if next_timer.is_none_or(|next_timer| until_next_event < next_timer) {
+                    next_timer = Some(until_next_event);
                 }
             }
         }
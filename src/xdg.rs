@@ -0,0 +1,105 @@
+use std::{env, ffi::OsStr, fs, os::unix::ffi::OsStrExt, path::PathBuf};
+
+// Helpers for the XDG base directory specification: variables that are unset, empty or that
+// contain a relative path are ignored and replaced by their default value
+
+pub fn home() -> PathBuf {
+    match env::var_os("HOME") {
+	Some(home) => PathBuf::from(home),
+	None => PathBuf::from("/"),
+    }
+}
+
+fn absolute_path_from_env(var: &str) -> Option<PathBuf> {
+    match env::var_os(var) {
+	Some(value) => {
+	    let path = PathBuf::from(value);
+	    if path.is_absolute() {
+		Some(path)
+	    } else {
+		None
+	    }
+	}
+	None => None,
+    }
+}
+
+pub fn config_home() -> PathBuf {
+    match absolute_path_from_env("XDG_CONFIG_HOME") {
+	Some(path) => path,
+	None => home().join(".config"),
+    }
+}
+
+pub fn cache_home() -> PathBuf {
+    match absolute_path_from_env("XDG_CACHE_HOME") {
+	Some(path) => path,
+	None => home().join(".cache"),
+    }
+}
+
+pub fn state_home() -> PathBuf {
+    match absolute_path_from_env("XDG_STATE_HOME") {
+	Some(path) => path,
+	None => home().join(".local").join("state"),
+    }
+}
+
+// In decreasing order of importance, as in the variable
+pub fn config_dirs() -> Vec<PathBuf> {
+    let dirs: Vec<PathBuf> = match env::var_os("XDG_CONFIG_DIRS") {
+	Some(value) => env::split_paths(&value)
+	    .filter(|path| path.is_absolute())
+	    .collect(),
+	None => Vec::new(),
+    };
+    if dirs.is_empty() {
+	return vec![PathBuf::from("/etc/xdg")];
+    }
+    dirs
+}
+
+// The directories of xdg-user-dirs that have a conventional default, relative to $HOME
+const USER_DIR_DEFAULTS: [(&str, &str); 8] = [
+    ("XDG_DESKTOP_DIR", "Desktop"),
+    ("XDG_DOWNLOAD_DIR", "Downloads"),
+    ("XDG_TEMPLATES_DIR", "Templates"),
+    ("XDG_PUBLICSHARE_DIR", "Public"),
+    ("XDG_DOCUMENTS_DIR", "Documents"),
+    ("XDG_MUSIC_DIR", "Music"),
+    ("XDG_PICTURES_DIR", "Pictures"),
+    ("XDG_VIDEOS_DIR", "Videos"),
+];
+
+// A user directory such as XDG_PICTURES_DIR, as configured in user-dirs.dirs (whose values are
+// either absolute or relative to $HOME), or its default value
+pub fn user_dir(name: &str) -> Option<PathBuf> {
+    if let Ok(contents) = fs::read(config_home().join("user-dirs.dirs")) {
+	for line in contents.split(|byte| *byte == b'\n') {
+	    let line = line.trim_ascii();
+	    let (key, value) = match line.iter().position(|byte| *byte == b'=') {
+		Some(index) => (&line[..index], &line[index + 1..]),
+		None => continue,
+	    };
+	    if key != name.as_bytes() {
+		continue;
+	    }
+	    let value = value.strip_prefix(b"\"").unwrap_or(value);
+	    let value = value.strip_suffix(b"\"").unwrap_or(value);
+	    if value == b"$HOME" {
+		return Some(home());
+	    }
+	    if let Some(relative) = value.strip_prefix(b"$HOME/") {
+		return Some(home().join(OsStr::from_bytes(relative)));
+	    }
+	    if value.starts_with(b"/") {
+		return Some(PathBuf::from(OsStr::from_bytes(value)));
+	    }
+	}
+    }
+
+    USER_DIR_DEFAULTS
+	.iter()
+	.find(|(key, _)| *key == name)
+	.map(|(_, default)| home().join(default))
+}